@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS anime_metadata (
+    id INTEGER PRIMARY KEY,
+    romaji_title TEXT,
+    english_title TEXT,
+    season TEXT,
+    season_year INTEGER NOT NULL,
+    description TEXT,
+    popularity INTEGER,
+    mean_score INTEGER,
+    genres TEXT
+);
+
+CREATE INDEX IF NOT EXISTS anime_metadata_season_year_idx ON anime_metadata (season_year);
@@ -0,0 +1,44 @@
+CREATE TABLE genre (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+-- AniList tag ids are used as primary keys.
+CREATE TABLE tag (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    category TEXT,
+    is_adult BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE media_genre (
+    media_id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    genre_id INTEGER NOT NULL REFERENCES genre (id) ON DELETE CASCADE,
+    PRIMARY KEY (media_id, genre_id)
+);
+
+CREATE INDEX media_genre_genre_idx ON media_genre (genre_id, media_id);
+
+CREATE TABLE media_tag (
+    media_id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+    rank INTEGER,
+    PRIMARY KEY (media_id, tag_id)
+);
+
+CREATE INDEX media_tag_tag_idx ON media_tag (tag_id, rank, media_id);
+
+-- Backfill from the comma-joined genres column. Tags were never stored, they
+-- are filled in the next time the metadata is crawled.
+CREATE TEMP TABLE legacy_genre ON COMMIT DROP AS
+SELECT DISTINCT id AS media_id, name
+FROM anime_metadata, unnest(string_to_array(genres, ',')) AS name
+WHERE name <> '';
+
+INSERT INTO genre (name) SELECT DISTINCT name FROM legacy_genre ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO media_genre (media_id, genre_id)
+SELECT l.media_id, g.id FROM legacy_genre l JOIN genre g ON g.name = l.name
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE anime_metadata DROP COLUMN genres;
@@ -0,0 +1 @@
+ALTER TABLE anime_metadata ADD COLUMN is_adult BOOLEAN;
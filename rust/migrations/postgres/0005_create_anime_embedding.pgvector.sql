@@ -0,0 +1,6 @@
+CREATE TABLE anime_embedding (
+    id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    embedding vector NOT NULL,
+    PRIMARY KEY (id, model)
+);
@@ -0,0 +1,15 @@
+ALTER TABLE anime_embedding
+    ADD COLUMN source TEXT NOT NULL DEFAULT 'summary',
+    ADD COLUMN dimension INTEGER,
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+UPDATE anime_embedding SET dimension = vector_dims(embedding);
+
+ALTER TABLE anime_embedding
+    ALTER COLUMN source DROP DEFAULT,
+    ALTER COLUMN dimension SET NOT NULL,
+    DROP CONSTRAINT anime_embedding_pkey,
+    ADD PRIMARY KEY (id, model, source),
+    ADD CHECK (vector_dims(embedding) = dimension);
+
+CREATE INDEX anime_embedding_model_idx ON anime_embedding (model, source);
@@ -0,0 +1,16 @@
+-- Postgres has no FTS5; generated tsvector columns stay in sync with the text
+-- without triggers. Titles are indexed without stemming so romaji spellings
+-- match as written, descriptions and summaries with English stemming.
+ALTER TABLE anime_metadata ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', coalesce(romaji_title, '')), 'A')
+    || setweight(to_tsvector('simple', coalesce(english_title, '')), 'A')
+    || setweight(to_tsvector('english', coalesce(description, '')), 'C')
+) STORED;
+
+ALTER TABLE anime_summary ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', coalesce(summary, '')), 'C')
+    || setweight(to_tsvector('english', coalesce(generated_themes, '')), 'B')
+) STORED;
+
+CREATE INDEX anime_metadata_search_idx ON anime_metadata USING GIN (search_vector);
+CREATE INDEX anime_summary_search_idx ON anime_summary USING GIN (search_vector);
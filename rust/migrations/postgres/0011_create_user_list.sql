@@ -0,0 +1,17 @@
+-- MyAnimeList id of each media, to match MAL list exports to AniList media.
+ALTER TABLE anime_metadata ADD COLUMN mal_id INTEGER;
+
+CREATE INDEX anime_metadata_mal_id_idx ON anime_metadata (mal_id);
+
+-- Imported AniList and MAL lists. media_id is an AniList id, which may not be
+-- crawled yet. Scores are out of 100, NULL when the user gave none.
+CREATE TABLE user_list (
+    user_name TEXT NOT NULL,
+    media_id INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    score INTEGER,
+    progress INTEGER,
+    source TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_name, media_id)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS anime_metadata (
+    id INTEGER PRIMARY KEY,
+    romaji_title TEXT,
+    english_title TEXT,
+    season TEXT,
+    season_year INTEGER NOT NULL,
+    description TEXT,
+    popularity INTEGER,
+    mean_score INTEGER,
+    genres TEXT
+);
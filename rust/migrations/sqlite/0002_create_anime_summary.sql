@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS anime_summary (
+    id INTEGER PRIMARY KEY,
+    summary TEXT,
+    generated_genres TEXT,
+    generated_themes TEXT
+);
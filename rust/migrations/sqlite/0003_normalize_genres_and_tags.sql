@@ -0,0 +1,50 @@
+CREATE TABLE genre (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+-- AniList tag ids are used as primary keys.
+CREATE TABLE tag (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    category TEXT,
+    is_adult INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE media_genre (
+    media_id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    genre_id INTEGER NOT NULL REFERENCES genre (id) ON DELETE CASCADE,
+    PRIMARY KEY (media_id, genre_id)
+);
+
+CREATE INDEX media_genre_genre_idx ON media_genre (genre_id, media_id);
+
+CREATE TABLE media_tag (
+    media_id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+    rank INTEGER,
+    PRIMARY KEY (media_id, tag_id)
+);
+
+CREATE INDEX media_tag_tag_idx ON media_tag (tag_id, rank, media_id);
+
+-- Backfill from the comma-joined genres column. Tags were never stored, they
+-- are filled in the next time the metadata is crawled.
+CREATE TEMP TABLE legacy_genre AS
+WITH RECURSIVE split (media_id, name, rest) AS (
+    SELECT id, '', genres || ',' FROM anime_metadata WHERE genres IS NOT NULL AND genres <> ''
+    UNION ALL
+    SELECT media_id, substr(rest, 1, instr(rest, ',') - 1), substr(rest, instr(rest, ',') + 1)
+    FROM split
+    WHERE rest <> ''
+)
+SELECT DISTINCT media_id, name FROM split WHERE name <> '';
+
+INSERT OR IGNORE INTO genre (name) SELECT DISTINCT name FROM legacy_genre;
+
+INSERT OR IGNORE INTO media_genre (media_id, genre_id)
+SELECT l.media_id, g.id FROM legacy_genre l JOIN genre g ON g.name = l.name;
+
+DROP TABLE legacy_genre;
+
+ALTER TABLE anime_metadata DROP COLUMN genres;
@@ -0,0 +1,7 @@
+-- Vectors are stored as little-endian f32 blobs.
+CREATE TABLE anime_embedding (
+    id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    PRIMARY KEY (id, model)
+);
@@ -0,0 +1,20 @@
+-- SQLite cannot change a primary key in place, so the table is rebuilt.
+CREATE TABLE anime_embedding_new (
+    id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    source TEXT NOT NULL,
+    dimension INTEGER NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, model, source),
+    CHECK (length(embedding) = dimension * 4)
+);
+
+INSERT INTO anime_embedding_new (id, model, source, dimension, embedding)
+SELECT id, model, 'summary', length(embedding) / 4, embedding FROM anime_embedding;
+
+DROP TABLE anime_embedding;
+
+ALTER TABLE anime_embedding_new RENAME TO anime_embedding;
+
+CREATE INDEX anime_embedding_model_idx ON anime_embedding (model, source);
@@ -0,0 +1,58 @@
+-- External content FTS5 tables: the text stays in anime_metadata and
+-- anime_summary, the triggers below keep the indexes in sync with them. An
+-- update is a 'delete' of the old values followed by an insert of the new
+-- ones, so rows must be upserted with ON CONFLICT DO UPDATE rather than
+-- INSERT OR REPLACE, which does not fire the delete triggers.
+CREATE VIRTUAL TABLE anime_metadata_fts USING fts5(
+    romaji_title,
+    english_title,
+    description,
+    content = 'anime_metadata',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE VIRTUAL TABLE anime_summary_fts USING fts5(
+    summary,
+    generated_themes,
+    content = 'anime_summary',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER anime_metadata_fts_insert AFTER INSERT ON anime_metadata BEGIN
+    INSERT INTO anime_metadata_fts (rowid, romaji_title, english_title, description)
+    VALUES (new.id, new.romaji_title, new.english_title, new.description);
+END;
+
+CREATE TRIGGER anime_metadata_fts_delete AFTER DELETE ON anime_metadata BEGIN
+    INSERT INTO anime_metadata_fts (anime_metadata_fts, rowid, romaji_title, english_title, description)
+    VALUES ('delete', old.id, old.romaji_title, old.english_title, old.description);
+END;
+
+CREATE TRIGGER anime_metadata_fts_update AFTER UPDATE ON anime_metadata BEGIN
+    INSERT INTO anime_metadata_fts (anime_metadata_fts, rowid, romaji_title, english_title, description)
+    VALUES ('delete', old.id, old.romaji_title, old.english_title, old.description);
+    INSERT INTO anime_metadata_fts (rowid, romaji_title, english_title, description)
+    VALUES (new.id, new.romaji_title, new.english_title, new.description);
+END;
+
+CREATE TRIGGER anime_summary_fts_insert AFTER INSERT ON anime_summary BEGIN
+    INSERT INTO anime_summary_fts (rowid, summary, generated_themes)
+    VALUES (new.id, new.summary, new.generated_themes);
+END;
+
+CREATE TRIGGER anime_summary_fts_delete AFTER DELETE ON anime_summary BEGIN
+    INSERT INTO anime_summary_fts (anime_summary_fts, rowid, summary, generated_themes)
+    VALUES ('delete', old.id, old.summary, old.generated_themes);
+END;
+
+CREATE TRIGGER anime_summary_fts_update AFTER UPDATE ON anime_summary BEGIN
+    INSERT INTO anime_summary_fts (anime_summary_fts, rowid, summary, generated_themes)
+    VALUES ('delete', old.id, old.summary, old.generated_themes);
+    INSERT INTO anime_summary_fts (rowid, summary, generated_themes)
+    VALUES (new.id, new.summary, new.generated_themes);
+END;
+
+INSERT INTO anime_metadata_fts (anime_metadata_fts) VALUES ('rebuild');
+INSERT INTO anime_summary_fts (anime_summary_fts) VALUES ('rebuild');
@@ -0,0 +1,3 @@
+-- AniList media format (TV, MOVIE, OVA, ...). Rows crawled before this
+-- migration stay NULL until they are crawled again.
+ALTER TABLE anime_metadata ADD COLUMN format TEXT;
@@ -0,0 +1,10 @@
+-- LLM reranking results, keyed by the normalized query, the LLM model and the
+-- ids of the candidates in the order they were sent.
+CREATE TABLE rerank_cache (
+    query TEXT NOT NULL,
+    model TEXT NOT NULL,
+    candidates TEXT NOT NULL,
+    ranking TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (query, model, candidates)
+);
@@ -0,0 +1,10 @@
+-- Relations between anime, e.g. sequels and side stories, used to tell which
+-- media belong to the same franchise. The related anime may not be crawled.
+CREATE TABLE media_relation (
+    media_id INTEGER NOT NULL REFERENCES anime_metadata (id) ON DELETE CASCADE,
+    related_id INTEGER NOT NULL,
+    relation_type TEXT NOT NULL,
+    PRIMARY KEY (media_id, related_id)
+);
+
+CREATE INDEX media_relation_related_idx ON media_relation (related_id);
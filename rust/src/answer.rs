@@ -0,0 +1,366 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::content_policy::ContentPolicy;
+use crate::llm::{ChatMessage, LlmClient, LlmError};
+use crate::search::{HybridWeights, SearchError, SearchFilter, Searcher};
+use crate::storage::Storage;
+use crate::types::{AnimeMetadata, AnimeSummary};
+
+// Media retrieved for each question.
+const RETRIEVED_PER_QUESTION: usize = 8;
+// Most media given to the LLM at once. Those carried over from earlier turns
+// only get the slots the retrieved ones leave.
+const MAX_CONTEXT_MEDIA: usize = 16;
+// Earlier questions and answers repeated to the LLM.
+const MAX_HISTORY_TURNS: usize = 6;
+const MAX_DESCRIPTION_CHARS: usize = 400;
+
+pub const REFUSAL: &str = "I can't answer that from the anime I found.";
+
+/// An answer grounded in the retrieved media.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Answer {
+    /// The answer, citing media as `[123]`.
+    pub text: String,
+    /// Ids of the media the answer relies on, all taken from the context.
+    pub citations: Vec<i32>,
+    /// True when the context was not enough to answer. `text` then says why
+    /// and `citations` is empty.
+    pub refused: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub question: String,
+    pub answer: Answer,
+}
+
+/// The state of a multi-turn conversation: the previous turns and the media
+/// they were about, so that follow-ups such as "which of these is the
+/// shortest?" refer to the same media. Serializable so that it can be kept by
+/// a client between requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Conversation {
+    pub turns: Vec<Turn>,
+    /// Media in the context of the last turn, cited ones first.
+    pub media: Vec<i32>,
+}
+
+impl Conversation {
+    /// Starts a conversation about given media, e.g. the hits of a search.
+    pub fn about(media: Vec<i32>) -> Self {
+        Self { turns: vec![], media }
+    }
+}
+
+/// The reply the instructions ask for.
+#[derive(Deserialize, Debug)]
+struct LlmAnswer {
+    answer: String,
+    #[serde(default)]
+    citations: Vec<i32>,
+    #[serde(default)]
+    insufficient: bool,
+}
+
+#[derive(Debug)]
+pub enum AnswerError {
+    Search(SearchError),
+    Llm(LlmError),
+    Database(sqlx::Error),
+    /// The reply does not have the requested shape.
+    Invalid(String),
+}
+
+impl fmt::Display for AnswerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnswerError::Search(e) => write!(f, "{}", e),
+            AnswerError::Llm(e) => write!(f, "{}", e),
+            AnswerError::Database(e) => write!(f, "database error: {}", e),
+            AnswerError::Invalid(message) => write!(f, "unexpected LLM answer: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AnswerError {}
+
+impl From<SearchError> for AnswerError {
+    fn from(e: SearchError) -> Self {
+        AnswerError::Search(e)
+    }
+}
+
+impl From<LlmError> for AnswerError {
+    fn from(e: LlmError) -> Self {
+        AnswerError::Llm(e)
+    }
+}
+
+impl From<sqlx::Error> for AnswerError {
+    fn from(e: sqlx::Error) -> Self {
+        AnswerError::Database(e)
+    }
+}
+
+const INSTRUCTIONS: &str = "You answer questions about anime using only the \
+catalog entries given in the last message, never your own knowledge. Cite \
+every entry you rely on by its id in square brackets, e.g. [123]. If the \
+entries do not contain what is needed to answer, say so briefly instead of \
+guessing. Reply with a JSON object {\"answer\": \"...\", \"citations\": [123], \
+\"insufficient\": false}, setting \"insufficient\" to true when you cannot \
+answer from the entries.";
+
+/// Answers questions about anime from retrieved `anime_metadata` and
+/// `anime_summary` rows.
+pub struct Answerer {
+    llm: LlmClient,
+    storage: Storage,
+    weights: HybridWeights,
+}
+
+impl Answerer {
+    /// Media are retrieved with [`Searcher::hybrid_search`] and `weights`.
+    pub fn new(llm: LlmClient, storage: Storage, weights: HybridWeights) -> Self {
+        Self { llm, storage, weights }
+    }
+
+    /// Answers the next question of a conversation and records the turn. The
+    /// context is the media retrieved for the question plus the first media of
+    /// the previous turn, less those the searching user's content policy denies.
+    /// Nothing is sent to the LLM when no media match.
+    pub async fn ask(&self, searcher: &Searcher, conversation: &mut Conversation, question: &str, filter: &SearchFilter) -> Result<Answer, AnswerError> {
+        let retrieved = searcher.hybrid_search(question, RETRIEVED_PER_QUESTION, filter, &self.weights).await?;
+        let carried = MAX_CONTEXT_MEDIA - RETRIEVED_PER_QUESTION;
+        let mut ids: Vec<i32> = conversation.media.iter().take(carried).copied().collect();
+        for hit in &retrieved {
+            if !ids.contains(&hit.metadata.id) {
+                ids.push(hit.metadata.id);
+            }
+        }
+
+        let context = self.context(&ids, searcher.policy(filter.user.as_deref())).await?;
+        let answer = if context.is_empty() {
+            Answer { text: REFUSAL.to_string(), citations: vec![], refused: true }
+        } else {
+            let mut messages = vec![ChatMessage::system(INSTRUCTIONS)];
+            let history = conversation.turns.len().saturating_sub(MAX_HISTORY_TURNS);
+            for turn in &conversation.turns[history..] {
+                messages.push(ChatMessage::user(turn.question.clone()));
+                messages.push(ChatMessage::assistant(turn.answer.text.clone()));
+            }
+            messages.push(ChatMessage::user(prompt(question, &context)));
+            let reply = self.llm.chat_json(&messages).await?;
+            let reply: LlmAnswer = serde_json::from_value(reply).map_err(|e| AnswerError::Invalid(e.to_string()))?;
+            grounded(reply, &context)
+        };
+
+        // Cited media stay first so that follow-ups keep talking about them.
+        let cited: HashSet<i32> = answer.citations.iter().copied().collect();
+        conversation.media = answer.citations.clone();
+        conversation.media.extend(context.iter().map(|(metadata, _)| metadata.id).filter(|id| !cited.contains(id)));
+        conversation.turns.push(Turn { question: question.to_string(), answer: answer.clone() });
+        Ok(answer)
+    }
+
+    /// Loads the media `policy` allows among `ids` with their summaries, in
+    /// that order. Conversations come from clients, so their media are not
+    /// trusted to be allowed.
+    async fn context(&self, ids: &[i32], policy: &ContentPolicy) -> Result<Vec<(AnimeMetadata, Option<AnimeSummary>)>, AnswerError> {
+        let mut media: HashMap<i32, AnimeMetadata> = self.storage.media(ids).await?
+            .into_iter()
+            .filter(|anime| policy.allows(anime))
+            .map(|anime| (anime.id, anime))
+            .collect();
+        let mut summaries: HashMap<i32, AnimeSummary> = self.storage.summaries(ids).await?
+            .into_iter()
+            .map(|summary| (summary.id, summary))
+            .collect();
+        Ok(ids.iter()
+            .filter_map(|id| media.remove(id).map(|anime| (anime, summaries.remove(id))))
+            .collect())
+    }
+}
+
+fn prompt(question: &str, context: &[(AnimeMetadata, Option<AnimeSummary>)]) -> String {
+    let mut prompt = String::from("Catalog entries:\n");
+    for (anime, summary) in context {
+        let title = anime.title.english.as_deref()
+            .or(anime.title.romaji.as_deref())
+            .unwrap_or_default();
+        prompt.push_str(&format!("\n[{}] {} ({}", anime.id, title, anime.season_year));
+        if let Some(format) = &anime.format {
+            prompt.push_str(&format!(", {}", format));
+        }
+        prompt.push_str(")\n");
+        if let Some(genres) = anime.genres.as_ref().filter(|genres| !genres.is_empty()) {
+            prompt.push_str(&format!("Genres: {}\n", genres.join(", ")));
+        }
+        if let Some(mean_score) = anime.mean_score {
+            prompt.push_str(&format!("Mean score: {}/100\n", mean_score));
+        }
+        match summary {
+            Some(summary) => {
+                let summary = &summary.generated_summary;
+                prompt.push_str(&format!("Summary: {}\n", summary.summary));
+                if !summary.generated_themes.is_empty() {
+                    prompt.push_str(&format!("Themes: {}\n", summary.generated_themes.join(", ")));
+                }
+            }
+            None => {
+                let description: String = anime.description.as_deref()
+                    .unwrap_or_default()
+                    .chars()
+                    .take(MAX_DESCRIPTION_CHARS)
+                    .collect();
+                prompt.push_str(&format!("Description: {}\n", description));
+            }
+        }
+    }
+    prompt.push_str(&format!("\nQuestion: {}", question));
+    prompt
+}
+
+/// Keeps the citations of entries that were in the context. An answer that
+/// cites none of them is not grounded and becomes a refusal.
+fn grounded(reply: LlmAnswer, context: &[(AnimeMetadata, Option<AnimeSummary>)]) -> Answer {
+    let known: HashSet<i32> = context.iter().map(|(anime, _)| anime.id).collect();
+    let mut citations: Vec<i32> = vec![];
+    for id in reply.citations {
+        if known.contains(&id) && !citations.contains(&id) {
+            citations.push(id);
+        }
+    }
+    if reply.insufficient {
+        let text = if reply.answer.trim().is_empty() { REFUSAL.to_string() } else { reply.answer };
+        return Answer { text, citations: vec![], refused: true };
+    }
+    if citations.is_empty() {
+        return Answer { text: REFUSAL.to_string(), citations, refused: true };
+    }
+    Answer { text: reply.answer, citations, refused: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::Json;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::config::{LlmConfig, SearchIndexConfig};
+    use crate::content_policy::PolicyProfiles;
+    use crate::embedder::Embedder;
+    use crate::storage::TempStorage;
+    use crate::types::AnimeMetadataRow;
+    use crate::vector_index::IndexKind;
+
+    fn anime(id: i32, is_adult: bool) -> AnimeMetadata {
+        AnimeMetadataRow {
+            id,
+            mal_id: None,
+            english_title: Some(format!("Anime {}", id)),
+            romaji_title: None,
+            season: None,
+            season_year: 2020,
+            format: None,
+            description: None,
+            popularity: None,
+            mean_score: None,
+            is_adult: Some(is_adult),
+        }
+        .into_metadata(vec![], vec![])
+    }
+
+    fn context(ids: &[i32]) -> Vec<(AnimeMetadata, Option<AnimeSummary>)> {
+        ids.iter().map(|id| (anime(*id, false), None)).collect()
+    }
+
+    fn reply(answer: &str, citations: &[i32], insufficient: bool) -> LlmAnswer {
+        LlmAnswer { answer: answer.to_string(), citations: citations.to_vec(), insufficient }
+    }
+
+    #[test]
+    fn citations_outside_the_context_are_dropped() {
+        let answer = grounded(reply("[2] fits, unlike [77].", &[2, 77, 2, 1], false), &context(&[1, 2, 3]));
+        assert_eq!(answer, Answer { text: "[2] fits, unlike [77].".to_string(), citations: vec![2, 1], refused: false });
+    }
+
+    #[test]
+    fn ungrounded_answers_are_refused() {
+        let answer = grounded(reply("Naruto [77].", &[77], false), &context(&[1, 2]));
+        assert_eq!(answer, Answer { text: REFUSAL.to_string(), citations: vec![], refused: true });
+        let answer = grounded(reply("Probably the first one.", &[], false), &context(&[1, 2]));
+        assert!(answer.refused);
+    }
+
+    #[test]
+    fn insufficient_context_is_a_refusal() {
+        let answer = grounded(reply("The entries don't say how long it is.", &[1], true), &context(&[1, 2]));
+        assert_eq!(answer, Answer { text: "The entries don't say how long it is.".to_string(), citations: vec![], refused: true });
+        let answer = grounded(reply(" ", &[], true), &context(&[1]));
+        assert_eq!(answer.text, REFUSAL);
+    }
+
+    #[tokio::test]
+    async fn the_context_leaves_out_media_the_policy_denies() {
+        let db = TempStorage::new("answer_policy").await;
+        db.upsert_metadata(vec![anime(1, false), anime(2, true), anime(3, false)]).await.unwrap();
+        let llm = LlmConfig { url: String::new(), model: "test".to_string(), api_keys: vec![] };
+        let answerer = Answerer::new(LlmClient::new(&llm), db.storage.clone(), HybridWeights::default());
+
+        let ids = |context: Vec<(AnimeMetadata, Option<AnimeSummary>)>| -> Vec<i32> {
+            context.iter().map(|(anime, _)| anime.id).collect()
+        };
+        let context = answerer.context(&[3, 2, 1, 9], &ContentPolicy::default()).await.unwrap();
+        assert_eq!(ids(context), vec![3, 1]);
+        let context = answerer.context(&[3, 2, 1], &ContentPolicy::permissive()).await.unwrap();
+        assert_eq!(ids(context), vec![3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn every_question_gets_its_own_hits_into_the_context() {
+        // Media 1 to 8 are about knights, 9 to 16 pirates and 17 to 24 cooking.
+        let topics = ["knight", "pirate", "cooking"];
+        let db = TempStorage::new("answer_turns").await;
+        let media = (1..=24).map(|id| {
+            let mut anime = anime(id, false);
+            anime.description = Some(format!("A {} story.", topics[(id as usize - 1) / 8]));
+            anime
+        });
+        db.upsert_metadata(media.collect()).await.unwrap();
+        let index = SearchIndexConfig { kind: IndexKind::Exact, dir: std::env::temp_dir().display().to_string(), ef_search: 10 };
+        let searcher = Searcher::load(db.storage.clone(), Arc::new(Embedder::tiny()), PolicyProfiles::default(), &index).await.unwrap();
+
+        // The backend cites every entry it is given, so that each turn
+        // carries a full context over to the next.
+        let contexts = Arc::new(Mutex::new(vec![]));
+        let seen = contexts.clone();
+        let llm = LlmClient::mock(move |Json(request): Json<Value>| async move {
+            let prompt = request["messages"].as_array().unwrap().last().unwrap()["content"].as_str().unwrap().to_string();
+            let ids: Vec<i32> = prompt.lines()
+                .filter_map(|line| line.strip_prefix('[')?.split(']').next()?.parse().ok())
+                .collect();
+            seen.lock().unwrap().push(ids.clone());
+            let content = json!({ "answer": "All of them.", "citations": ids }).to_string();
+            Json(LlmClient::completion(&content))
+        }).await;
+        let answerer = Answerer::new(llm, db.storage.clone(), HybridWeights::default());
+
+        let mut conversation = Conversation::default();
+        for topic in topics {
+            answerer.ask(&searcher, &mut conversation, topic, &SearchFilter::default()).await.unwrap();
+        }
+        let contexts = contexts.lock().unwrap().clone();
+        assert_eq!(contexts.len(), 3);
+        for (turn, context) in contexts.iter().enumerate() {
+            let first = turn as i32 * 8 + 1;
+            assert!((first..first + 8).all(|id| context.contains(&id)), "turn {} lacks its hits: {:?}", turn + 1, context);
+            assert!(context.len() <= MAX_CONTEXT_MEDIA);
+        }
+    }
+}
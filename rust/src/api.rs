@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::answer::{Answer, AnswerError, Answerer, Conversation};
+use crate::recommend::{SimilarOptions, TasteOptions};
+use crate::rerank::RerankedHit;
+use crate::search::{HybridWeights, SearchError, SearchFilter, SearchHit, SearchMode, Searcher};
+use crate::types::{AnimeGeneratedSummary, AnimeMetadata};
+
+pub const DEFAULT_PER_PAGE: usize = 10;
+pub const MAX_PER_PAGE: usize = 50;
+/// Deepest result reachable through pagination, so that a request cannot
+/// make the server rank and load the whole catalog.
+pub const MAX_RESULTS: usize = 500;
+
+/// What the handlers share. The searcher's index is loaded once at startup.
+pub struct ApiState {
+    pub searcher: Searcher,
+    pub weights: HybridWeights,
+    pub similar: SimilarOptions,
+    pub taste: TasteOptions,
+    pub answerer: Answerer,
+}
+
+/// An error response, sent as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self { status: StatusCode::BAD_REQUEST, message: message.into() }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self { status: StatusCode::NOT_FOUND, message: message.into() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            error!(status = self.status.as_u16(), message = %self.message, "API error");
+        }
+        (self.status, Json(json!({"error": self.message}))).into_response()
+    }
+}
+
+impl From<SearchError> for ApiError {
+    fn from(e: SearchError) -> Self {
+        match e {
+            SearchError::MissingEmbedding(_) => ApiError::not_found(e.to_string()),
+            SearchError::NoLikedMedia => ApiError::bad_request(e.to_string()),
+            e => Self { status: StatusCode::INTERNAL_SERVER_ERROR, message: e.to_string() },
+        }
+    }
+}
+
+impl From<AnswerError> for ApiError {
+    fn from(e: AnswerError) -> Self {
+        match e {
+            AnswerError::Search(e) => e.into(),
+            AnswerError::Database(e) => e.into(),
+            e @ (AnswerError::Llm(_) | AnswerError::Invalid(_)) => Self { status: StatusCode::BAD_GATEWAY, message: e.to_string() },
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        SearchError::Database(e).into()
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(e: QueryRejection) -> Self {
+        ApiError::bad_request(e.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> Self {
+        ApiError::bad_request(e.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(e: PathRejection) -> Self {
+        ApiError::bad_request(e.body_text())
+    }
+}
+
+/// The query parameters of a request, taken one by one so that each invalid
+/// value is reported by name. Parameters left over are unknown.
+struct Params(HashMap<String, String>);
+
+impl Params {
+    fn take(&mut self, name: &str) -> Option<String> {
+        self.0.remove(name).filter(|value| !value.trim().is_empty())
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, ApiError>
+    where
+        T::Err: Display,
+    {
+        match self.take(name) {
+            Some(value) => value.trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| ApiError::bad_request(format!("invalid {} {:?}: {}", name, value, e))),
+            None => Ok(None),
+        }
+    }
+
+    /// A comma-separated list.
+    fn list<T: FromStr>(&mut self, name: &str) -> Result<Vec<T>, ApiError>
+    where
+        T::Err: Display,
+    {
+        let Some(value) = self.take(name) else {
+            return Ok(vec![]);
+        };
+        value.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse().map_err(|e| ApiError::bad_request(format!("invalid {}: {}", name, e))))
+            .collect()
+    }
+
+    fn filter(&mut self) -> Result<SearchFilter, ApiError> {
+        let filter = SearchFilter {
+            min_year: self.parse("min_year")?,
+            max_year: self.parse("max_year")?,
+            seasons: self.list("seasons")?,
+            formats: self.list("formats")?,
+            min_mean_score: self.parse("min_mean_score")?,
+            min_popularity: self.parse("min_popularity")?,
+            include_genres: self.list("include_genres")?,
+            exclude_genres: self.list("exclude_genres")?,
+            include_themes: self.list("include_themes")?,
+            exclude_themes: self.list("exclude_themes")?,
+            exclude_seen_by: self.take("exclude_seen_by"),
+            user: self.take("user"),
+        };
+        if let (Some(min), Some(max)) = (filter.min_year, filter.max_year) {
+            if min > max {
+                return Err(ApiError::bad_request("min_year must not be after max_year"));
+            }
+        }
+        Ok(filter)
+    }
+
+    fn page(&mut self) -> Result<Page, ApiError> {
+        let page: usize = self.parse("page")?.unwrap_or(1);
+        let per_page = self.parse("per_page")?.unwrap_or(DEFAULT_PER_PAGE);
+        if page == 0 {
+            return Err(ApiError::bad_request("page starts at 1"));
+        }
+        if !(1..=MAX_PER_PAGE).contains(&per_page) {
+            return Err(ApiError::bad_request(format!("per_page must be between 1 and {}", MAX_PER_PAGE)));
+        }
+        if page.checked_mul(per_page).is_none_or(|results| results > MAX_RESULTS) {
+            return Err(ApiError::bad_request(format!("only the first {} results can be paged through", MAX_RESULTS)));
+        }
+        Ok(Page { page, per_page })
+    }
+
+    fn finish(self) -> Result<(), ApiError> {
+        let mut unknown: Vec<String> = self.0.into_keys().collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort();
+        Err(ApiError::bad_request(format!("unknown parameters: {}", unknown.join(", "))))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Page {
+    page: usize,
+    per_page: usize,
+}
+
+impl Page {
+    /// How many hits to ask for: one more than the page ends at, to tell
+    /// whether another page follows.
+    fn k(&self) -> usize {
+        self.page * self.per_page + 1
+    }
+
+    fn cut<T>(&self, hits: Vec<T>) -> Paged<T> {
+        let has_more = hits.len() > self.page * self.per_page;
+        let results = hits.into_iter()
+            .skip((self.page - 1) * self.per_page)
+            .take(self.per_page)
+            .collect();
+        Paged { page: self.page, per_page: self.per_page, has_more, results }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Paged<T> {
+    page: usize,
+    per_page: usize,
+    has_more: bool,
+    results: Vec<T>,
+}
+
+#[derive(Serialize, Debug)]
+struct Media {
+    metadata: AnimeMetadata,
+    summary: Option<AnimeGeneratedSummary>,
+}
+
+/// A question, with the conversation returned by the previous one.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct AskRequest {
+    question: String,
+    #[serde(default)]
+    conversation: Conversation,
+}
+
+#[derive(Serialize, Debug)]
+struct AskResponse {
+    answer: Answer,
+    /// To send with the next question.
+    conversation: Conversation,
+}
+
+#[derive(Serialize, Debug)]
+struct NameCount {
+    name: String,
+    count: i64,
+}
+
+/// The routes of the search API. Every response, errors included, is JSON.
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/search", get(search))
+        .route("/media/{id}", get(media))
+        .route("/media/{id}/similar", get(similar))
+        .route("/recommendations", get(recommendations))
+        .route("/users/{name}/recommendations", get(user_recommendations))
+        .route("/ask", post(ask))
+        .route("/genres", get(genres))
+        .route("/themes", get(themes))
+        .route("/openapi.json", get(|| async { Json(openapi()) }))
+        .fallback(|| async { ApiError::not_found("no such endpoint, see /openapi.json") })
+        .with_state(Arc::new(state))
+}
+
+async fn health(State(state): State<Arc<ApiState>>) -> Result<Json<Value>, ApiError> {
+    // Fails when the database is unreachable.
+    state.searcher.storage().season_year_range().await?;
+    Ok(Json(json!({
+        "status": "ok",
+        "database": state.searcher.storage().backend_name(),
+        "embeddings": state.searcher.index().len(),
+    })))
+}
+
+async fn search(
+    State(state): State<Arc<ApiState>>,
+    params: Result<Query<HashMap<String, String>>, QueryRejection>,
+) -> Result<Json<Paged<RerankedHit>>, ApiError> {
+    let mut params = Params(params?.0);
+    let query = params.take("q").ok_or_else(|| ApiError::bad_request("q is required"))?;
+    let mode = params.parse("mode")?.unwrap_or(SearchMode::Hybrid);
+    let page = params.page()?;
+    let filter = params.filter()?;
+    params.finish()?;
+
+    let hits = state.searcher.search_reranked(mode, &query, page.k(), &filter, &state.weights).await?;
+    Ok(Json(page.cut(hits)))
+}
+
+async fn media(
+    State(state): State<Arc<ApiState>>,
+    id: Result<Path<i32>, PathRejection>,
+) -> Result<Json<Media>, ApiError> {
+    let Path(id) = id?;
+    let storage = state.searcher.storage();
+    let metadata = storage.media(&[id]).await?
+        .into_iter()
+        .next()
+        .filter(|metadata| state.searcher.policy(None).allows(metadata))
+        .ok_or_else(|| ApiError::not_found(format!("no media {}", id)))?;
+    let summary = storage.summaries(&[id]).await?
+        .into_iter()
+        .next()
+        .map(|summary| summary.generated_summary);
+    Ok(Json(Media { metadata, summary }))
+}
+
+async fn similar(
+    State(state): State<Arc<ApiState>>,
+    id: Result<Path<i32>, PathRejection>,
+    params: Result<Query<HashMap<String, String>>, QueryRejection>,
+) -> Result<Json<Paged<SearchHit>>, ApiError> {
+    let Path(id) = id?;
+    let mut params = Params(params?.0);
+    let page = params.page()?;
+    let filter = params.filter()?;
+    params.finish()?;
+
+    let hits = state.searcher.recommend_similar(id, page.k(), &filter, &state.similar).await?;
+    Ok(Json(page.cut(hits)))
+}
+
+async fn recommendations(
+    State(state): State<Arc<ApiState>>,
+    params: Result<Query<HashMap<String, String>>, QueryRejection>,
+) -> Result<Json<Paged<SearchHit>>, ApiError> {
+    let mut params = Params(params?.0);
+    let liked: Vec<i32> = params.list("liked")?;
+    let disliked: Vec<i32> = params.list("disliked")?;
+    let page = params.page()?;
+    let filter = params.filter()?;
+    params.finish()?;
+
+    let hits = state.searcher.recommend_for_taste(&liked, &disliked, page.k(), &filter, &state.taste).await?;
+    Ok(Json(page.cut(hits)))
+}
+
+async fn user_recommendations(
+    State(state): State<Arc<ApiState>>,
+    name: Result<Path<String>, PathRejection>,
+    params: Result<Query<HashMap<String, String>>, QueryRejection>,
+) -> Result<Json<Paged<SearchHit>>, ApiError> {
+    let Path(name) = name?;
+    let mut params = Params(params?.0);
+    let page = params.page()?;
+    let filter = params.filter()?;
+    params.finish()?;
+    if filter.user.is_some() || filter.exclude_seen_by.is_some() {
+        return Err(ApiError::bad_request("user and exclude_seen_by are taken from the path"));
+    }
+
+    let hits = state.searcher.recommend_for_user(&name, page.k(), &filter, &state.taste).await?;
+    Ok(Json(page.cut(hits)))
+}
+
+async fn ask(
+    State(state): State<Arc<ApiState>>,
+    params: Result<Query<HashMap<String, String>>, QueryRejection>,
+    request: Result<Json<AskRequest>, JsonRejection>,
+) -> Result<Json<AskResponse>, ApiError> {
+    let mut params = Params(params?.0);
+    let filter = params.filter()?;
+    params.finish()?;
+    let Json(AskRequest { question, mut conversation }) = request?;
+    if question.trim().is_empty() {
+        return Err(ApiError::bad_request("question must not be empty"));
+    }
+
+    let answer = state.answerer.ask(&state.searcher, &mut conversation, question.trim(), &filter).await?;
+    Ok(Json(AskResponse { answer, conversation }))
+}
+
+async fn genres(State(state): State<Arc<ApiState>>) -> Result<Json<Vec<NameCount>>, ApiError> {
+    let genres = state.searcher.storage().genre_counts().await?;
+    Ok(Json(genres.into_iter().map(|(name, count)| NameCount { name, count }).collect()))
+}
+
+async fn themes(State(state): State<Arc<ApiState>>) -> Result<Json<Vec<NameCount>>, ApiError> {
+    let themes = state.searcher.storage().theme_counts().await?;
+    Ok(Json(themes.into_iter().map(|(name, count)| NameCount { name, count }).collect()))
+}
+
+/// The OpenAPI 3 description of [`router`], served at `/openapi.json`.
+pub fn openapi() -> Value {
+    let integer = |name: &str, description: &str| json!({
+        "name": name, "in": "query", "required": false,
+        "description": description, "schema": {"type": "integer"},
+    });
+    let list = |name: &str, description: &str| json!({
+        "name": name, "in": "query", "required": false,
+        "description": format!("{}, comma-separated.", description), "schema": {"type": "string"},
+        "explode": false,
+    });
+    let mut paged = vec![
+        json!({"name": "page", "in": "query", "required": false, "schema": {"type": "integer", "minimum": 1, "default": 1}}),
+        json!({"name": "per_page", "in": "query", "required": false, "schema": {"type": "integer", "minimum": 1, "maximum": MAX_PER_PAGE, "default": DEFAULT_PER_PAGE}}),
+        integer("min_year", "Earliest season year, inclusive."),
+        integer("max_year", "Latest season year, inclusive."),
+        list("seasons", "Seasons such as WINTER or FALL, any of which matches"),
+        list("formats", "Formats such as TV or MOVIE, any of which matches"),
+        integer("min_mean_score", "Lowest AniList mean score, out of 100."),
+        integer("min_popularity", "Lowest AniList popularity."),
+        list("include_genres", "Genres the media must all have"),
+        list("exclude_genres", "Genres the media must not have"),
+        list("include_themes", "Generated themes the media must all have"),
+        list("exclude_themes", "Generated themes the media must not have"),
+        json!({"name": "exclude_seen_by", "in": "query", "required": false, "schema": {"type": "string"},
+            "description": "Leaves out media on this user's imported list, except planned ones."}),
+        json!({"name": "user", "in": "query", "required": false, "schema": {"type": "string"},
+            "description": "Searches under this user's content policy profile."}),
+    ];
+    let filters = paged.clone();
+    // Without `page` and `per_page`.
+    let ask = paged[2..].to_vec();
+    let id = json!({"name": "id", "in": "path", "required": true, "description": "AniList media id.", "schema": {"type": "integer"}});
+    let error = json!({"description": "Invalid parameters.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}});
+    let not_found = json!({"description": "Unknown media, or media without a summary embedding.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}});
+    let hits = json!({"description": "A page of hits, best first.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Hits"}}}});
+    let counts = |description: &str| json!({"description": description, "content": {"application/json": {"schema": {
+        "type": "array",
+        "items": {"type": "object", "properties": {"name": {"type": "string"}, "count": {"type": "integer"}}},
+    }}}});
+
+    let mut search = vec![
+        json!({"name": "q", "in": "query", "required": true, "description": "The natural-language query.", "schema": {"type": "string"}}),
+        json!({"name": "mode", "in": "query", "required": false, "schema": {"type": "string", "enum": ["semantic", "keyword", "hybrid"], "default": "hybrid"}}),
+    ];
+    search.append(&mut paged);
+    let mut similar = vec![id.clone()];
+    similar.extend(filters.clone());
+    let mut taste = vec![
+        list("liked", "AniList ids of liked media, at least one"),
+        list("disliked", "AniList ids of disliked media"),
+    ];
+    taste.extend(filters.clone());
+    let user = json!({"name": "name", "in": "path", "required": true, "description": "User name the list was imported under.", "schema": {"type": "string"}});
+    let mut user_taste = vec![user];
+    user_taste.extend(filters.into_iter().filter(|parameter| !["exclude_seen_by", "user"].contains(&parameter["name"].as_str().unwrap_or_default())));
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "LAM search API", "version": env!("CARGO_PKG_VERSION")},
+        "paths": {
+            "/health": {"get": {
+                "summary": "Checks that the database answers.",
+                "responses": {"200": {"description": "The backend and the number of loaded embeddings."}},
+            }},
+            "/search": {"get": {
+                "summary": "Searches media by their generated summaries, keywords or both.",
+                "parameters": search,
+                "responses": {"200": hits, "400": error},
+            }},
+            "/media/{id}": {"get": {
+                "summary": "Returns a media with its generated summary.",
+                "parameters": [id],
+                "responses": {
+                    "200": {"description": "The media.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Media"}}}},
+                    "404": not_found,
+                },
+            }},
+            "/media/{id}/similar": {"get": {
+                "summary": "Recommends media like this one, leaving out its franchise.",
+                "parameters": similar,
+                "responses": {"200": hits, "400": error, "404": not_found},
+            }},
+            "/recommendations": {"get": {
+                "summary": "Recommends media for someone who liked and disliked the given media, diversified so that no franchise fills the page.",
+                "parameters": taste,
+                "responses": {"200": hits, "400": error, "404": not_found},
+            }},
+            "/users/{name}/recommendations": {"get": {
+                "summary": "Recommends media for a user from their imported list, under their content policy profile and leaving out what they have seen.",
+                "parameters": user_taste,
+                "responses": {"200": hits, "400": error},
+            }},
+            "/ask": {"post": {
+                "summary": "Answers a question from the media retrieved for it and those of the conversation so far.",
+                "parameters": ask,
+                "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Question"}}}},
+                "responses": {
+                    "200": {"description": "The answer and the conversation to send with the next question.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Answer"}}}},
+                    "400": error,
+                    "502": {"description": "The LLM could not be reached or gave an unusable answer.", "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}},
+                },
+            }},
+            "/genres": {"get": {
+                "summary": "Lists the AniList genres with their number of media.",
+                "responses": {"200": counts("Genres, by name.")},
+            }},
+            "/themes": {"get": {
+                "summary": "Lists the generated themes with their number of media.",
+                "responses": {"200": counts("Themes, by name.")},
+            }},
+        },
+        "components": {"schemas": {
+            "Error": {"type": "object", "properties": {"error": {"type": "string"}}},
+            "Media": {"type": "object", "properties": {
+                "metadata": {"type": "object", "description": "AniList metadata, with AniList's field names."},
+                "summary": {"type": "object", "nullable": true, "description": "The generated summary, genres and themes."},
+            }},
+            "Question": {"type": "object", "required": ["question"], "properties": {
+                "question": {"type": "string"},
+                "conversation": {"type": "object", "description": "The conversation returned with the previous answer, if any."},
+            }},
+            "Answer": {"type": "object", "properties": {
+                "answer": {"type": "object", "properties": {
+                    "text": {"type": "string", "description": "The answer, citing media as [123]."},
+                    "citations": {"type": "array", "items": {"type": "integer"}},
+                    "refused": {"type": "boolean", "description": "True when the media found were not enough to answer."},
+                }},
+                "conversation": {"type": "object"},
+            }},
+            "Hits": {"type": "object", "properties": {
+                "page": {"type": "integer"},
+                "per_page": {"type": "integer"},
+                "has_more": {"type": "boolean"},
+                "results": {"type": "array", "items": {"allOf": [
+                    {"$ref": "#/components/schemas/Media"},
+                    {"type": "object", "properties": {
+                        "score": {"type": "number"},
+                        "justification": {"type": "string", "description": "Why the LLM reranked the hit there, when reranking is on."},
+                    }},
+                ]}},
+            }},
+        }},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::{LlmConfig, SearchIndexConfig};
+    use crate::content_policy::PolicyProfiles;
+    use crate::embedder::Embedder;
+    use crate::llm::LlmClient;
+    use crate::storage::TempStorage;
+    use crate::types::{AnimeMetadataRow, MediaFormat, Season};
+    use crate::vector_index::IndexKind;
+
+    fn params(query: &[(&str, &str)]) -> Params {
+        Params(query.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn filters_are_read_from_parameters() {
+        let mut params = params(&[
+            ("min_year", " 2010 "),
+            ("seasons", "fall, spring,"),
+            ("formats", "tv"),
+            ("include_genres", "Drama,Romance"),
+            ("exclude_seen_by", ""),
+            ("user", "kana"),
+        ]);
+        let filter = params.filter().unwrap();
+        assert_eq!(filter.min_year, Some(2010));
+        assert_eq!(filter.seasons, vec![Season::Fall, Season::Spring]);
+        assert_eq!(filter.formats, vec![MediaFormat::Tv]);
+        assert_eq!(filter.include_genres, vec!["Drama", "Romance"]);
+        assert_eq!(filter.exclude_seen_by, None);
+        assert_eq!(filter.user.as_deref(), Some("kana"));
+        assert!(params.finish().is_ok());
+    }
+
+    #[test]
+    fn invalid_filters_are_reported_by_name() {
+        let error = params(&[("formats", "tv,manga")]).filter().unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(error.message.starts_with("invalid formats:"), "{}", error.message);
+
+        let error = params(&[("min_year", "soon")]).filter().unwrap_err();
+        assert!(error.message.starts_with("invalid min_year \"soon\""), "{}", error.message);
+
+        let error = params(&[("min_year", "2020"), ("max_year", "2010")]).filter().unwrap_err();
+        assert_eq!(error.message, "min_year must not be after max_year");
+    }
+
+    /// The routes over a database of media 1 and of media 2, a hentai the
+    /// default content policy denies.
+    async fn app(name: &str) -> (Router, TempStorage) {
+        let db = TempStorage::new(&format!("api_{}", name)).await;
+        let anime = |id: i32, is_adult: bool| AnimeMetadataRow {
+            id,
+            mal_id: None,
+            english_title: Some(format!("Anime {}", id)),
+            romaji_title: None,
+            season: None,
+            season_year: 2020,
+            format: None,
+            description: None,
+            popularity: None,
+            mean_score: None,
+            is_adult: Some(is_adult),
+        }
+        .into_metadata(vec![], vec![]);
+        db.upsert_metadata(vec![anime(1, false), anime(2, true)]).await.unwrap();
+
+        let index = SearchIndexConfig { kind: IndexKind::Exact, dir: std::env::temp_dir().display().to_string(), ef_search: 10 };
+        let searcher = Searcher::load(db.storage.clone(), Arc::new(Embedder::tiny()), PolicyProfiles::default(), &index).await.unwrap();
+        let llm = LlmConfig { url: String::new(), model: "test".to_string(), api_keys: vec![] };
+        let answerer = Answerer::new(LlmClient::new(&llm), db.storage.clone(), HybridWeights::default());
+        let app = router(ApiState {
+            searcher,
+            weights: HybridWeights::default(),
+            similar: SimilarOptions::default(),
+            taste: TasteOptions::default(),
+            answerer,
+        });
+        (app, db)
+    }
+
+    async fn get(app: Router, uri: &str) -> (StatusCode, Value) {
+        let response = app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_parameters_are_rejected() {
+        let (app, _db) = app("unknown").await;
+        let (status, body) = get(app, "/search?q=knight&sort=new&colour=red").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "unknown parameters: colour, sort");
+    }
+
+    #[tokio::test]
+    async fn pages_start_at_one_and_stop_at_the_limit() {
+        let (app, _db) = app("pages").await;
+        let (status, body) = get(app.clone(), "/search?q=knight&page=0").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "page starts at 1");
+
+        let (status, body) = get(app.clone(), "/search?q=knight&page=11&per_page=50").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], format!("only the first {} results can be paged through", MAX_RESULTS));
+
+        // Would overflow when multiplied by per_page.
+        let (status, _) = get(app.clone(), &format!("/search?q=knight&page={}&per_page=50", usize::MAX / 2)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = get(app, "/search?q=knight&per_page=5").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!(body["per_page"], 5);
+    }
+
+    #[tokio::test]
+    async fn media_the_policy_denies_are_not_found() {
+        let (app, _db) = app("policy").await;
+        let (status, body) = get(app.clone(), "/media/1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["metadata"]["id"], 1);
+
+        let (status, body) = get(app.clone(), "/media/2").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "no media 2");
+        let (status, _) = get(app, "/media/3").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
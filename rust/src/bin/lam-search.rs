@@ -0,0 +1,54 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use lam::answer::Answerer;
+use lam::embedder::Embedder;
+use lam::llm::LlmClient;
+use lam::query_parser::QueryParser;
+use lam::rerank::Reranker;
+use lam::repl::Repl;
+use lam::search::Searcher;
+use lam::config::Config;
+use lam::{logging, migrations, storage::Storage};
+use sqlx::Error;
+use tracing::warn;
+
+/// Interactive search over the local database: type a query, then `help` for
+/// the other commands.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = Config::load(None).map_err(|e| Error::Configuration(e.into()))?;
+    logging::init(&config.logging()).map_err(|e| Error::Configuration(e.into()))?;
+    for name in &config.ignored_env {
+        warn!(var = %name, "Ignoring an env var that names no setting");
+    }
+    let policy = config.policy_profiles().map_err(|e| Error::Configuration(e.into()))?;
+    let embedding = config.embedding().map_err(|e| Error::Configuration(e.into()))?;
+    let index = config.search_index().map_err(|e| Error::Configuration(e.into()))?;
+    let rerank_candidates = config.rerank_candidates().map_err(|e| Error::Configuration(e.into()))?;
+    let weights = config.hybrid_weights().map_err(|e| Error::Configuration(e.into()))?;
+    let similar = config.similar_options().map_err(|e| Error::Configuration(e.into()))?;
+    let taste = config.taste_options().map_err(|e| Error::Configuration(e.into()))?;
+    let storage = Storage::connect(&config.database()).await?;
+    migrations::migrate(&storage).await?;
+
+    let embedder = Embedder::load(Path::new(&embedding.model_dir), embedding.model)
+        .map_err(|e| Error::Configuration(format!("failed to load model from {}: {}", embedding.model_dir, e).into()))?;
+    let mut searcher = Searcher::load(storage.clone(), Arc::new(embedder), policy, &index)
+        .await
+        .map_err(|e| Error::Configuration(e.to_string().into()))?;
+    let llm = LlmClient::new(&config.llm());
+    if config.parse_queries() {
+        searcher = searcher.with_query_parser(QueryParser::new(llm.clone(), storage.clone()));
+    }
+    if config.rerank() {
+        searcher = searcher.with_reranker(Reranker::new(llm.clone(), storage.clone(), rerank_candidates));
+    }
+    let answerer = Answerer::new(llm, storage, weights.clone());
+
+    Repl::new(searcher, weights, similar, taste)
+        .with_answerer(answerer)
+        .run()
+        .await
+        .map_err(|e| Error::Configuration(e.to_string().into()))
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use lam::answer::Answerer;
+use lam::api::{router, ApiState};
+use lam::db_loader::{DbLoader, EmbeddingLoader, MetadataLoader, SummaryLoader};
+use lam::db_query::DbQuery;
+use lam::downloader::Downloader;
+use lam::embedder::{Embedder, EmbeddingGenerator};
+use lam::embedding_import::import_legacy_embeddings;
+use lam::hnsw::recall_at_k;
+use lam::llm::LlmClient;
+use lam::metrics::{self, metrics};
+use lam::progress::Progress;
+use lam::query_parser::QueryParser;
+use lam::rerank::Reranker;
+use lam::shutdown::Shutdown;
+use lam::repl::{print_hits, Repl};
+use lam::search::{SearchFilter, SearchMode, Searcher};
+use lam::summarizer::Summarizer;
+use lam::types::{AnimeEmbedding, AnimeMetadata, AnimeSummary, EmbeddingSource};
+use lam::user_list::{import_anilist_list, import_mal_export};
+use lam::vector_index::{ExactIndex, HnswIndex, IndexKind};
+use lam::config::{self, Config};
+use lam::{migrations, storage::Storage};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::{info, info_span, Instrument};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+// Media written to an export at a time.
+const EXPORT_CHUNK_SIZE: usize = 500;
+
+/// Connects to the configured database and applies pending migrations.
+async fn storage(config: &Config) -> Result<Storage> {
+    let storage = Storage::connect(&config.database()).await?;
+    migrations::migrate(&storage).await?;
+    Ok(storage)
+}
+
+/// Loads the embedding model and indexes the summary embeddings. Queries
+/// are parsed by the LLM first when `parse_queries` is set, and their hits
+/// reranked when `rerank` is.
+async fn searcher(config: &Config, storage: Storage) -> Result<Searcher> {
+    let embedding = config.embedding()?;
+    let embedder = Embedder::load(Path::new(&embedding.model_dir), embedding.model)
+        .map_err(|e| format!("failed to load model from {}: {}", embedding.model_dir, e))?;
+    let mut searcher = Searcher::load(storage.clone(), Arc::new(embedder), config.policy_profiles()?, &config.search_index()?).await?;
+    let llm = LlmClient::new(&config.llm());
+    if config.parse_queries() {
+        searcher = searcher.with_query_parser(QueryParser::new(llm.clone(), storage.clone()));
+    }
+    if config.rerank() {
+        searcher = searcher.with_reranker(Reranker::new(llm, storage, config.rerank_candidates()?));
+    }
+    Ok(searcher)
+}
+
+/// Serves the metrics if an endpoint is configured and logs them periodically.
+async fn start_metrics(config: &Config) -> Result<()> {
+    let options = config.metrics_options()?;
+    if let Some(listen) = options.listen {
+        let listener = TcpListener::bind(&listen).await?;
+        info!(%listen, "Serving metrics");
+        task::spawn(async move { axum::serve(listener, metrics::router()).await }.in_current_span());
+    }
+    if options.dump_interval_secs > 0 {
+        let mut interval = tokio::time::interval(Duration::from_secs(options.dump_interval_secs));
+        task::spawn(async move {
+            // The first tick is immediate.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                metrics().dump();
+            }
+        }.in_current_span());
+    }
+    Ok(())
+}
+
+pub async fn crawl(config: &Config) -> Result<()> {
+    let policy = config.content_policy()?;
+    let options = config.crawl_options()?;
+    let storage = storage(config).await?;
+    start_metrics(config).await?;
+    let (sender, receiver) = mpsc::channel::<Option<Vec<AnimeMetadata>>>(4);
+    metrics().watch_queue("metadata", &sender);
+    let progress = Progress::new("crawl", "pages", Downloader::seasons(&options));
+    let shutdown = Shutdown::on_ctrl_c();
+    let mut downloader = Downloader::new(sender, policy, options, progress.clone(), shutdown.clone());
+    let mut db_loader = MetadataLoader::new(receiver, storage);
+
+    let (downloaded, loaded) = tokio::try_join!(
+        task::spawn(async move { downloader.download().await }.instrument(info_span!("downloader"))),
+        task::spawn(async move { db_loader.start_load_job().await }.instrument(info_span!("loader"))),
+    )?;
+    downloaded?;
+    loaded?;
+    progress.finish();
+    metrics().dump();
+    println!("Downloaded {} media in {} pages", metrics().media_downloaded.get(), metrics().pages_downloaded.get());
+    if shutdown.requested() {
+        println!("Interrupted, run it again to continue where it stopped");
+    }
+    Ok(())
+}
+
+/// Summarizes with one worker per API key; the media are handed to whichever
+/// worker is ready. On Ctrl-C no more media are handed out, and the requests
+/// in flight finish and are loaded before it returns.
+pub async fn summarize(config: &Config) -> Result<()> {
+    let llm = config.llm();
+    if llm.api_keys.is_empty() {
+        return Err(format!("{} or {} must be set", config::LLM_API_KEYS_ENV, config::GROQ_API_KEYS_ENV).into());
+    }
+    let options = config.summarize_options()?;
+    let policy = config.content_policy()?;
+    let storage = storage(config).await?;
+    start_metrics(config).await?;
+    let progress = Progress::new("summarize", "media", storage.pending_summary_count().await? as u64);
+    let shutdown = Shutdown::on_ctrl_c();
+
+    let mut metadata_senders = vec![];
+    let mut metadata_receivers = vec![];
+    for _ in 0..llm.api_keys.len() {
+        let (sender, receiver) = mpsc::channel::<Option<AnimeMetadata>>(1);
+        metrics().watch_queue("metadata", &sender);
+        metadata_senders.push(sender);
+        metadata_receivers.push(receiver);
+    }
+    let (ready_sender, ready_receiver) = mpsc::channel::<usize>(llm.api_keys.len() + 1);
+    let (summary_sender, summary_receiver) = mpsc::channel::<Option<AnimeSummary>>(128);
+    metrics().watch_queue("ready", &ready_sender);
+    metrics().watch_queue("summary", &summary_sender);
+
+    let mut db_query = DbQuery::new(metadata_senders, ready_receiver, storage.clone(), policy, progress.clone(), shutdown.clone());
+    let mut db_loader = SummaryLoader::new(summary_receiver, storage.clone());
+    let summarizers = metadata_receivers.into_iter()
+        .enumerate()
+        .map(|(idx, metadata_receiver)| {
+            let span = info_span!("summarizer", idx, key = %llm.api_keys[idx]);
+            let mut summarizer = Summarizer::new(
+                metadata_receiver,
+                summary_sender.clone(),
+                ready_sender.clone(),
+                idx,
+                &llm,
+                &options,
+                progress.clone(),
+            );
+            task::spawn(async move { summarizer.start_summarize_job().await }.instrument(span))
+        })
+        .collect::<Vec<_>>();
+    // The loader stops once every summarizer has dropped its sender, after
+    // loading what they sent, and the query once no summarizer is left to
+    // take media.
+    drop(summary_sender);
+    drop(ready_sender);
+
+    let (queried, loaded, _) = tokio::try_join!(
+        task::spawn(async move { db_query.query_all_years().await }.instrument(info_span!("query"))),
+        task::spawn(async move { db_loader.start_load_job().await }.instrument(info_span!("loader"))),
+        future::try_join_all(summarizers),
+    )?;
+    queried?;
+    loaded?;
+    progress.finish();
+    metrics().dump();
+    println!(
+        "Summarized {} media, {} replies could not be parsed, {} requests failed, {} media have no summary",
+        metrics().summaries_produced.get(),
+        metrics().summary_parse_failures.get(),
+        metrics().summary_request_failures.get(),
+        storage.pending_summary_count().await?,
+    );
+    if shutdown.requested() {
+        println!("Interrupted, run it again to continue where it stopped");
+    }
+    Ok(())
+}
+
+pub async fn embed(config: &Config) -> Result<()> {
+    let embedding = config.embedding()?;
+    let storage = storage(config).await?;
+    let embedder = Embedder::load(Path::new(&embedding.model_dir), embedding.model)
+        .map_err(|e| format!("failed to load model from {}: {}", embedding.model_dir, e))?;
+    info!(model = %embedder.model_name, "Loaded embedding model");
+
+    let (sender, receiver) = mpsc::channel::<Option<Vec<AnimeEmbedding>>>(4);
+    let mut generator = EmbeddingGenerator::new(storage.clone(), Arc::new(embedder), embedding.source, sender, embedding.batch_size);
+    let mut db_loader = EmbeddingLoader::new(receiver, storage);
+    let (generated, loaded) = tokio::try_join!(
+        task::spawn(async move { generator.start_embed_job().await }.instrument(info_span!("embedder"))),
+        task::spawn(async move { db_loader.start_load_job().await }.instrument(info_span!("loader"))),
+    )?;
+    generated?;
+    loaded?;
+    Ok(())
+}
+
+/// Builds or updates the HNSW index. With `benchmark`, `(k, queries)`, the
+/// index is compared with exact search using stored vectors as queries.
+pub async fn index(config: &Config, benchmark: Option<(usize, usize)>) -> Result<()> {
+    let model = config.embedding_model();
+    let source = config.embedding_source()?;
+    let index = config::SearchIndexConfig { kind: IndexKind::Hnsw, ..config.search_index()? };
+    let storage = storage(config).await?;
+
+    let started = Instant::now();
+    let hnsw = HnswIndex::open(&storage, &model, source, &index).await?;
+    println!("{} {} index has {} vectors ({:.1?})", model, source.as_str(), hnsw.len(), started.elapsed());
+    if let Some((k, queries)) = benchmark {
+        benchmark_index(&storage, &hnsw, &model, source, k, queries).await?;
+    }
+    Ok(())
+}
+
+async fn benchmark_index(storage: &Storage, hnsw: &HnswIndex, model: &str, source: EmbeddingSource, k: usize, queries: usize) -> Result<()> {
+    let exact = ExactIndex::load(storage, model, source).await?;
+    if exact.is_empty() {
+        println!("No embeddings to benchmark");
+        return Ok(());
+    }
+    let step = (exact.len() / queries.max(1)).max(1);
+    let query_ids: Vec<i32> = storage.embeddings(model, source).await?
+        .into_iter()
+        .step_by(step)
+        .take(queries)
+        .map(|embedding| embedding.id)
+        .collect();
+    let vectors: Vec<Vec<f32>> = query_ids.iter()
+        .filter_map(|id| hnsw.graph().vector(*id).map(|v| v.to_vec()))
+        .collect();
+
+    let mut exact_time = Duration::ZERO;
+    let mut hnsw_time = Duration::ZERO;
+    let mut exact_ids = Vec::with_capacity(vectors.len());
+    let mut hnsw_ids = Vec::with_capacity(vectors.len());
+    for vector in &vectors {
+        let started = Instant::now();
+        let ranked = exact.rank(vector, None);
+        exact_time += started.elapsed();
+        exact_ids.push(ranked.into_iter().take(k).map(|(id, _)| id).collect::<Vec<_>>());
+
+        let started = Instant::now();
+        let ranked = hnsw.rank(vector, k, None);
+        hnsw_time += started.elapsed();
+        hnsw_ids.push(ranked.into_iter().take(k).map(|(id, _)| id).collect::<Vec<_>>());
+    }
+    let n = vectors.len().max(1) as u32;
+    println!("{} queries over {} vectors", vectors.len(), exact.len());
+    println!("exact: {:.1?} per query", exact_time / n);
+    println!("hnsw:  {:.1?} per query, recall@{} {:.3}", hnsw_time / n, k, recall_at_k(&exact_ids, &hnsw_ids));
+    Ok(())
+}
+
+/// Prints the hits of `query`, or starts the interactive prompt when it is
+/// empty.
+pub async fn search(config: &Config, query: &str, mode: SearchMode, k: usize, as_json: bool) -> Result<()> {
+    let weights = config.hybrid_weights()?;
+    let similar = config.similar_options()?;
+    let taste = config.taste_options()?;
+    let storage = storage(config).await?;
+    let searcher = searcher(config, storage.clone()).await?;
+    if query.trim().is_empty() {
+        let answerer = Answerer::new(LlmClient::new(&config.llm()), storage, weights.clone());
+        Repl::new(searcher, weights, similar, taste).with_answerer(answerer).run().await?;
+        return Ok(());
+    }
+    let hits = searcher.search_reranked(mode, query, k, &SearchFilter::default(), &weights).await?;
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+    } else {
+        print_hits(&hits);
+    }
+    Ok(())
+}
+
+pub async fn serve(config: &Config) -> Result<()> {
+    let weights = config.hybrid_weights()?;
+    let similar = config.similar_options()?;
+    let taste = config.taste_options()?;
+    let listen = config.listen()?;
+    let storage = storage(config).await?;
+    let searcher = searcher(config, storage.clone()).await?;
+    let answerer = Answerer::new(LlmClient::new(&config.llm()), storage, weights.clone());
+    let listener = TcpListener::bind(listen).await?;
+    info!(%listen, "Listening");
+    axum::serve(listener, router(ApiState { searcher, weights, similar, taste, answerer })).await?;
+    Ok(())
+}
+
+/// Writes one `{"metadata": ..., "summary": ...}` object per line, in id
+/// order. The content policy is not applied.
+pub async fn export(config: &Config, output: Option<&Path>) -> Result<()> {
+    let storage = storage(config).await?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let ids = storage.filtered_media_ids(&SearchFilter::default()).await?;
+    for chunk in ids.chunks(EXPORT_CHUNK_SIZE) {
+        let mut summaries: HashMap<i32, AnimeSummary> = storage.summaries(chunk).await?
+            .into_iter()
+            .map(|summary| (summary.id, summary))
+            .collect();
+        let mut media = storage.media(chunk).await?;
+        media.sort_by_key(|anime| anime.id);
+        for anime in media {
+            let summary = summaries.remove(&anime.id).map(|summary| summary.generated_summary);
+            writeln!(out, "{}", json!({"metadata": anime, "summary": summary}))?;
+        }
+    }
+    out.flush()?;
+    if let Some(path) = output {
+        info!(count = ids.len(), path = %path.display(), "Exported media");
+    }
+    Ok(())
+}
+
+pub async fn migrate(config: &Config) -> Result<()> {
+    let database = config.database();
+    let storage = Storage::connect(&database).await?;
+    let before = migrations::current_version(&storage).await?;
+    let applied = migrations::migrate(&storage).await?;
+    if applied.is_empty() {
+        println!("Schema of {} ({}) is up to date at version {}", database.url, storage.backend_name(), before);
+    } else {
+        println!("Migrated schema of {} ({}) from version {} to {}", database.url, storage.backend_name(), before, migrations::latest_version());
+    }
+    Ok(())
+}
+
+pub async fn stats(config: &Config) -> Result<()> {
+    let database = config.database();
+    let storage = storage(config).await?;
+    let counts = storage.row_counts().await?;
+    println!("Database: {} ({}), schema version {}", database.url, storage.backend_name(), migrations::current_version(&storage).await?);
+    match storage.season_year_range().await? {
+        Some((min, max)) => println!("Media: {} from {} to {}", counts.media, min, max),
+        None => println!("Media: {}", counts.media),
+    }
+    println!("Summaries: {}", counts.summaries);
+    println!("Genres: {}", storage.genre_counts().await?.len());
+    println!("Generated themes: {}", storage.theme_counts().await?.len());
+    for set in storage.embedding_sets().await? {
+        println!("Embeddings {} {}: {} vectors of dimension {}, last written {}", set.model, set.source, set.count, set.dimension, set.last_created_at);
+    }
+    println!("User lists: {} entries of {} users", counts.list_entries, counts.list_users);
+    Ok(())
+}
+
+pub async fn import_embeddings(config: &Config, ids: &Path, embeddings: &Path, model: &str) -> Result<()> {
+    let source = config.embedding_source()?;
+    let storage = storage(config).await?;
+    let imported = import_legacy_embeddings(&storage, ids, embeddings, model, source).await?;
+    println!("Imported {} {} embeddings of {}", imported, source.as_str(), model);
+    for set in storage.embedding_sets().await? {
+        println!("{} {}: {} vectors of dimension {}, last written {}", set.model, set.source, set.count, set.dimension, set.last_created_at);
+    }
+    Ok(())
+}
+
+pub async fn import_list(config: &Config, anilist: Option<&str>, mal: Option<&Path>, user: Option<&str>) -> Result<()> {
+    let storage = storage(config).await?;
+    if let Some(user_name) = anilist {
+        let imported = import_anilist_list(&storage, &config.crawl_options()?, user_name).await?;
+        println!("Imported {} AniList entries of {}", imported, user_name);
+    }
+    if let Some(path) = mal {
+        let import = import_mal_export(&storage, path, user).await?;
+        for (mal_id, title) in &import.unmatched {
+            println!("Skipped MAL anime {} ({}), it has not been crawled", mal_id, title);
+        }
+        println!("Imported {} MAL entries of {}, skipped {}", import.imported, import.user_name, import.unmatched.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use lam::config::Config;
+use lam::logging::{self, LogFormat};
+use lam::search::{Fusion, SearchMode};
+use lam::vector_index::IndexKind;
+use tracing::{info_span, warn, Instrument};
+
+mod commands;
+
+/// Search animes with LLM-generated summaries.
+///
+/// Settings are read from `lam.toml` (or the file given with `--config` or
+/// `LAM_CONFIG`), overridden by env vars such as `LAM_DATABASE_URL` or
+/// `LAM_HYBRID__RRF_K`, and by the flags below.
+#[derive(Parser)]
+#[command(name = "lam", version)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+// The settings flags override the config file and the env vars: each set of
+// flags applies itself to the `Config` the commands are given.
+#[derive(Args)]
+struct GlobalArgs {
+    /// Config file [default: lam.toml]
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// sqlite: or postgres: database URL [default: sqlite://anime_metadata.db]
+    #[arg(long, global = true, value_name = "URL")]
+    database_url: Option<String>,
+    /// Content policy profile [default: default]
+    #[arg(long, global = true, value_name = "NAME")]
+    content_profile: Option<String>,
+    /// text or json [default: text]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Log filter such as warn or info,lam::summarizer=debug [default: info]
+    #[arg(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
+}
+
+#[derive(Args)]
+struct LlmArgs {
+    /// OpenAI-compatible chat completions endpoint
+    #[arg(long, value_name = "URL")]
+    llm_url: Option<String>,
+    #[arg(long, value_name = "MODEL")]
+    llm_model: Option<String>,
+}
+
+#[derive(Args)]
+struct MetricsArgs {
+    /// Serve Prometheus metrics at http://ADDRESS/metrics during the run
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_listen: Option<String>,
+}
+
+#[derive(Args)]
+struct ModelArgs {
+    /// Directory of the embedding model
+    #[arg(long, value_name = "DIR")]
+    model_dir: Option<PathBuf>,
+    /// Embedding model name recorded with the vectors
+    #[arg(long, value_name = "NAME")]
+    model: Option<String>,
+}
+
+#[derive(Args)]
+struct IndexArgs {
+    /// exact or hnsw
+    #[arg(long, value_name = "KIND")]
+    index: Option<IndexKind>,
+    /// Where HNSW indexes are saved
+    #[arg(long, value_name = "DIR")]
+    index_dir: Option<PathBuf>,
+    #[arg(long, value_name = "N")]
+    ef_search: Option<usize>,
+    /// rrf or weighted
+    #[arg(long, value_name = "METHOD")]
+    fusion: Option<Fusion>,
+}
+
+#[derive(Args)]
+struct SearchLlmArgs {
+    #[command(flatten)]
+    llm: LlmArgs,
+    /// Top hits reranked by the LLM when rerank is on [default: 20]
+    #[arg(long, value_name = "N")]
+    rerank_candidates: Option<usize>,
+}
+
+/// Replaces a setting when its flag was given.
+fn set<T>(setting: &mut Option<T>, flag: Option<T>) {
+    if flag.is_some() {
+        *setting = flag;
+    }
+}
+
+impl GlobalArgs {
+    fn apply(self, config: &mut Config) {
+        set(&mut config.database_url, self.database_url);
+        set(&mut config.content_profile, self.content_profile);
+        set(&mut config.log_format, self.log_format);
+        set(&mut config.log_level, self.log_level);
+    }
+}
+
+impl LlmArgs {
+    fn apply(self, config: &mut Config) {
+        set(&mut config.llm_url, self.llm_url);
+        set(&mut config.llm_model, self.llm_model);
+    }
+}
+
+impl MetricsArgs {
+    fn apply(self, config: &mut Config) {
+        set(&mut config.metrics.listen, self.metrics_listen);
+    }
+}
+
+impl ModelArgs {
+    fn apply(self, config: &mut Config) {
+        set(&mut config.embedding_model_dir, self.model_dir.map(|dir| dir.display().to_string()));
+        set(&mut config.embedding_model, self.model);
+    }
+}
+
+impl IndexArgs {
+    fn apply(self, config: &mut Config) {
+        set(&mut config.search_index, self.index);
+        set(&mut config.index_dir, self.index_dir.map(|dir| dir.display().to_string()));
+        set(&mut config.ef_search, self.ef_search);
+        if let Some(fusion) = self.fusion {
+            config.hybrid.fusion = fusion;
+        }
+    }
+}
+
+impl SearchLlmArgs {
+    fn apply(self, config: &mut Config) {
+        self.llm.apply(config);
+        set(&mut config.rerank_candidates, self.rerank_candidates);
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download anime metadata from AniList
+    Crawl {
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    /// Generate summaries of the crawled media with the LLM
+    Summarize {
+        #[command(flatten)]
+        llm: LlmArgs,
+        #[command(flatten)]
+        metrics: MetricsArgs,
+    },
+    /// Embed the summaries that have no embedding yet
+    Embed {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// summary or description
+        #[arg(long, value_name = "SOURCE")]
+        source: Option<String>,
+        #[arg(long, value_name = "N")]
+        batch_size: Option<usize>,
+    },
+    /// Build or update the HNSW index
+    Index {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        index: IndexArgs,
+        /// Compare the index with exact search
+        #[arg(long)]
+        benchmark: bool,
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        /// Stored vectors used as benchmark queries
+        #[arg(long, default_value_t = 200)]
+        queries: usize,
+    },
+    /// Search once, or start the interactive prompt without a query
+    Search {
+        query: Vec<String>,
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        index: IndexArgs,
+        #[command(flatten)]
+        llm: SearchLlmArgs,
+        #[arg(long, default_value = "hybrid")]
+        mode: SearchMode,
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        /// Print the hits as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Serve the HTTP search API
+    Serve {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        index: IndexArgs,
+        #[command(flatten)]
+        llm: SearchLlmArgs,
+        /// Address to listen on [default: 127.0.0.1:8080]
+        #[arg(long, value_name = "ADDRESS")]
+        listen: Option<String>,
+    },
+    /// Write every media and its summary as JSON lines
+    Export {
+        /// Output file [default: stdout]
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Apply pending schema migrations
+    Migrate,
+    /// Show what the database holds
+    Stats,
+    /// Import the ids.txt/embeddings.csv pair of generate_embeddings.py
+    ImportEmbeddings {
+        #[arg(long, default_value = "ids.txt", value_name = "FILE")]
+        ids: PathBuf,
+        #[arg(long, default_value = "embeddings.csv", value_name = "FILE")]
+        embeddings: PathBuf,
+        #[arg(long, default_value = lam::embedding_import::LEGACY_EMBEDDING_MODEL, value_name = "NAME")]
+        model: String,
+        #[arg(long, value_name = "SOURCE")]
+        source: Option<String>,
+    },
+    /// Import a user's AniList list or MAL export
+    ImportList {
+        /// AniList user name
+        #[arg(long, value_name = "USER", conflicts_with = "mal", required_unless_present = "mal")]
+        anilist: Option<String>,
+        /// MAL XML export
+        #[arg(long, value_name = "FILE")]
+        mal: Option<PathBuf>,
+        /// User name of the MAL export [default: the name in the export]
+        #[arg(long, value_name = "USER", requires = "mal")]
+        user: Option<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut config = match Config::load(cli.global.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    cli.global.apply(&mut config);
+    if let Err(e) = logging::init(&config.logging()) {
+        eprintln!("error: {}", e);
+        return ExitCode::FAILURE;
+    }
+    for name in &config.ignored_env {
+        warn!(var = %name, "Ignoring an env var that names no setting");
+    }
+    // The pipeline stages get a span each, so their logs can be told apart
+    // when several run against the same database.
+    let result = match cli.command {
+        Command::Crawl { metrics } => {
+            metrics.apply(&mut config);
+            commands::crawl(&config).instrument(info_span!("crawl")).await
+        }
+        Command::Summarize { llm, metrics } => {
+            llm.apply(&mut config);
+            metrics.apply(&mut config);
+            commands::summarize(&config).instrument(info_span!("summarize")).await
+        }
+        Command::Embed { model, source, batch_size } => {
+            model.apply(&mut config);
+            set(&mut config.embedding_source, source);
+            set(&mut config.embedding_batch_size, batch_size);
+            commands::embed(&config).instrument(info_span!("embed")).await
+        }
+        Command::Index { model, index, benchmark, k, queries } => {
+            model.apply(&mut config);
+            index.apply(&mut config);
+            commands::index(&config, benchmark.then_some((k, queries))).instrument(info_span!("index")).await
+        }
+        Command::Search { query, model, index, llm, mode, k, json } => {
+            model.apply(&mut config);
+            index.apply(&mut config);
+            llm.apply(&mut config);
+            commands::search(&config, &query.join(" "), mode, k, json).await
+        }
+        Command::Serve { model, index, llm, listen } => {
+            model.apply(&mut config);
+            index.apply(&mut config);
+            llm.apply(&mut config);
+            set(&mut config.listen, listen);
+            commands::serve(&config).await
+        }
+        Command::Export { output } => commands::export(&config, output.as_deref()).await,
+        Command::Migrate => commands::migrate(&config).await,
+        Command::Stats => commands::stats(&config).await,
+        Command::ImportEmbeddings { ids, embeddings, model, source } => {
+            set(&mut config.embedding_source, source);
+            commands::import_embeddings(&config, &ids, &embeddings, &model).await
+        }
+        Command::ImportList { anilist, mal, user } => commands::import_list(&config, anilist.as_deref(), mal.as_deref(), user.as_deref()).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
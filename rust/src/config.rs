@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::content_policy::{ContentPolicy, PolicyProfiles, DEFAULT_PROFILE};
+use crate::downloader::CrawlOptions;
+use crate::logging::LogFormat;
+use crate::metrics::MetricsOptions;
+use crate::recommend::{SimilarOptions, TasteOptions};
+use crate::search::HybridWeights;
+use crate::summarizer::SummarizeOptions;
+use crate::types::EmbeddingSource;
+use crate::vector_index::IndexKind;
+
+pub const DATABASE_URL_ENV: &str = "LAM_DATABASE_URL";
+pub const DEFAULT_DATABASE_URL: &str = "sqlite://anime_metadata.db";
+pub const PGVECTOR_ENV: &str = "LAM_PGVECTOR";
+pub const CONTENT_PROFILE_ENV: &str = "LAM_CONTENT_PROFILE";
+pub const EMBEDDING_MODEL_DIR_ENV: &str = "LAM_EMBEDDING_MODEL_DIR";
+pub const EMBEDDING_MODEL_ENV: &str = "LAM_EMBEDDING_MODEL";
+pub const EMBEDDING_SOURCE_ENV: &str = "LAM_EMBEDDING_SOURCE";
+pub const DEFAULT_EMBEDDING_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+pub const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 32;
+pub const SEARCH_INDEX_ENV: &str = "LAM_SEARCH_INDEX";
+pub const INDEX_DIR_ENV: &str = "LAM_INDEX_DIR";
+pub const DEFAULT_INDEX_DIR: &str = "index";
+pub const DEFAULT_EF_SEARCH: usize = 64;
+pub const LLM_URL_ENV: &str = "LAM_LLM_URL";
+pub const LLM_MODEL_ENV: &str = "LAM_LLM_MODEL";
+pub const LLM_API_KEYS_ENV: &str = "LAM_LLM_API_KEYS";
+// Read when LAM_LLM_API_KEYS is not set, for setups predating it.
+pub const GROQ_API_KEYS_ENV: &str = "GROQ_API_KEYS_LAM";
+pub const DEFAULT_LLM_URL: &str = "https://api.groq.com/openai/v1/chat/completions";
+pub const DEFAULT_LLM_MODEL: &str = "llama-3.3-70b-versatile";
+pub const PARSE_QUERIES_ENV: &str = "LAM_PARSE_QUERIES";
+pub const RERANK_ENV: &str = "LAM_RERANK";
+pub const RERANK_CANDIDATES_ENV: &str = "LAM_RERANK_CANDIDATES";
+pub const DEFAULT_RERANK_CANDIDATES: usize = 20;
+pub const LISTEN_ENV: &str = "LAM_LISTEN";
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+pub const LOG_FORMAT_ENV: &str = "LAM_LOG_FORMAT";
+pub const LOG_LEVEL_ENV: &str = "LAM_LOG_LEVEL";
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const CONFIG_FILE_ENV: &str = "LAM_CONFIG";
+pub const DEFAULT_CONFIG_FILE: &str = "lam.toml";
+// Env vars naming a key, e.g. `LAM_DATABASE_URL` for `database_url` or
+// `LAM_HYBRID__RRF_K` for `hybrid.rrf_k`, override the config file.
+const ENV_PREFIX: &str = "LAM_";
+const ENV_SEPARATOR: &str = "__";
+// Env vars with the prefix that are not settings of the file.
+const NOT_SETTINGS_ENV: [&str; 2] = [CONFIG_FILE_ENV, LLM_API_KEYS_ENV];
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub database_url: Option<String>,
+    pub pgvector: Option<bool>,
+    pub content_profile: Option<String>,
+    // Named content policy profiles, e.g. `[content_policy.family]`.
+    #[serde(default)]
+    pub content_policy: HashMap<String, ContentPolicy>,
+    // The profile of users who search under a stricter or looser policy than
+    // `content_profile`, e.g. `kana = "family"`.
+    #[serde(default)]
+    pub user_profiles: HashMap<String, String>,
+    pub embedding_model_dir: Option<String>,
+    pub embedding_model: Option<String>,
+    pub embedding_source: Option<String>,
+    pub embedding_batch_size: Option<usize>,
+    pub search_index: Option<IndexKind>,
+    pub index_dir: Option<String>,
+    pub ef_search: Option<usize>,
+    #[serde(default)]
+    pub hybrid: HybridWeights,
+    pub llm_url: Option<String>,
+    pub llm_model: Option<String>,
+    pub parse_queries: Option<bool>,
+    pub rerank: Option<bool>,
+    pub rerank_candidates: Option<usize>,
+    #[serde(default)]
+    pub similar: SimilarOptions,
+    #[serde(default)]
+    pub taste: TasteOptions,
+    pub listen: Option<String>,
+    #[serde(default)]
+    pub crawl: CrawlOptions,
+    #[serde(default)]
+    pub summarize: SummarizeOptions,
+    pub log_format: Option<LogFormat>,
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub metrics: MetricsOptions,
+    /// Env vars with the `LAM_` prefix that name no setting, to be warned
+    /// about once logging is set up.
+    #[serde(skip)]
+    pub ignored_env: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EmbeddingConfig {
+    pub model_dir: String,
+    // Recorded with every vector so embeddings of different models never mix.
+    pub model: String,
+    pub source: EmbeddingSource,
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchIndexConfig {
+    pub kind: IndexKind,
+    // Where HNSW indexes are saved.
+    pub dir: String,
+    // Candidate list size of HNSW queries, higher is slower but more accurate.
+    pub ef_search: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    // An OpenAI-compatible chat completions endpoint.
+    pub url: String,
+    pub model: String,
+    // Summarization spreads its requests over every key; the other stages
+    // use the first one.
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// An LLM API key. `Debug` and `Display` only show its last four characters,
+/// enough to tell the keys apart in logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    /// The key itself, for the `Authorization` header.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chars: Vec<char> = self.0.chars().collect();
+        // Short keys are hidden entirely rather than mostly shown.
+        if chars.len() < 16 {
+            return write!(f, "***");
+        }
+        write!(f, "***{}", chars[chars.len() - 4..].iter().collect::<String>())
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKey({})", self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    // `tracing` filter directives such as `info` or `warn,lam::summarizer=debug`.
+    pub level: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    pub url: String,
+    // Only used by the Postgres backend.
+    pub pgvector: bool,
+}
+
+impl Config {
+    /// Reads the config file at `path`, or named by `LAM_CONFIG`, or
+    /// `lam.toml` in the working directory, then applies the env overrides. A
+    /// missing default file is not an error. Errors name the key, and the env
+    /// var when it came from one. Flags are applied by the caller on top.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let (path, explicit) = match path.map(|path| path.display().to_string()).or_else(|| std::env::var(CONFIG_FILE_ENV).ok()) {
+            Some(path) => (path, true),
+            None => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+        let table = match std::fs::read_to_string(&path) {
+            Ok(content) => content.parse::<toml::Table>().map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if explicit => return Err(format!("{}: {}", path, e)),
+            Err(_) => toml::Table::new(),
+        };
+        Self::from_table(table, &path, std::env::vars().collect())
+    }
+
+    /// Deserializes the file's `table` overridden by `vars`. Overrides that
+    /// name no setting are left out and listed in `ignored_env`, so that a
+    /// stray variable does not stop every command.
+    fn from_table(file: toml::Table, path: &str, vars: Vec<(String, String)>) -> Result<Self, String> {
+        let mut table = file.clone();
+        let mut overrides = env_overrides(&mut table, vars);
+        let mut ignored_env = vec![];
+        loop {
+            let error = match serde_path_to_error::deserialize::<_, Config>(toml::Value::Table(table.clone())) {
+                Ok(mut config) => {
+                    ignored_env.extend(overrides.ignored);
+                    config.ignored_env = ignored_env;
+                    return Ok(config);
+                }
+                Err(e) => e,
+            };
+            let key = error.path().to_string();
+            // An unknown key, or a table where a value belongs, set by env
+            // vars alone.
+            let unknown = error.inner().message().starts_with("unknown field");
+            let set_by_env = overrides.vars.keys().any(|overridden| within(overridden, &key));
+            let misplaced = !overrides.vars.contains_key(&key);
+            if set_by_env && (unknown || misplaced) && lookup(&file, &key).is_none() {
+                remove(&mut table, &key);
+                let (names, kept): (HashMap<_, _>, HashMap<_, _>) = overrides.vars
+                    .into_iter()
+                    .partition(|(overridden, _)| within(overridden, &key));
+                ignored_env.extend(names.into_values().map(|(name, _)| name));
+                overrides.vars = kept;
+                continue;
+            }
+            // A value that reads as TOML but not as the setting's type, e.g.
+            // a numeric model name, is taken as the string it was.
+            if let Some((_, raw)) = overrides.vars.get(&key) {
+                if !matches!(lookup(&table, &key), Some(toml::Value::String(_)) | None) {
+                    set(&mut table, &key, toml::Value::String(raw.clone()));
+                    continue;
+                }
+            }
+            // The file is at fault for its unknown keys, even when an env var
+            // sets them too.
+            let source = match overrides.vars.get(&key) {
+                Some((name, _)) if !unknown => name.as_str(),
+                _ => path,
+            };
+            return Err(if key == "." {
+                format!("{}: {}", source, error.inner().message())
+            } else {
+                format!("{}: {}: {}", source, key, error.inner().message())
+            });
+        }
+    }
+}
+
+/// The env vars applied to a config table.
+struct EnvOverrides {
+    /// The env var and raw value that set each key, by dotted key.
+    vars: HashMap<String, (String, String)>,
+    /// Env vars that cannot name a setting.
+    ignored: Vec<String>,
+}
+
+/// Sets the keys named by `LAM_<KEY>` and `LAM_<TABLE>__<KEY>` env vars.
+/// Values are read as TOML when they parse as such, e.g. `0.5` or `true`,
+/// and as strings otherwise.
+fn env_overrides(table: &mut toml::Table, mut vars: Vec<(String, String)>) -> EnvOverrides {
+    let mut overrides = EnvOverrides { vars: HashMap::new(), ignored: vec![] };
+    vars.retain(|(name, _)| name.starts_with(ENV_PREFIX) && !NOT_SETTINGS_ENV.contains(&name.as_str()));
+    vars.sort();
+    for (name, raw) in vars {
+        let keys: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_SEPARATOR)
+            .map(|key| key.to_lowercase())
+            .collect();
+        let key = keys.join(".");
+        if keys.iter().any(|key| key.is_empty()) || !set_path(table, &keys, parse_env(&raw)) {
+            overrides.ignored.push(name);
+            continue;
+        }
+        overrides.vars.insert(key, (name, raw));
+    }
+    overrides
+}
+
+/// Whether the dotted `key` is `table` or one of its keys.
+fn within(key: &str, table: &str) -> bool {
+    key == table || key.strip_prefix(table).is_some_and(|rest| rest.starts_with('.'))
+}
+
+fn parse_env(raw: &str) -> toml::Value {
+    format!("value = {}", raw)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Sets the value at `keys`, creating the tables on the way. Returns false
+/// when one of them is a value rather than a table.
+fn set_path(table: &mut toml::Table, keys: &[String], value: toml::Value) -> bool {
+    let Some((last, tables)) = keys.split_last() else {
+        return false;
+    };
+    let mut current = table;
+    for key in tables {
+        match current.entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+        {
+            Some(table) => current = table,
+            None => return false,
+        }
+    }
+    current.insert(last.clone(), value);
+    true
+}
+
+fn set(table: &mut toml::Table, key: &str, value: toml::Value) {
+    let keys: Vec<String> = key.split('.').map(str::to_string).collect();
+    set_path(table, &keys, value);
+}
+
+fn lookup<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    let (tables, last) = match key.rsplit_once('.') {
+        Some((tables, last)) => (Some(tables), last),
+        None => (None, key),
+    };
+    let mut current = table;
+    for key in tables.into_iter().flat_map(|tables| tables.split('.')) {
+        current = current.get(key)?.as_table()?;
+    }
+    current.get(last)
+}
+
+fn remove(table: &mut toml::Table, key: &str) {
+    let (tables, last) = match key.rsplit_once('.') {
+        Some((tables, last)) => (Some(tables), last),
+        None => (None, key),
+    };
+    let mut current = table;
+    for key in tables.into_iter().flat_map(|tables| tables.split('.')) {
+        match current.get_mut(key).and_then(|value| value.as_table_mut()) {
+            Some(table) => current = table,
+            None => return,
+        }
+    }
+    current.remove(last);
+}
+
+impl Config {
+    /// The database settings. The URL defaults to a SQLite file in the
+    /// working directory.
+    pub fn database(&self) -> DatabaseConfig {
+        DatabaseConfig {
+            url: self.database_url.clone().unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string()),
+            pgvector: self.pgvector.unwrap_or(false),
+        }
+    }
+
+    /// The content policy of the `content_profile`. The `default` profile
+    /// falls back to [`ContentPolicy::default`] when the config file does not
+    /// define it.
+    pub fn content_policy(&self) -> Result<ContentPolicy, String> {
+        self.profile_policy(self.content_profile.as_deref().unwrap_or(DEFAULT_PROFILE))
+    }
+
+    /// The policies searches apply: the profile of [`Config::content_policy`]
+    /// for everyone, except the users listed in `[user_profiles]`.
+    pub fn policy_profiles(&self) -> Result<PolicyProfiles, String> {
+        let mut profiles = PolicyProfiles::single(self.content_policy()?);
+        for (user, profile) in &self.user_profiles {
+            let policy = self.profile_policy(profile).map_err(|e| format!("user_profiles.{}: {}", user, e))?;
+            profiles.users.insert(user.clone(), policy);
+        }
+        Ok(profiles)
+    }
+
+    fn profile_policy(&self, profile: &str) -> Result<ContentPolicy, String> {
+        match self.content_policy.get(profile) {
+            Some(policy) => Ok(policy.clone()),
+            None if profile == DEFAULT_PROFILE => Ok(ContentPolicy::default()),
+            None => Err(format!("unknown content policy profile {:?}", profile)),
+        }
+    }
+
+    /// The embedding model. The model directory has no default, the source
+    /// defaults to the generated summaries.
+    pub fn embedding(&self) -> Result<EmbeddingConfig, String> {
+        let model_dir = self.embedding_model_dir
+            .clone()
+            .ok_or_else(|| format!("no embedding model directory, set --model-dir, {} or embedding_model_dir", EMBEDDING_MODEL_DIR_ENV))?;
+        let batch_size = self.embedding_batch_size.unwrap_or(DEFAULT_EMBEDDING_BATCH_SIZE);
+        if batch_size == 0 {
+            return Err("embedding_batch_size must be positive".to_string());
+        }
+        Ok(EmbeddingConfig { model_dir, model: self.embedding_model(), source: self.embedding_source()?, batch_size })
+    }
+
+    /// The embedding model name recorded with the vectors.
+    pub fn embedding_model(&self) -> String {
+        self.embedding_model.clone().unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string())
+    }
+
+    pub fn embedding_source(&self) -> Result<EmbeddingSource, String> {
+        self.embedding_source.as_deref().map_or(Ok(EmbeddingSource::Summary), str::parse)
+    }
+
+    /// The search index, exact unless `hnsw` is asked for.
+    pub fn search_index(&self) -> Result<SearchIndexConfig, String> {
+        let ef_search = self.ef_search.unwrap_or(DEFAULT_EF_SEARCH);
+        if ef_search == 0 {
+            return Err("ef_search must be positive".to_string());
+        }
+        Ok(SearchIndexConfig {
+            kind: self.search_index.unwrap_or(IndexKind::Exact),
+            dir: self.index_dir.clone().unwrap_or_else(|| DEFAULT_INDEX_DIR.to_string()),
+            ef_search,
+        })
+    }
+
+    /// The hybrid search weights of the `[hybrid]` table.
+    pub fn hybrid_weights(&self) -> Result<HybridWeights, String> {
+        let weights = &self.hybrid;
+        if weights.semantic < 0.0 || weights.keyword < 0.0 || weights.popularity < 0.0 || weights.mean_score < 0.0 {
+            return Err("hybrid weights must not be negative".to_string());
+        }
+        if weights.rrf_k <= 0.0 {
+            return Err("hybrid.rrf_k must be positive".to_string());
+        }
+        if weights.candidates == 0 {
+            return Err("hybrid.candidates must be positive".to_string());
+        }
+        Ok(weights.clone())
+    }
+
+    /// The "more like this" options of the `[similar]` table.
+    pub fn similar_options(&self) -> Result<SimilarOptions, String> {
+        let options = &self.similar;
+        if !(0.0..=1.0).contains(&options.theme_weight) {
+            return Err("similar.theme_weight must be between 0 and 1".to_string());
+        }
+        if options.candidates == 0 {
+            return Err("similar.candidates must be positive".to_string());
+        }
+        Ok(options.clone())
+    }
+
+    /// The taste profile options of the `[taste]` table.
+    pub fn taste_options(&self) -> Result<TasteOptions, String> {
+        let options = &self.taste;
+        if options.dislike_weight < 0.0 {
+            return Err("taste.dislike_weight must not be negative".to_string());
+        }
+        if !(0.0..=1.0).contains(&options.diversity_lambda) {
+            return Err("taste.diversity_lambda must be between 0 and 1".to_string());
+        }
+        if options.candidates == 0 {
+            return Err("taste.candidates must be positive".to_string());
+        }
+        Ok(options.clone())
+    }
+
+    /// How AniList is crawled, from the `[crawl]` table.
+    pub fn crawl_options(&self) -> Result<CrawlOptions, String> {
+        let options = &self.crawl;
+        if options.url.trim().is_empty() {
+            return Err("crawl.url must not be empty".to_string());
+        }
+        if options.first_year < options.last_year {
+            return Err("crawl.first_year must not be before crawl.last_year".to_string());
+        }
+        if options.max_attempts == 0 {
+            return Err("crawl.max_attempts must be positive".to_string());
+        }
+        Ok(options.clone())
+    }
+
+    /// The summarization request settings of the `[summarize]` table.
+    pub fn summarize_options(&self) -> Result<SummarizeOptions, String> {
+        let options = &self.summarize;
+        if !(0.0..=2.0).contains(&options.temperature) {
+            return Err("summarize.temperature must be between 0 and 2".to_string());
+        }
+        if options.max_tokens == 0 {
+            return Err("summarize.max_tokens must be positive".to_string());
+        }
+        if options.max_attempts == 0 {
+            return Err("summarize.max_attempts must be positive".to_string());
+        }
+        Ok(options.clone())
+    }
+
+    /// The LLM backend, Groq unless told otherwise. API keys are only read
+    /// from the environment, never from the config file: `LAM_LLM_API_KEYS`,
+    /// or `GROQ_API_KEYS_LAM`, holds one or more keys separated by `---`.
+    pub fn llm(&self) -> LlmConfig {
+        let api_keys = std::env::var(LLM_API_KEYS_ENV)
+            .or_else(|_| std::env::var(GROQ_API_KEYS_ENV))
+            .map(|keys| {
+                keys.split("---")
+                    .map(|key| key.trim())
+                    .filter(|key| !key.is_empty())
+                    .map(ApiKey::new)
+                    .collect()
+            })
+            .unwrap_or_default();
+        LlmConfig {
+            url: self.llm_url.clone().unwrap_or_else(|| DEFAULT_LLM_URL.to_string()),
+            model: self.llm_model.clone().unwrap_or_else(|| DEFAULT_LLM_MODEL.to_string()),
+            api_keys,
+        }
+    }
+
+    /// Whether searches have the LLM split free-text queries into text and
+    /// filters. Off by default.
+    pub fn parse_queries(&self) -> bool {
+        self.parse_queries.unwrap_or(false)
+    }
+
+    /// Whether search hits are reranked by the LLM. Off by default.
+    pub fn rerank(&self) -> bool {
+        self.rerank.unwrap_or(false)
+    }
+
+    /// How many of the top hits are sent to the LLM for reranking.
+    pub fn rerank_candidates(&self) -> Result<usize, String> {
+        let candidates = self.rerank_candidates.unwrap_or(DEFAULT_RERANK_CANDIDATES);
+        if candidates == 0 {
+            return Err("rerank_candidates must be positive".to_string());
+        }
+        Ok(candidates)
+    }
+
+    /// The address the API server listens on, `127.0.0.1:8080` by default.
+    pub fn listen(&self) -> Result<SocketAddr, String> {
+        let listen = self.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+        listen.parse().map_err(|_| format!("listen must be an address such as {}, got {:?}", DEFAULT_LISTEN, listen))
+    }
+
+    /// How pipeline metrics are exposed, from the `[metrics]` table.
+    pub fn metrics_options(&self) -> Result<MetricsOptions, String> {
+        if let Some(listen) = &self.metrics.listen {
+            listen.parse::<SocketAddr>()
+                .map_err(|_| format!("metrics.listen must be an address such as 127.0.0.1:9090, got {:?}", listen))?;
+        }
+        Ok(self.metrics.clone())
+    }
+
+    /// How logs are written, text at the `info` level unless told otherwise.
+    pub fn logging(&self) -> LogConfig {
+        LogConfig {
+            format: self.log_format.unwrap_or(LogFormat::Text),
+            level: self.log_level.clone().unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::from_table(file.parse().unwrap(), "lam.toml", vars)
+    }
+
+    #[test]
+    fn env_vars_override_the_file() {
+        let config = load(
+            "database_url = \"sqlite://file.db\"\n[hybrid]\nrrf_k = 10.0\nkeyword = 0.5",
+            &[
+                ("LAM_DATABASE_URL", "sqlite://env.db"),
+                ("LAM_HYBRID__RRF_K", "30"),
+                // Reads as a number, but the model name is a string.
+                ("LAM_LLM_MODEL", "123"),
+                ("LAM_LLM_API_KEYS", "secret"),
+                ("HOME", "/root"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.database().url, "sqlite://env.db");
+        assert_eq!(config.hybrid.rrf_k, 30.0);
+        assert_eq!(config.hybrid.keyword, 0.5);
+        assert_eq!(config.llm_model.as_deref(), Some("123"));
+        assert!(config.ignored_env.is_empty());
+    }
+
+    #[test]
+    fn env_vars_naming_no_setting_are_ignored() {
+        let config = load(
+            "[similar]\ncandidates = 50",
+            &[
+                ("LAM_STRAY", "1"),
+                ("LAM_HYBRID__NOPE", "2"),
+                ("LAM_NOPE__DEEPER__STILL", "3"),
+                ("LAM_DATABASE_URL__X", "4"),
+                ("LAM_SIMILAR__THEME_WEIGHT", "0.5"),
+            ],
+        )
+        .unwrap();
+        let mut ignored = config.ignored_env.clone();
+        ignored.sort();
+        assert_eq!(ignored, vec!["LAM_DATABASE_URL__X", "LAM_HYBRID__NOPE", "LAM_NOPE__DEEPER__STILL", "LAM_STRAY"]);
+        assert_eq!(config.similar.candidates, 50);
+        assert_eq!(config.similar.theme_weight, 0.5);
+
+        // Unknown keys of the file are still errors.
+        let error = load("[hybrid]\nnope = 1", &[("LAM_HYBRID__NOPE", "2")]).unwrap_err();
+        assert!(error.starts_with("lam.toml: hybrid.nope: unknown field"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_values_name_their_source() {
+        let error = load("", &[("LAM_HYBRID__RRF_K", "abc")]).unwrap_err();
+        assert_eq!(error, "LAM_HYBRID__RRF_K: hybrid.rrf_k: invalid type: string \"abc\", expected f32");
+        let error = load("[similar]\ntheme_weight = \"high\"", &[]).unwrap_err();
+        assert_eq!(error, "lam.toml: similar.theme_weight: invalid type: string \"high\", expected f32");
+        let error = load("", &[("LAM_PGVECTOR", "yes")]).unwrap_err();
+        assert!(error.starts_with("LAM_PGVECTOR: pgvector: invalid type"), "{}", error);
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = load("", &[]).unwrap();
+        assert_eq!(config.database().url, DEFAULT_DATABASE_URL);
+        assert!(config.hybrid_weights().is_ok());
+        assert!(config.similar_options().is_ok());
+        assert!(config.taste_options().is_ok());
+        assert!(config.crawl_options().is_ok());
+        assert!(config.summarize_options().is_ok());
+        assert!(config.metrics_options().is_ok());
+        assert_eq!(config.search_index().unwrap().ef_search, DEFAULT_EF_SEARCH);
+        assert_eq!(config.rerank_candidates().unwrap(), DEFAULT_RERANK_CANDIDATES);
+        assert_eq!(config.listen().unwrap().to_string(), DEFAULT_LISTEN);
+        assert!(config.embedding().unwrap_err().starts_with("no embedding model directory"));
+    }
+
+    #[test]
+    fn settings_out_of_range_are_rejected() {
+        let invalid = |file: &str| -> String {
+            let config = load(file, &[]).unwrap();
+            [
+                config.hybrid_weights().err(),
+                config.similar_options().err(),
+                config.taste_options().err(),
+                config.crawl_options().err(),
+                config.summarize_options().err(),
+                config.search_index().err(),
+                config.rerank_candidates().err(),
+                config.listen().err(),
+                config.metrics_options().err(),
+                config.content_policy().err(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("; ")
+        };
+        assert_eq!(invalid("[hybrid]\nkeyword = -1.0"), "hybrid weights must not be negative");
+        assert_eq!(invalid("[hybrid]\nrrf_k = 0.0"), "hybrid.rrf_k must be positive");
+        assert_eq!(invalid("[similar]\ntheme_weight = 1.5"), "similar.theme_weight must be between 0 and 1");
+        assert_eq!(invalid("[taste]\ndiversity_lambda = -0.1"), "taste.diversity_lambda must be between 0 and 1");
+        assert_eq!(invalid("[taste]\ncandidates = 0"), "taste.candidates must be positive");
+        assert_eq!(invalid("[crawl]\nfirst_year = 1999"), "crawl.first_year must not be before crawl.last_year");
+        assert_eq!(invalid("[summarize]\ntemperature = 3.0"), "summarize.temperature must be between 0 and 2");
+        assert_eq!(invalid("ef_search = 0"), "ef_search must be positive");
+        assert_eq!(invalid("rerank_candidates = 0"), "rerank_candidates must be positive");
+        assert_eq!(invalid("listen = \"nowhere\""), "listen must be an address such as 127.0.0.1:8080, got \"nowhere\"");
+        assert_eq!(invalid("[metrics]\nlisten = \"9090\""), "metrics.listen must be an address such as 127.0.0.1:9090, got \"9090\"");
+        assert_eq!(invalid("content_profile = \"family\""), "unknown content policy profile \"family\"");
+    }
+
+    #[test]
+    fn api_keys_are_redacted() {
+        let key = ApiKey::new("gsk_abcdefghijklmnop1234");
+        assert_eq!(key.to_string(), "***1234");
+        assert_eq!(format!("{:?}", key), "ApiKey(***1234)");
+        assert_eq!(key.expose(), "gsk_abcdefghijklmnop1234");
+        // Too short to show any of it.
+        assert_eq!(ApiKey::new("short-key-12").to_string(), "***");
+        let llm = LlmConfig { url: String::new(), model: String::new(), api_keys: vec![key] };
+        assert!(!format!("{:?}", llm).contains("abcdefgh"));
+    }
+}
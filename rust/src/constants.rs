@@ -1 +0,0 @@
-pub const DATABASE_URL: &str = "sqlite://anime_metadata.db";
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::types::AnimeMetadata;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Decides which media LAM crawls, summarizes and returns from search. The
+/// same policy is meant to be applied at every stage so that a title excluded
+/// at crawl time cannot reappear later through another path.
+///
+/// Genre and tag names are matched case-insensitively. Tags ranked below
+/// `min_tag_rank` (AniList ranks are percentages) are ignored by the tag lists.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentPolicy {
+    /// Whether media flagged `isAdult` by AniList are allowed.
+    pub allow_adult: bool,
+    /// Media with any of these genres are excluded.
+    pub deny_genres: Vec<String>,
+    /// When not empty, media must have at least one of these genres.
+    pub allow_genres: Vec<String>,
+    /// Media with any of these tags are excluded.
+    pub deny_tags: Vec<String>,
+    /// When not empty, media must have at least one of these tags.
+    pub allow_tags: Vec<String>,
+    pub min_tag_rank: i32,
+}
+
+impl Default for ContentPolicy {
+    fn default() -> Self {
+        Self {
+            allow_adult: false,
+            // Rows crawled before `isAdult` was stored only have their genres
+            // to go by.
+            deny_genres: vec!["Hentai".to_string()],
+            allow_genres: vec![],
+            deny_tags: vec![],
+            allow_tags: vec![],
+            min_tag_rank: 0,
+        }
+    }
+}
+
+fn contains(names: &[String], name: &str) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+impl ContentPolicy {
+    /// A policy that lets everything through.
+    pub fn permissive() -> Self {
+        Self {
+            allow_adult: true,
+            deny_genres: vec![],
+            ..Self::default()
+        }
+    }
+
+    pub fn allows(&self, anime: &AnimeMetadata) -> bool {
+        if !self.allow_adult && anime.is_adult == Some(true) {
+            return false;
+        }
+
+        let genres: Vec<&str> = anime.genres.iter().flatten().map(|g| g.as_str()).collect();
+        if genres.iter().any(|g| contains(&self.deny_genres, g)) {
+            return false;
+        }
+        if !self.allow_genres.is_empty() && !genres.iter().any(|g| contains(&self.allow_genres, g)) {
+            return false;
+        }
+
+        let tags: Vec<&str> = anime.tags.iter()
+            .flatten()
+            .filter(|t| t.rank.unwrap_or(0) >= self.min_tag_rank)
+            .map(|t| t.name.as_str())
+            .collect();
+        if tags.iter().any(|t| contains(&self.deny_tags, t)) {
+            return false;
+        }
+        if !self.allow_tags.is_empty() && !tags.iter().any(|t| contains(&self.allow_tags, t)) {
+            return false;
+        }
+        true
+    }
+
+    /// Keeps the media allowed by the policy and returns how many were dropped.
+    pub fn retain(&self, media: &mut Vec<AnimeMetadata>) -> usize {
+        let before = media.len();
+        media.retain(|anime| self.allows(anime));
+        before - media.len()
+    }
+}
+
+/// The content policy of each user searching: users given a profile in the
+/// `[user_profiles]` table of the config file get its policy, everyone else
+/// the default one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PolicyProfiles {
+    pub default: ContentPolicy,
+    pub users: HashMap<String, ContentPolicy>,
+}
+
+impl PolicyProfiles {
+    /// The same policy for everyone.
+    pub fn single(policy: ContentPolicy) -> Self {
+        Self { default: policy, users: HashMap::new() }
+    }
+
+    pub fn for_user(&self, user: Option<&str>) -> &ContentPolicy {
+        user.and_then(|user| self.users.get(user)).unwrap_or(&self.default)
+    }
+
+    /// Every policy that applies to someone, each once.
+    pub fn distinct(&self) -> Vec<&ContentPolicy> {
+        let mut distinct = vec![&self.default];
+        for policy in self.users.values() {
+            if !distinct.contains(&policy) {
+                distinct.push(policy);
+            }
+        }
+        distinct
+    }
+}
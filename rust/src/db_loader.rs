@@ -1,28 +1,28 @@
-use sqlx::{Result, SqliteConnection};
+use sqlx::Result;
 use tokio::sync::mpsc;
+use tracing::{debug, error};
 
-use crate::types::{AnimeMetadata, AnimeSummary};
+use crate::storage::Storage;
+use crate::types::{AnimeEmbedding, AnimeMetadata, AnimeSummary};
 
 #[allow(async_fn_in_trait)]
 pub trait DbLoader<T> {
     fn loader_name(&mut self) -> String;
-    fn get_conn(&mut self) -> &mut SqliteConnection;
+    fn get_storage(&mut self) -> &Storage;
     fn get_receiver(&mut self) -> &mut mpsc::Receiver<Option<T>>;
 
-    async fn create_table_if_not_exists(conn: &mut SqliteConnection) -> Result<()>;
-    async fn load(conn: &mut SqliteConnection, data: T) -> Result<()>;
+    async fn load(storage: &Storage, data: T) -> Result<()>;
 
+    /// Loads what is received until a `None` comes or every sender is
+    /// dropped, so that everything queued before is loaded.
     async fn start_load_job(&mut self) -> Result<bool> {
-        Self::create_table_if_not_exists(self.get_conn()).await?;
         loop {
             match self.get_receiver().recv().await {
                 Some(maybe_data) => {
-                    println!("Loader {} has received data", self.loader_name());
                     match maybe_data {
                         Some(data) => {
-                            let response = Self::load(self.get_conn(), data).await;
-                            if response.is_err() {
-                                println!("{:?}", response.unwrap_err());
+                            if let Err(e) = Self::load(self.get_storage(), data).await {
+                                error!(loader = self.loader_name(), error = %e, "Load failed");
                             }
                         },
                         None => return Ok(true),
@@ -36,7 +36,7 @@ pub trait DbLoader<T> {
 
 pub struct SummaryLoader {
     receiver: mpsc::Receiver<Option<AnimeSummary>>,
-    conn: SqliteConnection,
+    storage: Storage,
 }
 
 impl DbLoader<AnimeSummary> for SummaryLoader {
@@ -44,55 +44,31 @@ impl DbLoader<AnimeSummary> for SummaryLoader {
         "SummaryLoader".to_string()
     }
 
-    fn get_conn(&mut self) -> &mut SqliteConnection {
-        &mut self.conn
+    fn get_storage(&mut self) -> &Storage {
+        &self.storage
     }
 
     fn get_receiver(&mut self) -> &mut mpsc::Receiver<Option<AnimeSummary>> {
         &mut self.receiver
     }
 
-    async fn create_table_if_not_exists(conn: &mut SqliteConnection) -> Result<()> {
-        let sql = "
-            CREATE TABLE IF NOT EXISTS anime_summary (
-                id INTEGER PRIMARY KEY,
-                summary TEXT,
-                generated_genres TEXT,
-                generated_themes TEXT
-            );
-        ";
-        sqlx::query(sql).execute(conn).await?;
-        Ok(())
-    }
-
-    async fn load(conn: &mut SqliteConnection, data: AnimeSummary) -> Result<()> {
-        let insert_sql = "
-            INSERT OR REPLACE INTO anime_summary (id, summary, generated_genres, generated_themes)
-        ";
-        let mut query = sqlx::QueryBuilder::new(insert_sql);
-        query.push_values(vec![data], |mut b, anime| {
-            b.push_bind(anime.id)
-                .push_bind(anime.generated_summary.summary)
-                .push_bind(anime.generated_summary.generated_genres.join(","))
-                .push_bind(anime.generated_summary.generated_themes.join(","));
-        });
-        let built_query = query.build();
-        // println!("{}", built_query.sql());
-        built_query.execute(conn).await?;
-        println!("Loaded!");
+    async fn load(storage: &Storage, data: AnimeSummary) -> Result<()> {
+        let id = data.id;
+        storage.upsert_summary(data).await?;
+        debug!(id, "Loaded summary");
         Ok(())
     }
 }
 
 impl SummaryLoader {
-    pub fn new(receiver: mpsc::Receiver<Option<AnimeSummary>>, conn: SqliteConnection) -> Self {
-        Self { receiver, conn }
+    pub fn new(receiver: mpsc::Receiver<Option<AnimeSummary>>, storage: Storage) -> Self {
+        Self { receiver, storage }
     }
 }
 
 pub struct MetadataLoader {
     receiver: mpsc::Receiver<Option<Vec<AnimeMetadata>>>,
-    conn: SqliteConnection,
+    storage: Storage,
 }
 
 impl DbLoader<Vec<AnimeMetadata>> for MetadataLoader {
@@ -100,61 +76,59 @@ impl DbLoader<Vec<AnimeMetadata>> for MetadataLoader {
         "MetadataLoader".to_string()
     }
 
-    fn get_conn(&mut self) -> &mut SqliteConnection {
-        &mut self.conn
+    fn get_storage(&mut self) -> &Storage {
+        &self.storage
     }
 
     fn get_receiver(&mut self) -> &mut mpsc::Receiver<Option<Vec<AnimeMetadata>>> {
         &mut self.receiver
     }
 
-    async fn create_table_if_not_exists(conn: &mut SqliteConnection) -> Result<()> {
-        let sql = "
-            CREATE TABLE IF NOT EXISTS anime_metadata (
-                id INTEGER PRIMARY KEY,
-                romaji_title TEXT,
-                english_title TEXT,
-                season TEXT,
-                season_year INTEGER NOT NULL,
-                description TEXT,
-                popularity INTEGER,
-                mean_score INTEGER,
-                genres TEXT
-            );
-        ";
-        sqlx::query(sql).execute(conn).await?;
-        Ok(())
-    }
-
-    async fn load(conn: &mut SqliteConnection, data: Vec<AnimeMetadata>) -> Result<()> {
+    async fn load(storage: &Storage, data: Vec<AnimeMetadata>) -> Result<()> {
         if data.is_empty() {
             return Ok(());
         }
-        let insert_sql = "
-            INSERT OR REPLACE INTO anime_metadata (id, romaji_title, english_title, season, season_year, description, popularity, mean_score, genres)
-        ";
-        let mut query = sqlx::QueryBuilder::new(insert_sql);
-        query.push_values(data, |mut b, anime| {
-            b.push_bind(anime.id)
-                .push_bind(anime.title.romaji.clone())
-                .push_bind(anime.title.english.clone())
-                .push_bind(anime.season.clone())
-                .push_bind(anime.season_year)
-                .push_bind(anime.description.clone())
-                .push_bind(anime.popularity)
-                .push_bind(anime.mean_score)
-                .push_bind(anime.genres.unwrap_or_default().join(","));
-        });
-        let built_query = query.build();
-        // println!("{}", built_query.sql());
-        built_query.execute(conn).await?;
-        println!("Loaded!");
+        let count = data.len();
+        storage.upsert_metadata(data).await?;
+        debug!(count, "Loaded metadata");
         Ok(())
     }
 }
 
 impl MetadataLoader {
-    pub fn new(receiver: mpsc::Receiver<Option<Vec<AnimeMetadata>>>, conn: SqliteConnection) -> Self {
-        Self { receiver, conn }
+    pub fn new(receiver: mpsc::Receiver<Option<Vec<AnimeMetadata>>>, storage: Storage) -> Self {
+        Self { receiver, storage }
+    }
+}
+
+pub struct EmbeddingLoader {
+    receiver: mpsc::Receiver<Option<Vec<AnimeEmbedding>>>,
+    storage: Storage,
+}
+
+impl DbLoader<Vec<AnimeEmbedding>> for EmbeddingLoader {
+    fn loader_name(&mut self) -> String {
+        "EmbeddingLoader".to_string()
+    }
+
+    fn get_storage(&mut self) -> &Storage {
+        &self.storage
+    }
+
+    fn get_receiver(&mut self) -> &mut mpsc::Receiver<Option<Vec<AnimeEmbedding>>> {
+        &mut self.receiver
+    }
+
+    async fn load(storage: &Storage, data: Vec<AnimeEmbedding>) -> Result<()> {
+        let count = data.len();
+        storage.upsert_embeddings(data).await?;
+        debug!(count, "Loaded embeddings");
+        Ok(())
+    }
+}
+
+impl EmbeddingLoader {
+    pub fn new(receiver: mpsc::Receiver<Option<Vec<AnimeEmbedding>>>, storage: Storage) -> Self {
+        Self { receiver, storage }
     }
 }
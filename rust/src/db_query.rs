@@ -1,84 +1,132 @@
-use sqlx::{Result, SqliteConnection};
-use tokio::sync::mpsc;
+use std::sync::Arc;
 
-use crate::types::{AnimeMetadata, AnimeMetadataRow, Title};
+use sqlx::Result;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
-#[derive(Debug, sqlx::FromRow)]
-struct Years {
-    max_year: i32,
-    min_year: i32,
-}
+use crate::content_policy::ContentPolicy;
+use crate::progress::Progress;
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
+use crate::types::AnimeMetadata;
 
 pub struct DbQuery {
     senders: Vec<mpsc::Sender<Option<AnimeMetadata>>>,
     ready_receiver: mpsc::Receiver<usize>,
-    conn: SqliteConnection,
+    storage: Storage,
+    policy: ContentPolicy,
+    progress: Arc<Progress>,
+    shutdown: Shutdown,
 }
 
 impl DbQuery {
-    pub fn new(senders: Vec<mpsc::Sender<Option<AnimeMetadata>>>, ready_receiver: mpsc::Receiver<usize>, conn: SqliteConnection) -> Self {
-        Self { senders, ready_receiver, conn }
+    pub fn new(senders: Vec<mpsc::Sender<Option<AnimeMetadata>>>, ready_receiver: mpsc::Receiver<usize>, storage: Storage, policy: ContentPolicy, progress: Arc<Progress>, shutdown: Shutdown) -> Self {
+        Self { senders, ready_receiver, storage, policy, progress, shutdown }
     }
 
+    /// Hands the pending media to the summarizers until every year is done, a
+    /// shutdown is requested or every summarizer is gone, then tells them to
+    /// stop. Returns whether every year was done.
     pub async fn query_all_years(&mut self) -> Result<bool> {
-        let years: Years = sqlx::query_as("SELECT MAX(season_year) AS max_year, MIN(season_year) AS min_year FROM anime_metadata;").fetch_one(&mut self.conn).await?;
-        for year in years.min_year..years.max_year+1 {
-            let rows = self.query_year(year).await.unwrap();
-            println!("Year: {}, num rows: {}", year, rows.len());
-            if rows.is_empty() {
-                continue;
+        let mut done = true;
+        if let Some((min_year, max_year)) = self.storage.season_year_range().await? {
+            for year in min_year..max_year+1 {
+                if self.shutdown.requested() {
+                    done = false;
+                    break;
+                }
+                let rows = self.query_year(year).await?;
+                info!(year, pending = rows.len(), "Queried pending summaries");
+                if rows.is_empty() {
+                    continue;
+                }
+                if !self.handle_year(rows).await {
+                    warn!("Every summarizer stopped, not querying the remaining years");
+                    done = false;
+                    break;
+                }
             }
-            self.handle_year(rows).await;
-            // let _ = self.sender.send(Some(rows)).await;
         }
-        // let _ = self.sender.send(None).await;
         for idx in 0..self.senders.len() {
             let _ = self.senders[idx].send(None).await;
         }
-        println!("Finished sending metadata");
-        Ok(true)
+        info!("Finished sending metadata");
+        Ok(done && !self.shutdown.requested())
     }
 
-    async fn handle_year(&mut self, rows: Vec<AnimeMetadata>) {
+    /// Hands out the media of a year. Returns false once no summarizer is
+    /// left to take them.
+    async fn handle_year(&mut self, rows: Vec<AnimeMetadata>) -> bool {
         for row in rows {
-            match self.ready_receiver.recv().await {
+            let ready = tokio::select! {
+                ready = self.ready_receiver.recv() => ready,
+                _ = self.shutdown.wait() => return true,
+            };
+            match ready {
                 Some(idx) => {
-                    let response = self.senders[idx].send(Some(row)).await;
-                    if response.is_err() {
-                        println!("Error sending metadata to {}, err: {}", idx, response.unwrap_err());
+                    if let Err(e) = self.senders[idx].send(Some(row)).await {
+                        warn!(summarizer = idx, error = %e, "Could not send metadata");
                     }
                 },
-                None => todo!(),
+                None => return false,
             }
         }
+        true
     }
 
     pub async fn query_year(&mut self, season_year: i32) -> Result<Vec<AnimeMetadata>> {
-        let rows: Vec<AnimeMetadataRow> = sqlx::query_as("
-            SELECT * FROM anime_metadata
-            WHERE LOWER(genres) NOT LIKE '%hen%'
-                AND description <> ''
-                AND description IS NOT NULL
-                AND season_year = ?
-                AND id NOT IN (
-                    SELECT id FROM anime_summary
-                );
-            ").bind(season_year).fetch_all(&mut self.conn).await?;
-        let media = rows.into_iter().map(|row| {
-            AnimeMetadata {
-                id: row.id,
-                title: Title {
-                    romaji: row.romaji_title,
-                    english: row.english_title,
-                },
-                season: row.season,
-                season_year: row.season_year,
-                description: row.description,
-                popularity: row.popularity,
-                mean_score: row.mean_score,
-                genres: row.genres.map(|g| g.split(",").into_iter().map(|x| x.to_string()).collect()),
-            }
-        }).collect();
+        let mut media = self.storage.pending_summaries(season_year).await?;
+        let skipped = self.policy.retain(&mut media);
+        if skipped > 0 {
+            // The progress total was counted before the policy was applied.
+            self.progress.skip(skipped as u64);
+            debug!(year = season_year, skipped, "Skipped media denied by the content policy");
+        }
         Ok(media)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::TempStorage;
+    use crate::types::AnimeMetadataRow;
+
+    fn anime(id: i32, season_year: i32) -> AnimeMetadata {
+        AnimeMetadataRow {
+            id,
+            mal_id: None,
+            english_title: Some(format!("Anime {}", id)),
+            romaji_title: None,
+            season: None,
+            season_year,
+            format: None,
+            description: Some("A description.".to_string()),
+            popularity: None,
+            mean_score: None,
+            is_adult: Some(false),
+        }
+        .into_metadata(vec![], vec![])
+    }
+
+    #[tokio::test]
+    async fn stops_once_every_summarizer_is_gone() {
+        let db = TempStorage::new("db_query_gone").await;
+        db.upsert_metadata(vec![anime(1, 2020), anime(2, 2020), anime(3, 2021)]).await.unwrap();
+        let (sender, mut receiver) = mpsc::channel(2);
+        let (ready_sender, ready_receiver) = mpsc::channel(2);
+        let (_requester, shutdown) = Shutdown::manual();
+        let progress = Progress::new("test", "media", 3);
+        let mut query = DbQuery::new(vec![sender], ready_receiver, db.storage.clone(), ContentPolicy::default(), progress, shutdown);
+
+        // The only summarizer takes one media and exits.
+        ready_sender.send(0).await.unwrap();
+        drop(ready_sender);
+        let done = tokio::time::timeout(Duration::from_secs(5), query.query_all_years()).await.unwrap().unwrap();
+        assert!(!done);
+        assert_eq!(receiver.recv().await.unwrap().map(|anime| anime.id), Some(1));
+        assert!(receiver.recv().await.unwrap().is_none());
+    }
+}
@@ -1,8 +1,16 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
 use serde_json::json;
 use reqwest::Client;
 use tokio::sync::mpsc;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use tokio::time::{sleep, Duration};
 
+use crate::content_policy::ContentPolicy;
+use crate::metrics::metrics;
+use crate::progress::Progress;
+use crate::shutdown::Shutdown;
 use crate::types::AnimeMetadata;
 
 const QUERY: &str = "
@@ -42,6 +50,7 @@ query (
   Page(page: $page, perPage: 50) {
     pageInfo {
       hasNextPage
+      lastPage
     }
     media(
       id: $id
@@ -76,58 +85,138 @@ query (
       isAdult: $isAdult
     ) {
       id
+      idMal
       title {
         romaji
         english
       }
       season
       seasonYear
+      format
       description
       popularity
       meanScore
+      isAdult
       genres
+      tags {
+        id
+        name
+        category
+        rank
+        isAdult
+      }
+      relations {
+        edges {
+          relationType
+          node {
+            id
+            type
+          }
+        }
+      }
     }
   }
 }
 ";
 
+/// How AniList is crawled, set in the `[crawl]` table of the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrawlOptions {
+    /// The AniList GraphQL endpoint.
+    pub url: String,
+    /// Season years are crawled from `first_year` back to `last_year`.
+    pub first_year: i32,
+    pub last_year: i32,
+    /// Pause between two pages, to stay under AniList's rate limit.
+    pub page_delay_secs: u64,
+    /// Pause after a rate limited or failed request.
+    pub retry_delay_secs: u64,
+    /// Attempts at a request before it is skipped.
+    pub max_attempts: u32,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            url: "https://graphql.anilist.co/".to_string(),
+            first_year: 2025,
+            last_year: 2000,
+            page_delay_secs: 2,
+            retry_delay_secs: 10,
+            max_attempts: 3,
+        }
+    }
+}
+
 pub struct Downloader {
     sender: mpsc::Sender<Option<Vec<AnimeMetadata>>>,
+    policy: ContentPolicy,
+    options: CrawlOptions,
+    progress: Arc<Progress>,
+    shutdown: Shutdown,
 }
 
 impl Downloader {
-    pub fn new(sender: mpsc::Sender<Option<Vec<AnimeMetadata>>>) -> Self {
-        Self { sender }
+    pub fn new(sender: mpsc::Sender<Option<Vec<AnimeMetadata>>>, policy: ContentPolicy, options: CrawlOptions, progress: Arc<Progress>, shutdown: Shutdown) -> Self {
+        Self { sender, policy, options, progress, shutdown }
+    }
+
+    /// The number of seasons to crawl, the least number of pages there is.
+    pub fn seasons(options: &CrawlOptions) -> u64 {
+        (options.first_year - options.last_year + 1) as u64
     }
 
     pub async fn download(&mut self) -> Result<bool, reqwest::Error> {
         let mut page = 1;
-        let mut season_year = 2025;
+        let mut season_year = self.options.first_year;
         let media_type = "ANIME";
         let mut has_next_page = true;
+        // Let AniList drop adult media up front when the policy denies them.
+        let is_adult = if self.policy.allow_adult { None } else { Some(false) };
+        // The first page of a season tells how many it has.
+        let seasons = Downloader::seasons(&self.options);
+        let mut seasons_seen = 0;
+        let mut pages_seen = 0;
 
-        while season_year > 1999 || has_next_page {
-            let response = Downloader::fire_request(page, media_type, season_year)
-                .await
-                .map(Downloader::handle_response);
-            match response {
-                Ok((media, new_has_next_page)) => {
-                    println!("Finished downloading season {} page {}", season_year, page);
+        while season_year >= self.options.last_year || has_next_page {
+            if self.shutdown.requested() {
+                info!(year = season_year, page, "Stopped before this page");
+                return Ok(false);
+            }
+            let span = info_span!("page", year = season_year, page);
+            let response = Downloader::fire_request(&self.options, page, media_type, season_year, is_adult)
+                .instrument(span.clone())
+                .await;
+            if let (Ok(response), 1) = (&response, page) {
+                let last_page = response["data"]["Page"]["pageInfo"]["lastPage"].as_u64().unwrap_or(1).max(1);
+                seasons_seen += 1;
+                pages_seen += last_page;
+                self.progress.set_total(expected_pages(seasons, seasons_seen, pages_seen));
+            }
+            match response.map(|response| span.in_scope(|| Downloader::handle_response(response))) {
+                Ok((mut media, new_has_next_page)) => {
                     has_next_page = new_has_next_page;
-                    let send_response = self.sender.send(Some(media)).await;
-                    if send_response.is_err() {
-                        println!("{:?}", send_response.unwrap_err());
-                        eprintln!("Failed to push data to the queue");
+                    let skipped = self.policy.retain(&mut media);
+                    if skipped > 0 {
+                        debug!(parent: &span, skipped, "Skipped media denied by the content policy");
+                    }
+                    let count = media.len();
+                    if let Err(e) = self.sender.send(Some(media)).await {
+                        error!(parent: &span, error = %e, "Failed to push data to the queue");
                         return Ok(false);
                     }
-                    println!("Sent season {} page {}", season_year, page);
+                    metrics().pages_downloaded.inc();
+                    metrics().media_downloaded.add(count as u64);
+                    self.progress.inc(1);
+                    info!(parent: &span, media = count, "Downloaded");
 
                     page += 1;
                     if !has_next_page {
                         season_year -= 1;
                         page = 1;
                     }
-                    sleep(Duration::from_secs(2)).await;
+                    sleep(Duration::from_secs(self.options.page_delay_secs)).await;
                 },
                 Err(e) => return Err(e),
             }
@@ -137,44 +226,74 @@ impl Downloader {
     }
 
     pub async fn fire_request(
+        options: &CrawlOptions,
         page: i32,
         media_type: &str,
         season_year: i32,
+        is_adult: Option<bool>,
+    ) -> Result<serde_json::Value, reqwest::Error> {
+        let variables = json!({"page": page, "type": media_type, "seasonYear": season_year, "isAdult": is_adult});
+        Downloader::graphql(options, QUERY, variables).await
+    }
+
+    /// Sends a query to the AniList GraphQL API, waiting out rate limits.
+    /// Returns `Null` after `options.max_attempts` failed attempts.
+    pub async fn graphql(
+        options: &CrawlOptions,
+        query: &str,
+        variables: serde_json::Value,
     ) -> Result<serde_json::Value, reqwest::Error> {
-        let mut retry = -1;
+        let mut attempts = 0;
         loop {
-            retry += 1;
-            if retry == 3 {
-                println!("Tried 3 times, skip to next request...");
+            if attempts == options.max_attempts {
+                warn!(attempts = options.max_attempts, "Giving up on this request");
                 return Ok(serde_json::Value::Null);
             }
+            attempts += 1;
 
             let client = Client::new();
-            let json = json!({"query": QUERY, "variables": {"page": page, "type": media_type, "seasonYear": season_year}});
-            let response = client.post("https://graphql.anilist.co/")
+            let json = json!({"query": query, "variables": variables});
+            let response = client.post(&options.url)
                 .header("Content-Type", "application/json")
                 .header("Accept", "application/json")
                 .body(json.to_string())
                 .send()
-                .await
-                .unwrap();
-        
+                .await;
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics().anilist_request_failures.inc();
+                    warn!(error = %e, retry_delay = options.retry_delay_secs, "Request failed, sleeping");
+                    sleep(Duration::from_secs(options.retry_delay_secs)).await;
+                    continue;
+                }
+            };
+
             if response.status() == 429 {
-                println!("{}", response.headers()["x-ratelimit-remaining"].to_str().unwrap());
-                println!("Hit limit, sleeping for 10 seconds");
-                sleep(Duration::from_secs(10)).await;
+                metrics().anilist_rate_limited.inc();
+                let remaining = response.headers()
+                    .get("x-ratelimit-remaining")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("?");
+                warn!(remaining, retry_delay = options.retry_delay_secs, "Hit rate limit, sleeping");
+                sleep(Duration::from_secs(options.retry_delay_secs)).await;
                 continue;
             }
 
             if response.status() != 200 {
-                println!("Got the following: {}", response.status());
-                println!("Sleeping for 10 seconds");
-                sleep(Duration::from_secs(10)).await;
+                warn!(status = %response.status(), retry_delay = options.retry_delay_secs, "Request failed, sleeping");
+                sleep(Duration::from_secs(options.retry_delay_secs)).await;
                 continue;
             }
-            return response.text()
-                .await
-                .map(|response| serde_json::from_str(&response).unwrap());
+            let body = response.text().await?;
+            match serde_json::from_str(&body) {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    metrics().anilist_request_failures.inc();
+                    warn!(error = %e, retry_delay = options.retry_delay_secs, "Invalid JSON response, sleeping");
+                    sleep(Duration::from_secs(options.retry_delay_secs)).await;
+                }
+            }
         }
     }
 
@@ -186,20 +305,40 @@ impl Downloader {
         let media = data["media"].as_array().map(|arr| {
             let anime_metadata_vec: Vec<AnimeMetadata> = arr
                 .iter()
-                .filter_map(|val| {
-                    let anime_metadata: Option<AnimeMetadata> = match serde_json::from_value(val.clone()) {
-                        Ok(anime_metadata) => Some(anime_metadata),
-                        Err(_) => None,
-                    };
-                    anime_metadata
-                })
+                .filter_map(|val| serde_json::from_value(val.clone()).ok())
                 .collect();
             anime_metadata_vec
         }).unwrap_or_default();
         if media.is_empty() {
-            println!("{:#}", response);
-            println!("Empty list, something is wrong!");
+            debug!(%response, "Empty response");
+            warn!("Empty list, something is wrong!");
         }
         (media, *has_next_page)
     }
 }
+
+/// The pages of a crawl over `seasons` seasons, once the first `seasons_seen`
+/// told they have `pages_seen` pages. The seasons not reached yet are expected
+/// to have as many as the average so far, rounded up.
+fn expected_pages(seasons: u64, seasons_seen: u64, pages_seen: u64) -> u64 {
+    if seasons_seen == 0 {
+        return seasons;
+    }
+    let remaining = seasons.saturating_sub(seasons_seen);
+    pages_seen + remaining * pages_seen.div_ceil(seasons_seen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unseen_seasons_are_expected_to_have_the_average_pages() {
+        assert_eq!(expected_pages(8, 0, 0), 8);
+        assert_eq!(expected_pages(8, 1, 5), 5 + 7 * 5);
+        assert_eq!(expected_pages(8, 2, 5), 5 + 6 * 3);
+        assert_eq!(expected_pages(8, 8, 21), 21);
+        // More seasons than planned, e.g. a crawl running into next year.
+        assert_eq!(expected_pages(8, 9, 30), 30);
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use candle_core::{Device, Error, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::{error, info};
+
+use crate::storage::Storage;
+use crate::types::{AnimeEmbedding, EmbeddingSource};
+
+const MAX_SEQUENCE_LENGTH: usize = 512;
+
+/// A BERT-style sentence-transformer (e.g. all-MiniLM-L6-v2) running on the
+/// CPU. `model_dir` must contain the `config.json`, `tokenizer.json` and
+/// `model.safetensors` files of the model.
+pub struct Embedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    pub model_name: String,
+}
+
+impl Embedder {
+    pub fn load(model_dir: &Path, model_name: String) -> Result<Self> {
+        let device = Device::Cpu;
+        let config = std::fs::read_to_string(model_dir.join("config.json"))?;
+        let config: Config = serde_json::from_str(&config).map_err(Error::wrap)?;
+
+        let mut tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).map_err(Error::msg)?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_SEQUENCE_LENGTH.min(config.max_position_embeddings),
+                ..Default::default()
+            }))
+            .map_err(Error::msg)?;
+
+        let weights = model_dir.join("model.safetensors");
+        // Safety: the weights file is memory mapped and must not be modified
+        // while the model is loaded.
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DTYPE, &device)? };
+        let model = BertModel::load(vb, &config)?;
+        Ok(Self { model, tokenizer, device, model_name })
+    }
+
+    /// Embeds a batch of texts into L2-normalized vectors using mean pooling
+    /// over the non-padding tokens, as sentence-transformers does.
+    pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let encodings = self.tokenizer.encode_batch(texts.to_vec(), true).map_err(Error::msg)?;
+        let token_ids = encodings.iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<Result<Vec<_>>>()?;
+        let attention_mask = encodings.iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<Result<Vec<_>>>()?;
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let output = self.model.forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let pooled = output.broadcast_mul(&mask)?.sum(1)?.broadcast_div(&mask.sum(1)?)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        pooled.broadcast_div(&norm)?.to_vec2()
+    }
+
+    pub fn embed_one(&self, text: &str) -> Result<Vec<f32>> {
+        let mut embeddings = self.embed(&[text.to_string()])?;
+        embeddings.pop().ok_or_else(|| Error::msg("empty embedding batch"))
+    }
+}
+
+/// Embeds every text of `source` that has no embedding for the model yet and
+/// sends the vectors in batches to an `EmbeddingLoader`.
+pub struct EmbeddingGenerator {
+    storage: Storage,
+    embedder: Arc<Embedder>,
+    source: EmbeddingSource,
+    sender: mpsc::Sender<Option<Vec<AnimeEmbedding>>>,
+    batch_size: usize,
+}
+
+impl EmbeddingGenerator {
+    pub fn new(
+        storage: Storage,
+        embedder: Arc<Embedder>,
+        source: EmbeddingSource,
+        sender: mpsc::Sender<Option<Vec<AnimeEmbedding>>>,
+        batch_size: usize,
+    ) -> Self {
+        Self { storage, embedder, source, sender, batch_size }
+    }
+
+    pub async fn start_embed_job(&mut self) -> Result<bool> {
+        let mut after_id = 0;
+        let mut total = 0;
+        loop {
+            let batch = self.storage
+                .texts_without_embedding(&self.embedder.model_name, self.source, after_id, self.batch_size as i64)
+                .await
+                .map_err(Error::wrap)?;
+            let Some(&(last_id, _)) = batch.last() else {
+                break;
+            };
+            after_id = last_id;
+
+            let (ids, texts): (Vec<i32>, Vec<String>) = batch.into_iter().unzip();
+            let embedder = self.embedder.clone();
+            // Inference is CPU bound, keep it off the async workers.
+            let vectors = task::spawn_blocking(move || embedder.embed(&texts))
+                .await
+                .map_err(Error::wrap)??;
+            let embeddings: Vec<AnimeEmbedding> = ids.into_iter()
+                .zip(vectors)
+                .map(|(id, embedding)| AnimeEmbedding {
+                    id,
+                    model: self.embedder.model_name.clone(),
+                    source: self.source,
+                    embedding,
+                })
+                .collect();
+            total += embeddings.len();
+            info!(total, source = self.source.as_str(), after_id, "Embedded texts");
+            if let Err(e) = self.sender.send(Some(embeddings)).await {
+                error!(error = %e, "Could not send embeddings to the loader");
+                return Ok(false);
+            }
+        }
+        let _ = self.sender.send(None).await;
+        info!(total, source = self.source.as_str(), "Finished embedding");
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+impl Embedder {
+    /// A randomly initialized two-layer model over a handful of words, for
+    /// tests that need a [`crate::search::Searcher`] but not good vectors.
+    pub(crate) fn tiny() -> Self {
+        use std::str::FromStr;
+
+        let device = Device::Cpu;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "vocab_size": 8, "hidden_size": 8, "num_hidden_layers": 2, "num_attention_heads": 2,
+            "intermediate_size": 16, "hidden_act": "gelu", "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 32, "type_vocab_size": 2, "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12, "pad_token_id": 0, "model_type": "bert",
+        }))
+        .unwrap();
+        let tokenizer = Tokenizer::from_str(r#"{
+            "version": "1.0", "truncation": null, "padding": null, "added_tokens": [], "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"}, "post_processor": null, "decoder": null,
+            "model": {"type": "WordLevel", "unk_token": "[UNK]",
+                "vocab": {"[PAD]": 0, "[UNK]": 1, "knight": 2, "dragon": 3, "space": 4, "love": 5, "school": 6, "robot": 7}}
+        }"#)
+        .unwrap();
+        let weights = candle_nn::VarMap::new();
+        let model = BertModel::load(VarBuilder::from_varmap(&weights, DTYPE, &device), &config).unwrap();
+        Self { model, tokenizer, device, model_name: "tiny".to_string() }
+    }
+}
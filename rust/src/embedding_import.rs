@@ -0,0 +1,129 @@
+use std::fmt;
+use std::path::Path;
+use tracing::info;
+
+use crate::storage::Storage;
+use crate::types::{AnimeEmbedding, EmbeddingSource};
+
+/// The model `generate_embeddings.py` used to write `embeddings.csv`.
+pub const LEGACY_EMBEDDING_MODEL: &str = "sentence-transformers/all-mpnet-base-v2";
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(String, std::io::Error),
+    Parse { file: String, line: usize, message: String },
+    Mismatch(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(file, e) => write!(f, "{}: {}", file, e),
+            ImportError::Parse { file, line, message } => write!(f, "{}:{}: {}", file, line, message),
+            ImportError::Mismatch(message) => write!(f, "{}", message),
+            ImportError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<sqlx::Error> for ImportError {
+    fn from(e: sqlx::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+/// The lines of a file with their 1-based line numbers. `generate_embeddings.py`
+/// writes `ids.txt` without a trailing newline, `np.savetxt` with one. A blank
+/// line is an error: skipping it in one file but not the other would pair
+/// every following id with the wrong vector.
+fn lines(path: &Path) -> Result<Vec<(usize, String)>, ImportError> {
+    let file = path.display().to_string();
+    let content = std::fs::read_to_string(path).map_err(|e| ImportError::Io(file.clone(), e))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match line.trim() {
+            "" => Err(ImportError::Parse { file: file.clone(), line: i + 1, message: "blank line".to_string() }),
+            line => Ok((i + 1, line.to_string())),
+        })
+        .collect()
+}
+
+/// Reads an `ids.txt`/`embeddings.csv` pair, where line `n` of the CSV holds
+/// the vector of the media id on line `n` of `ids.txt`. Both files must have
+/// the same number of lines and every vector the same dimension.
+pub fn read_legacy_embeddings(
+    ids_path: &Path,
+    embeddings_path: &Path,
+    model: &str,
+    source: EmbeddingSource,
+) -> Result<Vec<AnimeEmbedding>, ImportError> {
+    let ids_file = ids_path.display().to_string();
+    let embeddings_file = embeddings_path.display().to_string();
+    let ids = lines(ids_path)?;
+    let rows = lines(embeddings_path)?;
+    if ids.len() != rows.len() {
+        return Err(ImportError::Mismatch(format!(
+            "{} has {} ids but {} has {} vectors",
+            ids_file, ids.len(), embeddings_file, rows.len(),
+        )));
+    }
+
+    let mut dimension = None;
+    let mut embeddings = Vec::with_capacity(ids.len());
+    for ((id_line, id), (row_line, row)) in ids.into_iter().zip(rows) {
+        let id: i32 = id.parse().map_err(|_| ImportError::Parse {
+            file: ids_file.clone(),
+            line: id_line,
+            message: format!("invalid media id {:?}", id),
+        })?;
+        let embedding = row
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| ImportError::Parse {
+                file: embeddings_file.clone(),
+                line: row_line,
+                message: e.to_string(),
+            })?;
+        match dimension {
+            None => dimension = Some(embedding.len()),
+            Some(dimension) if dimension != embedding.len() => {
+                return Err(ImportError::Parse {
+                    file: embeddings_file.clone(),
+                    line: row_line,
+                    message: format!("expected {} values, got {}", dimension, embedding.len()),
+                });
+            }
+            Some(_) => {}
+        }
+        embeddings.push(AnimeEmbedding {
+            id,
+            model: model.to_string(),
+            source,
+            embedding,
+        });
+    }
+    Ok(embeddings)
+}
+
+/// Imports an `ids.txt`/`embeddings.csv` pair into `anime_embedding` and
+/// returns the number of vectors stored. The vectors are written in one
+/// transaction, so nothing is written if either file is malformed or an id
+/// is not a stored media.
+pub async fn import_legacy_embeddings(
+    storage: &Storage,
+    ids_path: &Path,
+    embeddings_path: &Path,
+    model: &str,
+    source: EmbeddingSource,
+) -> Result<usize, ImportError> {
+    let embeddings = read_legacy_embeddings(ids_path, embeddings_path, model, source)?;
+    let total = embeddings.len();
+    info!(total, "Importing embeddings");
+    storage.upsert_embeddings(embeddings).await?;
+    Ok(total)
+}
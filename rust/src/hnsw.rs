@@ -0,0 +1,523 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{self, Read, Write};
+
+use crate::vector_index::dot;
+
+// Sanity bounds of a saved graph, far above anything `insert` produces, so
+// that a corrupt file cannot make `read_from` allocate without limit.
+const MAX_LAYERS: usize = 64;
+const MAX_M: usize = 1024;
+const MAX_DIMENSION: usize = 1 << 16;
+
+/// Construction parameters of an [`Hnsw`] graph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswParams {
+    /// Links per node on the upper layers, twice as many on layer 0.
+    pub m: usize,
+    /// Size of the candidate list while inserting.
+    pub ef_construction: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self { m: 16, ef_construction: 200 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scored {
+    score: f32,
+    node: u32,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A hierarchical navigable small world graph (Malkov & Yashunin) over
+/// normalized vectors, scored by dot product. Removed ids stay in the graph as
+/// tombstones that searches walk through but never return; an id inserted
+/// again with a new vector gets a new node and tombstones the old one.
+/// Rebuild the graph once [`Hnsw::deleted`] grows large.
+pub struct Hnsw {
+    params: HnswParams,
+    dimension: usize,
+    ids: Vec<i32>,
+    vectors: Vec<f32>,
+    // links[node][layer]
+    links: Vec<Vec<Vec<u32>>>,
+    deleted: Vec<bool>,
+    // Live nodes only.
+    nodes_by_id: HashMap<i32, u32>,
+    entry: Option<u32>,
+    rng: u64,
+}
+
+impl Hnsw {
+    pub fn new(dimension: usize, params: HnswParams) -> Self {
+        Self {
+            params,
+            dimension,
+            ids: vec![],
+            vectors: vec![],
+            links: vec![],
+            deleted: vec![],
+            nodes_by_id: HashMap::new(),
+            entry: None,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// The number of live ids.
+    pub fn len(&self) -> usize {
+        self.nodes_by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes_by_id.is_empty()
+    }
+
+    /// The number of tombstones.
+    pub fn deleted(&self) -> usize {
+        self.ids.len() - self.nodes_by_id.len()
+    }
+
+    /// The live ids, in no particular order.
+    pub fn ids(&self) -> impl Iterator<Item = i32> + '_ {
+        self.nodes_by_id.keys().copied()
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        self.nodes_by_id.contains_key(&id)
+    }
+
+    pub fn vector(&self, id: i32) -> Option<&[f32]> {
+        self.nodes_by_id.get(&id).map(|node| self.node_vector(*node))
+    }
+
+    fn node_vector(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dimension;
+        &self.vectors[start..start + self.dimension]
+    }
+
+    fn score(&self, query: &[f32], node: u32) -> f32 {
+        dot(query, self.node_vector(node))
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 { self.params.m * 2 } else { self.params.m }
+    }
+
+    // splitmix64, so that builds are reproducible without a rand dependency.
+    fn next_random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // In (0, 1] so that the logarithm below is finite.
+        ((z >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    fn random_layer(&mut self) -> usize {
+        let ml = 1.0 / (self.params.m.max(2) as f64).ln();
+        (-self.next_random().ln() * ml).floor() as usize
+    }
+
+    fn top_layer(&self) -> usize {
+        self.entry.map_or(0, |entry| self.links[entry as usize].len() - 1)
+    }
+
+    /// Removes an id from the search results. Its node is kept to navigate
+    /// through.
+    pub fn remove(&mut self, id: i32) -> bool {
+        match self.nodes_by_id.remove(&id) {
+            Some(node) => {
+                self.deleted[node as usize] = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a normalized vector, or replaces the vector of an existing id.
+    /// Inserting an id again with the same vector does nothing.
+    pub fn insert(&mut self, id: i32, vector: &[f32]) {
+        assert_eq!(vector.len(), self.dimension, "vector dimension does not match the index");
+        if self.vector(id) == Some(vector) {
+            return;
+        }
+        self.remove(id);
+
+        let node = self.ids.len() as u32;
+        let layer = self.random_layer();
+        self.ids.push(id);
+        self.vectors.extend_from_slice(vector);
+        self.links.push(vec![vec![]; layer + 1]);
+        self.deleted.push(false);
+        self.nodes_by_id.insert(id, node);
+
+        let Some(mut entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+        let top = self.top_layer();
+        for lc in (layer + 1..=top).rev() {
+            entry = self.greedy(vector, entry, lc);
+        }
+        let mut entries = vec![entry];
+        for lc in (0..=layer.min(top)).rev() {
+            let candidates = self.search_layer(vector, &entries, self.params.ef_construction, lc);
+            let neighbors = self.select_neighbors(&candidates, self.params.m);
+            for &neighbor in &neighbors {
+                self.links[neighbor as usize][lc].push(node);
+                if self.links[neighbor as usize][lc].len() > self.max_links(lc) {
+                    self.shrink(neighbor, lc);
+                }
+            }
+            self.links[node as usize][lc] = neighbors;
+            entries = candidates.iter().map(|c| c.node).collect();
+        }
+        if layer > top {
+            self.entry = Some(node);
+        }
+    }
+
+    fn shrink(&mut self, node: u32, layer: usize) {
+        let base = self.node_vector(node).to_vec();
+        let mut candidates: Vec<Scored> = self.links[node as usize][layer].iter()
+            .map(|&n| Scored { score: self.score(&base, n), node: n })
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        self.links[node as usize][layer] = self.select_neighbors(&candidates, self.max_links(layer));
+    }
+
+    /// The neighbor selection heuristic of the paper: a candidate is kept only
+    /// if it is closer to the base than to every neighbor kept so far, which
+    /// spreads the links in different directions. Pruned candidates fill up
+    /// the remaining slots. `candidates` must be sorted best first.
+    fn select_neighbors(&self, candidates: &[Scored], m: usize) -> Vec<u32> {
+        let mut kept: Vec<u32> = Vec::with_capacity(m);
+        let mut pruned = vec![];
+        for candidate in candidates {
+            if kept.len() == m {
+                break;
+            }
+            let vector = self.node_vector(candidate.node);
+            if kept.iter().all(|&k| self.score(vector, k) < candidate.score) {
+                kept.push(candidate.node);
+            } else {
+                pruned.push(candidate.node);
+            }
+        }
+        kept.extend(pruned.into_iter().take(m - kept.len()));
+        kept
+    }
+
+    fn greedy(&self, query: &[f32], entry: u32, layer: usize) -> u32 {
+        self.search_layer(query, &[entry], 1, layer)[0].node
+    }
+
+    /// Best-first search of one layer, returning up to `ef` nodes best first.
+    fn search_layer(&self, query: &[f32], entries: &[u32], ef: usize, layer: usize) -> Vec<Scored> {
+        let mut visited: HashSet<u32> = entries.iter().copied().collect();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &entry in entries {
+            let scored = Scored { score: self.score(query, entry), node: entry };
+            candidates.push(scored);
+            results.push(Reverse(scored));
+            if results.len() > ef {
+                results.pop();
+            }
+        }
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map_or(f32::NEG_INFINITY, |r| r.0.score);
+            if candidate.score < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in &self.links[candidate.node as usize][layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let score = self.score(query, neighbor);
+                let worst = results.peek().map_or(f32::NEG_INFINITY, |r| r.0.score);
+                if results.len() < ef || score > worst {
+                    let scored = Scored { score, node: neighbor };
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        let mut results: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Returns up to `k` `(id, score)` pairs for a normalized query, best
+    /// first. A larger `ef` trades speed for recall.
+    pub fn search(&self, query: &[f32], k: usize, ef: usize) -> Vec<(i32, f32)> {
+        let Some(mut entry) = self.entry else {
+            return vec![];
+        };
+        for lc in (1..=self.top_layer()).rev() {
+            entry = self.greedy(query, entry, lc);
+        }
+        self.search_layer(query, &[entry], ef.max(k), 0)
+            .into_iter()
+            .filter(|scored| !self.deleted[scored.node as usize])
+            .take(k)
+            .map(|scored| (self.ids[scored.node as usize], scored.score))
+            .collect()
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_u64(writer, self.params.m as u64)?;
+        write_u64(writer, self.params.ef_construction as u64)?;
+        write_u64(writer, self.dimension as u64)?;
+        write_u64(writer, self.rng)?;
+        write_u64(writer, self.entry.map_or(u64::MAX, |entry| entry as u64))?;
+        write_u64(writer, self.ids.len() as u64)?;
+        for (node, id) in self.ids.iter().enumerate() {
+            writer.write_all(&id.to_le_bytes())?;
+            writer.write_all(&[self.deleted[node] as u8])?;
+            for value in self.node_vector(node as u32) {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            let layers = &self.links[node];
+            write_u64(writer, layers.len() as u64)?;
+            for links in layers {
+                write_u64(writer, links.len() as u64)?;
+                for link in links {
+                    writer.write_all(&link.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let params = HnswParams {
+            m: read_u64(reader)? as usize,
+            ef_construction: read_u64(reader)? as usize,
+        };
+        let dimension = read_u64(reader)? as usize;
+        if !(1..=MAX_M).contains(&params.m) || dimension > MAX_DIMENSION {
+            return Err(invalid_data("graph parameters out of range"));
+        }
+        let rng = read_u64(reader)?;
+        let entry = match read_u64(reader)? {
+            u64::MAX => None,
+            entry => Some(entry as u32),
+        };
+        let count = read_u64(reader)? as usize;
+        let mut graph = Self::new(dimension, params);
+        graph.rng = rng;
+        graph.entry = entry;
+        for node in 0..count {
+            let id = read_i32(reader)?;
+            let mut deleted = [0];
+            reader.read_exact(&mut deleted)?;
+            for _ in 0..dimension {
+                graph.vectors.push(read_f32(reader)?);
+            }
+            let layers = read_u64(reader)? as usize;
+            if !(1..=MAX_LAYERS).contains(&layers) {
+                return Err(invalid_data("node layer count out of range"));
+            }
+            let mut node_links = Vec::with_capacity(layers);
+            for layer in 0..layers {
+                let len = read_u64(reader)? as usize;
+                if len > graph.max_links(layer) {
+                    return Err(invalid_data("too many links"));
+                }
+                let mut links = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let link = read_u32(reader)?;
+                    if link as usize >= count {
+                        return Err(invalid_data("link to a missing node"));
+                    }
+                    links.push(link);
+                }
+                node_links.push(links);
+            }
+            graph.ids.push(id);
+            graph.links.push(node_links);
+            graph.deleted.push(deleted[0] != 0);
+            if deleted[0] == 0 && graph.nodes_by_id.insert(id, node as u32).is_some() {
+                return Err(invalid_data("id stored twice"));
+            }
+        }
+        if entry.is_some_and(|entry| entry as usize >= count) {
+            return Err(invalid_data("entry point out of range"));
+        }
+        Ok(graph)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+/// The average fraction of the exact top-k ids found by an approximate search.
+pub fn recall_at_k(exact: &[Vec<i32>], approximate: &[Vec<i32>]) -> f64 {
+    if exact.is_empty() {
+        return 1.0;
+    }
+    let total: f64 = exact.iter()
+        .zip(approximate)
+        .map(|(exact, approximate)| {
+            if exact.is_empty() {
+                return 1.0;
+            }
+            let found = approximate.iter().filter(|id| exact.contains(id)).count();
+            found as f64 / exact.len() as f64
+        })
+        .sum();
+    total / exact.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_index::normalize;
+
+    fn random_vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = Hnsw::new(0, HnswParams::default());
+        rng.rng = seed;
+        (0..count)
+            .map(|_| {
+                let mut vector: Vec<f32> = (0..dimension).map(|_| rng.next_random() as f32 - 0.5).collect();
+                normalize(&mut vector);
+                vector
+            })
+            .collect()
+    }
+
+    fn exact_top(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<i32> {
+        let mut scored: Vec<(i32, f32)> = vectors.iter()
+            .enumerate()
+            .map(|(id, vector)| (id as i32, dot(query, vector)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn recall_against_exact_search() {
+        let vectors = random_vectors(1000, 32, 1);
+        let mut graph = Hnsw::new(32, HnswParams::default());
+        for (id, vector) in vectors.iter().enumerate() {
+            graph.insert(id as i32, vector);
+        }
+        let queries = random_vectors(100, 32, 2);
+        let exact: Vec<Vec<i32>> = queries.iter().map(|query| exact_top(&vectors, query, 10)).collect();
+        let approximate: Vec<Vec<i32>> = queries.iter()
+            .map(|query| graph.search(query, 10, 64).into_iter().map(|(id, _)| id).collect())
+            .collect();
+        let recall = recall_at_k(&exact, &approximate);
+        assert!(recall >= 0.9, "recall@10 is {}", recall);
+    }
+
+    #[test]
+    fn removed_and_replaced_ids() {
+        let vectors = random_vectors(200, 8, 3);
+        let mut graph = Hnsw::new(8, HnswParams::default());
+        for (id, vector) in vectors.iter().enumerate() {
+            graph.insert(id as i32, vector);
+        }
+        assert!(graph.remove(5));
+        assert!(!graph.remove(5));
+        assert!(graph.search(&vectors[5], 10, 64).iter().all(|(id, _)| *id != 5));
+
+        // Moving 7 onto 9's vector makes it a nearest neighbor of 9.
+        graph.insert(7, &vectors[9]);
+        assert_eq!(graph.vector(7), Some(vectors[9].as_slice()));
+        let top: Vec<i32> = graph.search(&vectors[9], 2, 64).into_iter().map(|(id, _)| id).collect();
+        assert!(top.contains(&7) && top.contains(&9), "{:?}", top);
+        // The same vector again changes nothing.
+        graph.insert(7, &vectors[9]);
+        assert_eq!((graph.len(), graph.deleted()), (199, 2));
+    }
+
+    #[test]
+    fn saved_graphs_are_read_back() {
+        let vectors = random_vectors(50, 4, 4);
+        let mut graph = Hnsw::new(4, HnswParams::default());
+        for (id, vector) in vectors.iter().enumerate() {
+            graph.insert(id as i32, vector);
+        }
+        graph.remove(3);
+        let mut bytes = vec![];
+        graph.write_to(&mut bytes).unwrap();
+        let read = Hnsw::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!((read.len(), read.deleted()), (49, 1));
+        assert_eq!(read.search(&vectors[10], 5, 16), graph.search(&vectors[10], 5, 16));
+    }
+
+    #[test]
+    fn corrupt_counts_are_invalid_data() {
+        let mut graph = Hnsw::new(2, HnswParams::default());
+        graph.insert(1, &[1.0, 0.0]);
+        let mut bytes = vec![];
+        graph.write_to(&mut bytes).unwrap();
+        // The layer count of the only node follows the header, its id, its
+        // deleted flag and its vector.
+        let layers_at = 6 * 8 + 4 + 1 + 2 * 4;
+        let mut corrupt = bytes.clone();
+        corrupt[layers_at..layers_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let error = Hnsw::read_from(&mut corrupt.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // So does the link count of its first layer.
+        let mut corrupt = bytes;
+        corrupt[layers_at + 8..layers_at + 16].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        let error = Hnsw::read_from(&mut corrupt.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
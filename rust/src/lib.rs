@@ -2,5 +2,25 @@ pub mod types;
 pub mod downloader;
 pub mod db_loader;
 pub mod summarizer;
-pub mod constants;
 pub mod db_query;
+pub mod migrations;
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod progress;
+pub mod shutdown;
+pub mod storage;
+pub mod content_policy;
+pub mod embedder;
+pub mod embedding_import;
+pub mod search;
+pub mod hnsw;
+pub mod vector_index;
+pub mod llm;
+pub mod query_parser;
+pub mod rerank;
+pub mod answer;
+pub mod recommend;
+pub mod user_list;
+pub mod api;
+pub mod repl;
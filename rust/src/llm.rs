@@ -0,0 +1,253 @@
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::config::{ApiKey, LlmConfig};
+use crate::metrics::metrics;
+
+const MAX_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// Wait after a 429 that doesn't say how long to wait.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self { role: "system".to_string(), content: content.into() }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self { role: "user".to_string(), content: content.into() }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self { role: "assistant".to_string(), content: content.into() }
+    }
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    MissingApiKey,
+    Http(reqwest::Error),
+    Status(u16),
+    /// Still rate limited after the last attempt, asked to wait this long.
+    RateLimited(Duration),
+    /// The backend answered, but not with what was asked for.
+    Response(String),
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::MissingApiKey => write!(f, "no LLM API key is configured"),
+            LlmError::Http(e) => write!(f, "LLM request failed: {}", e),
+            LlmError::Status(status) => write!(f, "LLM backend answered with status {}", status),
+            LlmError::RateLimited(retry_after) => write!(f, "LLM backend is rate limited for {}s", retry_after.as_secs()),
+            LlmError::Response(message) => write!(f, "unexpected LLM response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        LlmError::Http(e)
+    }
+}
+
+/// A client for the OpenAI-compatible chat completions endpoint configured by
+/// [`crate::config::Config::llm`]. Rate limited requests are retried after the delay
+/// the backend asks for; other failed requests are only retried when
+/// [`LlmClient::with_retries`] sets a delay.
+#[derive(Clone)]
+pub struct LlmClient {
+    client: Client,
+    url: String,
+    model: String,
+    api_key: Option<ApiKey>,
+    // Which configured key `api_key` is, to label metrics with.
+    key_index: usize,
+    temperature: f32,
+    max_tokens: u32,
+    max_attempts: u32,
+    retry_delay: Option<Duration>,
+}
+
+impl LlmClient {
+    /// A client using the first configured API key.
+    pub fn new(config: &LlmConfig) -> Self {
+        Self::with_key(config, 0)
+    }
+
+    /// A client using the configured API key at `index`.
+    pub fn with_key(config: &LlmConfig, index: usize) -> Self {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            url: config.url.clone(),
+            model: config.model.clone(),
+            api_key: config.api_keys.get(index).cloned(),
+            key_index: index,
+            temperature: 0.0,
+            max_tokens: 1024,
+            max_attempts: MAX_ATTEMPTS,
+            retry_delay: None,
+        }
+    }
+
+    /// Samples replies at `temperature`, up to `max_tokens` long.
+    pub fn with_sampling(mut self, temperature: f32, max_tokens: u32) -> Self {
+        self.temperature = temperature;
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Makes up to `max_attempts` requests, waiting `retry_delay` after a
+    /// failed one.
+    pub fn with_retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = Some(retry_delay);
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Sends a conversation and returns the content of the reply. With
+    /// `json_object` the backend is asked to reply with a JSON object.
+    pub async fn chat(&self, messages: &[ChatMessage], json_object: bool) -> Result<String, LlmError> {
+        let api_key = self.api_key.as_ref().ok_or(LlmError::MissingApiKey)?;
+        let mut payload = json!({
+            "model": self.model,
+            "messages": messages,
+            "temperature": self.temperature,
+            "max_tokens": self.max_tokens,
+            "stream": false,
+        });
+        if json_object {
+            payload["response_format"] = json!({ "type": "json_object" });
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let result = self.request(api_key, &payload).await;
+            let retry_after = match &result {
+                Err(LlmError::RateLimited(retry_after)) => Some(*retry_after),
+                Err(LlmError::Http(_) | LlmError::Status(_)) => self.retry_delay,
+                _ => None,
+            };
+            match (result, retry_after) {
+                (Err(e), Some(delay)) if attempt < self.max_attempts => {
+                    warn!(error = %e, retry_delay = delay.as_secs(), attempt, "LLM request failed, retrying");
+                    sleep(delay).await;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    async fn request(&self, api_key: &ApiKey, payload: &serde_json::Value) -> Result<String, LlmError> {
+        let started = Instant::now();
+        let response = self.client.post(&self.url)
+            .bearer_auth(api_key.expose())
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await?;
+        let status = response.status();
+        metrics().llm_latency.observe(started.elapsed());
+        metrics().llm_requests.inc(status.as_str());
+        if status.as_u16() == 429 {
+            metrics().llm_rate_limited.inc(&self.key_index.to_string());
+            let retry_after = response.headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| retry_after(value, SystemTime::now()))
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            return Err(LlmError::RateLimited(retry_after));
+        }
+        if !status.is_success() {
+            return Err(LlmError::Status(status.as_u16()));
+        }
+        let body: serde_json::Value = serde_json::from_str(&response.text().await?)
+            .map_err(|e| LlmError::Response(e.to_string()))?;
+        for kind in ["prompt", "completion"] {
+            if let Some(tokens) = body["usage"][format!("{}_tokens", kind)].as_u64() {
+                metrics().llm_tokens.add(kind, tokens);
+            }
+        }
+        body["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|content| content.to_string())
+            .ok_or_else(|| LlmError::Response("no message content".to_string()))
+    }
+
+    /// Like [`LlmClient::chat`] in JSON mode, parsing the reply.
+    pub async fn chat_json(&self, messages: &[ChatMessage]) -> Result<serde_json::Value, LlmError> {
+        let content = self.chat(messages, true).await?;
+        serde_json::from_str(&content).map_err(|e| LlmError::Response(format!("invalid JSON: {}", e)))
+    }
+}
+
+#[cfg(test)]
+impl LlmClient {
+    /// A client for a local backend serving chat completions with `handler`.
+    pub(crate) async fn mock<H, T>(handler: H) -> Self
+    where
+        H: axum::handler::Handler<T, ()>,
+        T: 'static,
+    {
+        let app = axum::Router::new().route("/", axum::routing::post(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        LlmClient::new(&LlmConfig { url, model: "test".to_string(), api_keys: vec![ApiKey::new("test-key")] })
+    }
+
+    /// The body of a chat completion replying `content`.
+    pub(crate) fn completion(content: &str) -> serde_json::Value {
+        json!({ "choices": [{ "message": { "content": content } }] })
+    }
+}
+
+/// Reads a `Retry-After` header, either a number of seconds or an HTTP date.
+/// Dates in the past mean no wait.
+fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_reads_seconds_and_dates() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2026 07:28:00 GMT").unwrap();
+        assert_eq!(retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after("Wed, 21 Oct 2026 07:28:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after("Wed, 21 Oct 2026 07:27:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(retry_after("soon", now), None);
+        assert_eq!(retry_after("-5", now), None);
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::IsTerminal;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+use crate::progress::StderrWriter;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event, with the fields of its spans.
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Installs the global subscriber. Logs go to stderr, above any progress bar,
+/// so that what commands print on stdout, search hits or exports, can be
+/// piped.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| format!("invalid log level {:?}: {}", config.level, e))?;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| StderrWriter);
+    let result = match config.format {
+        LogFormat::Text => subscriber.with_ansi(std::io::stderr().is_terminal()).try_init(),
+        LogFormat::Json => subscriber.json().with_current_span(false).with_span_list(true).try_init(),
+    };
+    result.map_err(|e| format!("failed to set up logging: {}", e))
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::info;
+
+// Upper bounds of the LLM latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// How the pipeline metrics are exposed, set in the `[metrics]` table of the
+/// config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsOptions {
+    /// Address of the Prometheus endpoint, served at `/metrics` while
+    /// `crawl` and `summarize` run. Nothing is served without it.
+    pub listen: Option<String>,
+    /// Interval of the metrics log line, 0 to only log them at the end.
+    pub dump_interval_secs: u64,
+}
+
+impl Default for MetricsOptions {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            dump_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A counter per value of one label.
+pub struct LabeledCounter {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str) -> Self {
+        Self { label, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, value: &str) {
+        self.add(value, 1);
+    }
+
+    pub fn add(&self, value: &str, n: u64) {
+        *self.values.lock().unwrap().entry(value.to_string()).or_default() += n;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.values.lock().unwrap().values().sum()
+    }
+}
+
+#[derive(Default)]
+struct HistogramState {
+    // Not cumulative, one count per bucket and a last one for +Inf.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+/// Observed durations in the `LATENCY_BUCKETS`.
+#[derive(Default)]
+pub struct Histogram(Mutex<HistogramState>);
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        let mut state = self.0.lock().unwrap();
+        state.counts[bucket] += 1;
+        state.sum += seconds;
+    }
+
+    /// The number of observations and their mean in seconds.
+    pub fn mean(&self) -> (u64, f64) {
+        let state = self.0.lock().unwrap();
+        let count = state.counts.iter().sum::<u64>();
+        (count, if count == 0 { 0.0 } else { state.sum / count as f64 })
+    }
+}
+
+type QueueProbe = Box<dyn Fn() -> Option<(usize, usize)> + Send + Sync>;
+
+/// The counters of the crawl and summarization pipelines, shared by the whole
+/// process through [`metrics`].
+pub struct Metrics {
+    pub pages_downloaded: Counter,
+    pub media_downloaded: Counter,
+    pub anilist_rate_limited: Counter,
+    /// AniList requests that failed to send or returned invalid JSON.
+    pub anilist_request_failures: Counter,
+    pub summaries_produced: Counter,
+    pub summary_parse_failures: Counter,
+    /// Media given up on after their last summarization request failed.
+    pub summary_request_failures: Counter,
+    /// LLM responses by status code.
+    pub llm_requests: LabeledCounter,
+    /// 429 responses by index of the API key in the config.
+    pub llm_rate_limited: LabeledCounter,
+    pub llm_latency: Histogram,
+    /// Tokens reported by the backend, `prompt` or `completion`.
+    pub llm_tokens: LabeledCounter,
+    queues: Mutex<Vec<(&'static str, QueueProbe)>>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            pages_downloaded: Counter::default(),
+            media_downloaded: Counter::default(),
+            anilist_rate_limited: Counter::default(),
+            anilist_request_failures: Counter::default(),
+            summaries_produced: Counter::default(),
+            summary_parse_failures: Counter::default(),
+            summary_request_failures: Counter::default(),
+            llm_requests: LabeledCounter::new("status"),
+            llm_rate_limited: LabeledCounter::new("key"),
+            llm_latency: Histogram::default(),
+            llm_tokens: LabeledCounter::new("kind"),
+            queues: Mutex::new(vec![]),
+        }
+    }
+
+    /// Reports the depth of a channel under `name`. Only a weak handle is
+    /// kept, so watching a channel does not keep it open.
+    pub fn watch_queue<T: Send + 'static>(&self, name: &'static str, sender: &mpsc::Sender<T>) {
+        let sender = sender.downgrade();
+        let probe: QueueProbe = Box::new(move || {
+            sender.upgrade().map(|sender| (sender.max_capacity() - sender.capacity(), sender.max_capacity()))
+        });
+        self.queues.lock().unwrap().push((name, probe));
+    }
+
+    /// The depth and capacity of every open watched channel. Channels watched
+    /// under the same name are added up.
+    pub fn queue_depths(&self) -> BTreeMap<&'static str, (usize, usize)> {
+        let mut depths = BTreeMap::new();
+        for (name, probe) in self.queues.lock().unwrap().iter() {
+            if let Some((depth, capacity)) = probe() {
+                let entry: &mut (usize, usize) = depths.entry(*name).or_default();
+                entry.0 += depth;
+                entry.1 += capacity;
+            }
+        }
+        depths
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "lam_pages_downloaded_total", "AniList pages downloaded.", self.pages_downloaded.get());
+        counter(&mut out, "lam_media_downloaded_total", "Media downloaded from AniList.", self.media_downloaded.get());
+        counter(&mut out, "lam_anilist_rate_limited_total", "429 responses from AniList.", self.anilist_rate_limited.get());
+        counter(&mut out, "lam_anilist_request_failures_total", "AniList requests that failed or returned invalid JSON.", self.anilist_request_failures.get());
+        counter(&mut out, "lam_summaries_produced_total", "Summaries generated and sent to the loader.", self.summaries_produced.get());
+        counter(&mut out, "lam_summary_parse_failures_total", "LLM replies that were not a valid summary.", self.summary_parse_failures.get());
+        counter(&mut out, "lam_summary_request_failures_total", "Media given up on after failed LLM requests.", self.summary_request_failures.get());
+        labeled_counter(&mut out, "lam_llm_requests_total", "LLM responses by status code.", &self.llm_requests);
+        labeled_counter(&mut out, "lam_llm_rate_limited_total", "429 responses from the LLM backend by API key index.", &self.llm_rate_limited);
+        labeled_counter(&mut out, "lam_llm_tokens_total", "Tokens used by LLM requests.", &self.llm_tokens);
+
+        let name = "lam_llm_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of LLM requests.\n# TYPE {} histogram", name, name);
+        let state = self.llm_latency.0.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(state.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += state.counts[LATENCY_BUCKETS.len()];
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, state.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+        drop(state);
+
+        let depths = self.queue_depths();
+        let _ = writeln!(out, "# HELP lam_queue_depth Items waiting in a pipeline channel.\n# TYPE lam_queue_depth gauge");
+        for (queue, (depth, _)) in &depths {
+            let _ = writeln!(out, "lam_queue_depth{{queue=\"{}\"}} {}", escape(queue), depth);
+        }
+        let _ = writeln!(out, "# HELP lam_queue_capacity Capacity of a pipeline channel.\n# TYPE lam_queue_capacity gauge");
+        for (queue, (_, capacity)) in &depths {
+            let _ = writeln!(out, "lam_queue_capacity{{queue=\"{}\"}} {}", escape(queue), capacity);
+        }
+        out
+    }
+
+    /// Logs the main metrics on one line.
+    pub fn dump(&self) {
+        let (llm_requests, llm_mean_latency) = self.llm_latency.mean();
+        let queues = self.queue_depths()
+            .iter()
+            .map(|(queue, (depth, capacity))| format!("{}={}/{}", queue, depth, capacity))
+            .collect::<Vec<_>>()
+            .join(" ");
+        info!(
+            pages = self.pages_downloaded.get(),
+            media = self.media_downloaded.get(),
+            summaries = self.summaries_produced.get(),
+            parse_failures = self.summary_parse_failures.get(),
+            request_failures = self.summary_request_failures.get(),
+            llm_requests,
+            llm_mean_latency = format!("{:.2}s", llm_mean_latency),
+            llm_rate_limited = self.llm_rate_limited.total(),
+            anilist_rate_limited = self.anilist_rate_limited.get(),
+            anilist_request_failures = self.anilist_request_failures.get(),
+            tokens = self.llm_tokens.total(),
+            queues,
+            "Metrics",
+        );
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+fn labeled_counter(out: &mut String, name: &str, help: &str, counter: &LabeledCounter) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (value, count) in counter.values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, counter.label, escape(value), count);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+async fn prometheus() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics().render())
+}
+
+/// Serves the metrics at `/metrics`.
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(prometheus))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_uses_the_prometheus_text_format() {
+        let metrics = Metrics::new();
+        metrics.pages_downloaded.add(3);
+        metrics.llm_requests.inc("200");
+        metrics.llm_requests.add("500", 2);
+        metrics.llm_rate_limited.inc("1");
+        metrics.llm_tokens.inc("say \"hi\"");
+        metrics.llm_latency.observe(Duration::from_millis(200));
+        metrics.llm_latency.observe(Duration::from_secs(2));
+        metrics.llm_latency.observe(Duration::from_secs(120));
+        let (sender, _receiver) = mpsc::channel::<()>(4);
+        sender.try_send(()).unwrap();
+        metrics.watch_queue("loader", &sender);
+
+        let out = metrics.render();
+        for line in [
+            "# TYPE lam_pages_downloaded_total counter",
+            "lam_pages_downloaded_total 3",
+            "lam_anilist_request_failures_total 0",
+            "lam_llm_requests_total{status=\"200\"} 1",
+            "lam_llm_requests_total{status=\"500\"} 2",
+            "lam_llm_rate_limited_total{key=\"1\"} 1",
+            "lam_llm_tokens_total{kind=\"say \\\"hi\\\"\"} 1",
+            "# TYPE lam_llm_request_duration_seconds histogram",
+            "lam_llm_request_duration_seconds_bucket{le=\"0.1\"} 0",
+            "lam_llm_request_duration_seconds_bucket{le=\"0.25\"} 1",
+            "lam_llm_request_duration_seconds_bucket{le=\"2.5\"} 2",
+            "lam_llm_request_duration_seconds_bucket{le=\"60\"} 2",
+            "lam_llm_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "lam_llm_request_duration_seconds_sum 122.2",
+            "lam_llm_request_duration_seconds_count 3",
+            "lam_queue_depth{queue=\"loader\"} 1",
+            "lam_queue_capacity{queue=\"loader\"} 4",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {:?} in\n{}", line, out);
+        }
+
+        drop(sender);
+        assert!(!metrics.render().contains("queue=\"loader\""));
+    }
+}
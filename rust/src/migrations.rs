@@ -0,0 +1,208 @@
+use sqlx::{PgConnection, PgPool, Result, SqlitePool};
+use tracing::info;
+
+use crate::storage::Storage;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+    // Replaces `postgres` when the pgvector extension is enabled.
+    pub postgres_pgvector: Option<&'static str>,
+}
+
+// Migrations are applied in order and must never be edited once released,
+// add a new one instead. Both backends share the same version numbers.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create anime_metadata",
+        sqlite: include_str!("../migrations/sqlite/0001_create_anime_metadata.sql"),
+        postgres: include_str!("../migrations/postgres/0001_create_anime_metadata.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 2,
+        description: "create anime_summary",
+        sqlite: include_str!("../migrations/sqlite/0002_create_anime_summary.sql"),
+        postgres: include_str!("../migrations/postgres/0002_create_anime_summary.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 3,
+        description: "normalize genres and tags",
+        sqlite: include_str!("../migrations/sqlite/0003_normalize_genres_and_tags.sql"),
+        postgres: include_str!("../migrations/postgres/0003_normalize_genres_and_tags.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 4,
+        description: "add anime_metadata.is_adult",
+        sqlite: include_str!("../migrations/sqlite/0004_add_anime_metadata_is_adult.sql"),
+        postgres: include_str!("../migrations/postgres/0004_add_anime_metadata_is_adult.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 5,
+        description: "create anime_embedding",
+        sqlite: include_str!("../migrations/sqlite/0005_create_anime_embedding.sql"),
+        postgres: include_str!("../migrations/postgres/0005_create_anime_embedding.sql"),
+        postgres_pgvector: Some(include_str!("../migrations/postgres/0005_create_anime_embedding.pgvector.sql")),
+    },
+    Migration {
+        version: 6,
+        description: "add embedding provenance",
+        sqlite: include_str!("../migrations/sqlite/0006_add_embedding_provenance.sql"),
+        postgres: include_str!("../migrations/postgres/0006_add_embedding_provenance.sql"),
+        postgres_pgvector: Some(include_str!("../migrations/postgres/0006_add_embedding_provenance.pgvector.sql")),
+    },
+    Migration {
+        version: 7,
+        description: "create keyword search indexes",
+        sqlite: include_str!("../migrations/sqlite/0007_create_keyword_search.sql"),
+        postgres: include_str!("../migrations/postgres/0007_create_keyword_search.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 8,
+        description: "add anime_metadata.format",
+        sqlite: include_str!("../migrations/sqlite/0008_add_anime_metadata_format.sql"),
+        postgres: include_str!("../migrations/postgres/0008_add_anime_metadata_format.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 9,
+        description: "create rerank_cache",
+        sqlite: include_str!("../migrations/sqlite/0009_create_rerank_cache.sql"),
+        postgres: include_str!("../migrations/postgres/0009_create_rerank_cache.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 10,
+        description: "create media_relation",
+        sqlite: include_str!("../migrations/sqlite/0010_create_media_relation.sql"),
+        postgres: include_str!("../migrations/postgres/0010_create_media_relation.sql"),
+        postgres_pgvector: None,
+    },
+    Migration {
+        version: 11,
+        description: "create user_list",
+        sqlite: include_str!("../migrations/sqlite/0011_create_user_list.sql"),
+        postgres: include_str!("../migrations/postgres/0011_create_user_list.sql"),
+        postgres_pgvector: None,
+    },
+];
+
+// Arbitrary key for the Postgres advisory lock held while migrating, so that
+// several instances starting at once don't race each other.
+const POSTGRES_MIGRATION_LOCK: i64 = 0x4c414d;
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn pending(current: i64) -> Result<impl Iterator<Item = &'static Migration>> {
+    if current > latest_version() {
+        return Err(sqlx::Error::Configuration(format!(
+            "database schema version {} is newer than the latest known migration {}",
+            current,
+            latest_version()
+        ).into()));
+    }
+    Ok(MIGRATIONS.iter().filter(move |m| m.version > current))
+}
+
+pub async fn current_version(storage: &Storage) -> Result<i64> {
+    match storage {
+        Storage::Sqlite(pool) => current_version_sqlite(pool).await,
+        Storage::Postgres { pool, .. } => current_version_postgres(&mut *pool.acquire().await?).await,
+    }
+}
+
+/// Applies every pending migration and returns the versions that were applied.
+/// SQLite runs each migration in its own transaction, Postgres runs them all
+/// in one transaction under an advisory lock. Whether pgvector is enabled
+/// must be decided before the first migration that has a pgvector variant.
+pub async fn migrate(storage: &Storage) -> Result<Vec<i64>> {
+    match storage {
+        Storage::Sqlite(pool) => migrate_sqlite(pool).await,
+        Storage::Postgres { pool, pgvector } => migrate_postgres(pool, *pgvector).await,
+    }
+}
+
+async fn current_version_sqlite(pool: &SqlitePool) -> Result<i64> {
+    let sql = "
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+    ";
+    sqlx::query(sql).execute(pool).await?;
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version;")
+        .fetch_one(pool)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+async fn migrate_sqlite(pool: &SqlitePool) -> Result<Vec<i64>> {
+    let current = current_version_sqlite(pool).await?;
+    let mut applied = vec![];
+    for migration in pending(current)? {
+        let mut tx = pool.begin().await?;
+        sqlx::raw_sql(migration.sqlite).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES (?, ?);")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!(version = migration.version, description = migration.description, "Applied migration");
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+async fn current_version_postgres(conn: &mut PgConnection) -> Result<i64> {
+    let sql = "
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version BIGINT PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+    ";
+    sqlx::query(sql).execute(&mut *conn).await?;
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version;")
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+async fn migrate_postgres(pool: &PgPool, pgvector: bool) -> Result<Vec<i64>> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1);")
+        .bind(POSTGRES_MIGRATION_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    // Read under the lock, so that an instance that waited for it sees the
+    // migrations the previous holder applied.
+    let current = current_version_postgres(&mut tx).await?;
+    let mut applied = vec![];
+    for migration in pending(current)? {
+        let sql = match migration.postgres_pgvector {
+            Some(sql) if pgvector => sql,
+            _ => migration.postgres,
+        };
+        sqlx::raw_sql(sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_version (version, description) VALUES ($1, $2);")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+        info!(version = migration.version, description = migration.description, "Applied migration");
+        applied.push(migration.version);
+    }
+    tx.commit().await?;
+    Ok(applied)
+}
@@ -0,0 +1,164 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::info;
+
+// How often progress is logged when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+const TEMPLATE: &str = "{prefix} [{bar:40}] {pos}/{len} {msg} {per_sec} ETA {eta}";
+
+static BARS: LazyLock<MultiProgress> = LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()));
+
+/// Writes log lines to stderr above the progress bars rather than through
+/// them.
+pub struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        BARS.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Progress of a long run over a known amount of work: a live bar with
+/// throughput and ETA on a terminal, a log line every 30 seconds otherwise,
+/// even while no work gets done.
+pub struct Progress {
+    unit: &'static str,
+    bar: Option<ProgressBar>,
+    done: AtomicU64,
+    total: AtomicU64,
+    started: Instant,
+    finished: AtomicBool,
+}
+
+impl Progress {
+    /// Must be called within a Tokio runtime, which logs the progress when
+    /// there is no bar.
+    pub fn new(name: &'static str, unit: &'static str, total: u64) -> Arc<Self> {
+        let bar = io::stderr().is_terminal().then(|| {
+            let bar = BARS.add(ProgressBar::new(total));
+            bar.set_style(ProgressStyle::with_template(TEMPLATE).unwrap().progress_chars("=> "));
+            bar.set_prefix(name);
+            bar.set_message(unit);
+            bar.enable_steady_tick(Duration::from_millis(200));
+            bar
+        });
+        let progress = Arc::new(Self {
+            unit,
+            bar,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(total),
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+        });
+        if progress.bar.is_none() {
+            let progress = Arc::downgrade(&progress);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(LOG_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    match progress.upgrade() {
+                        Some(progress) if !progress.finished.load(Ordering::Relaxed) => progress.log(),
+                        _ => return,
+                    }
+                }
+            });
+        }
+        progress
+    }
+
+    pub fn inc(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+        if let Some(bar) = &self.bar {
+            bar.inc(n);
+        }
+    }
+
+    /// Replaces the expected amount of work, for runs that only learn it as
+    /// they go.
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        if let Some(bar) = &self.bar {
+            bar.set_length(total);
+        }
+    }
+
+    /// Removes work that turned out not to be needed from the expected amount.
+    pub fn skip(&self, n: u64) {
+        let total = self.total.load(Ordering::Relaxed).saturating_sub(n);
+        self.set_total(total);
+    }
+
+    /// Removes the bar and logs how long the run took.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+            BARS.remove(bar);
+        }
+        let done = self.done.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed();
+        info!(
+            done,
+            unit = self.unit,
+            elapsed = format!("{:.0?}", elapsed),
+            rate = format!("{:.2}/s", rate(done, elapsed)),
+            "Finished",
+        );
+    }
+
+    fn log(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed();
+        let eta = eta(done, total, elapsed).map_or_else(|| "unknown".to_string(), |eta| format!("{:.0?}", eta));
+        info!(done, total, unit = self.unit, rate = format!("{:.2}/s", rate(done, elapsed)), eta, "Progress");
+    }
+}
+
+fn rate(done: u64, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+    if seconds > 0.0 { done as f64 / seconds } else { 0.0 }
+}
+
+/// The time left at the average rate so far, unknown before any progress.
+fn eta(done: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    let rate = rate(done, elapsed);
+    (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(done) as f64 / rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_extrapolates_the_average_rate() {
+        assert_eq!(rate(30, Duration::from_secs(60)), 0.5);
+        assert_eq!(rate(30, Duration::ZERO), 0.0);
+        assert_eq!(eta(30, 120, Duration::from_secs(60)), Some(Duration::from_secs(180)));
+        assert_eq!(eta(130, 120, Duration::from_secs(60)), Some(Duration::ZERO), "work past the total leaves nothing");
+        assert_eq!(eta(0, 120, Duration::from_secs(60)), None);
+    }
+
+    #[tokio::test]
+    async fn skipped_work_leaves_the_total() {
+        let progress = Progress::new("test", "media", 10);
+        progress.inc(2);
+        progress.skip(3);
+        assert_eq!(progress.total.load(Ordering::Relaxed), 7);
+        progress.skip(20);
+        assert_eq!(progress.total.load(Ordering::Relaxed), 0);
+        progress.set_total(12);
+        assert_eq!(progress.total.load(Ordering::Relaxed), 12);
+        assert_eq!(progress.done.load(Ordering::Relaxed), 2);
+        progress.finish();
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::llm::{ChatMessage, LlmClient, LlmError};
+use crate::search::SearchFilter;
+use crate::storage::Storage;
+use crate::types::{MediaFormat, Season};
+
+/// A free-text request split into what it is about and the constraints it
+/// states, e.g. "highly rated 2010s mecha with a sad ending" into the text
+/// "mecha with a sad ending" and `min_year: 2010, max_year: 2019,
+/// min_mean_score: 75`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ParsedQuery {
+    /// What to match semantically, without the extracted constraints.
+    pub text: String,
+    pub filter: SearchFilter,
+    /// False when the LLM could not be used and `text` is the raw query.
+    pub understood: bool,
+}
+
+impl ParsedQuery {
+    pub fn plain(query: &str) -> Self {
+        Self { text: query.to_string(), filter: SearchFilter::default(), understood: false }
+    }
+
+    /// The parsed constraints added to `filter`. Where both set a value,
+    /// `filter` wins; genre and theme lists are combined.
+    pub fn within(&self, filter: &SearchFilter) -> SearchFilter {
+        let parsed = &self.filter;
+        SearchFilter {
+            min_year: filter.min_year.or(parsed.min_year),
+            max_year: filter.max_year.or(parsed.max_year),
+            seasons: or_parsed(&filter.seasons, &parsed.seasons),
+            formats: or_parsed(&filter.formats, &parsed.formats),
+            min_mean_score: filter.min_mean_score.or(parsed.min_mean_score),
+            min_popularity: filter.min_popularity.or(parsed.min_popularity),
+            include_genres: combined(&filter.include_genres, &parsed.include_genres),
+            exclude_genres: combined(&filter.exclude_genres, &parsed.exclude_genres),
+            include_themes: combined(&filter.include_themes, &parsed.include_themes),
+            exclude_themes: combined(&filter.exclude_themes, &parsed.exclude_themes),
+            exclude_seen_by: filter.exclude_seen_by.clone(),
+            user: filter.user.clone(),
+        }
+    }
+}
+
+fn or_parsed<T: Clone>(values: &[T], parsed: &[T]) -> Vec<T> {
+    if values.is_empty() { parsed.to_vec() } else { values.to_vec() }
+}
+
+/// Both lists of names, without the parsed names `names` already has.
+fn combined(names: &[String], parsed: &[String]) -> Vec<String> {
+    let mut combined = names.to_vec();
+    combined.extend(parsed.iter().filter(|name| !names.iter().any(|n| n.eq_ignore_ascii_case(name))).cloned());
+    combined
+}
+
+/// The reply the JSON schema describes.
+#[derive(Deserialize, Debug)]
+struct LlmQuery {
+    text: String,
+    min_year: Option<i32>,
+    max_year: Option<i32>,
+    #[serde(default)]
+    seasons: Vec<Season>,
+    #[serde(default)]
+    formats: Vec<MediaFormat>,
+    min_mean_score: Option<i32>,
+    min_popularity: Option<i32>,
+    #[serde(default)]
+    include_genres: Vec<String>,
+    #[serde(default)]
+    exclude_genres: Vec<String>,
+    #[serde(default)]
+    exclude_themes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum QueryParseError {
+    Llm(LlmError),
+    Database(sqlx::Error),
+    /// The reply does not match the schema.
+    Invalid(String),
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryParseError::Llm(e) => write!(f, "{}", e),
+            QueryParseError::Database(e) => write!(f, "database error: {}", e),
+            QueryParseError::Invalid(message) => write!(f, "the LLM reply does not match the query schema: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for QueryParseError {}
+
+impl From<LlmError> for QueryParseError {
+    fn from(e: LlmError) -> Self {
+        QueryParseError::Llm(e)
+    }
+}
+
+impl From<sqlx::Error> for QueryParseError {
+    fn from(e: sqlx::Error) -> Self {
+        QueryParseError::Database(e)
+    }
+}
+
+/// The JSON schema of the structured query. Genres are limited to the ones in
+/// the database so that the LLM cannot invent filters that match nothing.
+pub fn query_schema(genres: &[String]) -> serde_json::Value {
+    let year = json!({ "type": ["integer", "null"], "minimum": 1900, "maximum": 2100 });
+    let genre_list = json!({ "type": "array", "items": { "enum": genres }, "uniqueItems": true });
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["text"],
+        "properties": {
+            "text": { "type": "string" },
+            "min_year": year,
+            "max_year": year,
+            "seasons": {
+                "type": "array",
+                "items": { "enum": ["WINTER", "SPRING", "SUMMER", "FALL"] },
+                "uniqueItems": true
+            },
+            "formats": {
+                "type": "array",
+                "items": { "enum": ["TV", "TV_SHORT", "MOVIE", "SPECIAL", "OVA", "ONA", "MUSIC"] },
+                "uniqueItems": true
+            },
+            "min_mean_score": { "type": ["integer", "null"], "minimum": 0, "maximum": 100 },
+            "min_popularity": { "type": ["integer", "null"], "minimum": 0 },
+            "include_genres": genre_list,
+            "exclude_genres": genre_list,
+            "exclude_themes": { "type": "array", "items": { "type": "string" } }
+        }
+    })
+}
+
+const INSTRUCTIONS: &str = "You turn requests for anime into search queries. \
+Reply with a JSON object matching the JSON schema below. Put what the anime \
+should be about in \"text\", leaving out the constraints you move into other \
+fields. Only set a field when the request asks for it. A decade such as \
+\"2010s\" is min_year 2010 and max_year 2019. \"Highly rated\" means a \
+min_mean_score of 75, \"popular\" a min_popularity of 50000. Use \
+exclude_genres and exclude_themes for things the user does not want.";
+
+/// Turns free-text requests into a [`ParsedQuery`] with the configured LLM.
+pub struct QueryParser {
+    llm: LlmClient,
+    storage: Storage,
+}
+
+impl QueryParser {
+    pub fn new(llm: LlmClient, storage: Storage) -> Self {
+        Self { llm, storage }
+    }
+
+    /// Parses a query, falling back to a plain semantic query when the LLM is
+    /// unavailable or its reply does not validate.
+    pub async fn parse(&self, query: &str) -> ParsedQuery {
+        match self.try_parse(query).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!(query, error = %e, "Could not parse query, searching it as is");
+                ParsedQuery::plain(query)
+            }
+        }
+    }
+
+    pub async fn try_parse(&self, query: &str) -> Result<ParsedQuery, QueryParseError> {
+        let genres: Vec<String> = self.storage.genre_counts().await?
+            .into_iter()
+            .map(|(genre, _)| genre)
+            .collect();
+        let schema = query_schema(&genres);
+        let messages = [
+            ChatMessage::system(format!("{}\n\n{}", INSTRUCTIONS, schema)),
+            ChatMessage::user(query),
+        ];
+        let reply = self.llm.chat_json(&messages).await?;
+        validate(&schema, &reply)?;
+        let parsed: LlmQuery = serde_json::from_value(reply).map_err(|e| QueryParseError::Invalid(e.to_string()))?;
+        if let (Some(min_year), Some(max_year)) = (parsed.min_year, parsed.max_year) {
+            if min_year > max_year {
+                return Err(QueryParseError::Invalid(format!("min_year {} is after max_year {}", min_year, max_year)));
+            }
+        }
+        let text = parsed.text.trim();
+        Ok(ParsedQuery {
+            // A request made only of constraints still needs something to
+            // rank by.
+            text: if text.is_empty() { query.to_string() } else { text.to_string() },
+            filter: SearchFilter {
+                min_year: parsed.min_year,
+                max_year: parsed.max_year,
+                seasons: parsed.seasons,
+                formats: parsed.formats,
+                min_mean_score: parsed.min_mean_score,
+                min_popularity: parsed.min_popularity,
+                include_genres: parsed.include_genres,
+                exclude_genres: parsed.exclude_genres,
+                include_themes: vec![],
+                exclude_themes: parsed.exclude_themes,
+                exclude_seen_by: None,
+                user: None,
+            },
+            understood: true,
+        })
+    }
+}
+
+fn validate(schema: &serde_json::Value, reply: &serde_json::Value) -> Result<(), QueryParseError> {
+    let validator = jsonschema::validator_for(schema).map_err(|e| QueryParseError::Invalid(e.to_string()))?;
+    let errors: Vec<String> = validator.iter_errors(reply)
+        .map(|e| format!("{} at {}", e, e.instance_path))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(QueryParseError::Invalid(errors.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::Json;
+
+    use super::*;
+    use crate::storage::TempStorage;
+
+    /// A parser whose LLM backend answers every request with `status` and a
+    /// reply of `content`, over an empty database.
+    async fn parser(name: &str, status: StatusCode, content: &str) -> (QueryParser, TempStorage) {
+        let reply = LlmClient::completion(content);
+        let llm = LlmClient::mock(move || async move { (status, Json(reply)) }).await;
+        let db = TempStorage::new(&format!("query_parser_{}", name)).await;
+        (QueryParser::new(llm, db.storage.clone()), db)
+    }
+
+    #[tokio::test]
+    async fn valid_replies_become_filters() {
+        let reply = r#"{"text": "mecha with a sad ending", "min_year": 2010, "max_year": 2019, "min_mean_score": 75, "formats": ["TV"]}"#;
+        let (parser, _db) = parser("valid", StatusCode::OK, reply).await;
+        let parsed = parser.parse("highly rated 2010s mecha TV shows with a sad ending").await;
+        assert!(parsed.understood);
+        assert_eq!(parsed.text, "mecha with a sad ending");
+        assert_eq!(parsed.filter, SearchFilter {
+            min_year: Some(2010),
+            max_year: Some(2019),
+            formats: vec![MediaFormat::Tv],
+            min_mean_score: Some(75),
+            ..SearchFilter::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn replies_violating_the_schema_are_searched_as_is() {
+        let replies = [
+            ("score", r#"{"text": "mecha", "min_mean_score": 150}"#),
+            ("genre", r#"{"text": "mecha", "include_genres": ["Cooking"]}"#),
+            ("field", r#"{"text": "mecha", "studio": "Sunrise"}"#),
+            ("text", r#"{"min_year": 2010}"#),
+            ("years", r#"{"text": "mecha", "min_year": 2019, "max_year": 2010}"#),
+        ];
+        for (name, reply) in replies {
+            let (parser, _db) = parser(&format!("invalid_{}", name), StatusCode::OK, reply).await;
+            let error = parser.try_parse("old mecha").await.unwrap_err();
+            assert!(matches!(error, QueryParseError::Invalid(_)), "{}: {}", name, error);
+            assert_eq!(parser.parse("old mecha").await, ParsedQuery::plain("old mecha"));
+        }
+    }
+
+    #[tokio::test]
+    async fn llm_failures_are_searched_as_is() {
+        let (unavailable, _db) = parser("unavailable", StatusCode::INTERNAL_SERVER_ERROR, "{}").await;
+        let error = unavailable.try_parse("old mecha").await.unwrap_err();
+        assert!(matches!(error, QueryParseError::Llm(LlmError::Status(500))), "{}", error);
+        assert_eq!(unavailable.parse("old mecha").await, ParsedQuery::plain("old mecha"));
+
+        let (rambling, _db) = parser("not_json", StatusCode::OK, "old mecha, I guess").await;
+        let error = rambling.try_parse("old mecha").await.unwrap_err();
+        assert!(matches!(error, QueryParseError::Llm(LlmError::Response(_))), "{}", error);
+    }
+
+    #[test]
+    fn parsed_constraints_are_added_to_the_filter() {
+        let parsed = ParsedQuery {
+            text: "mecha".to_string(),
+            filter: SearchFilter {
+                min_year: Some(2010),
+                max_year: Some(2019),
+                formats: vec![MediaFormat::Tv],
+                exclude_genres: vec!["Ecchi".to_string(), "Horror".to_string()],
+                ..SearchFilter::default()
+            },
+            understood: true,
+        };
+        let filter = SearchFilter {
+            min_year: Some(2015),
+            formats: vec![MediaFormat::Movie],
+            exclude_genres: vec!["ecchi".to_string()],
+            user: Some("kana".to_string()),
+            ..SearchFilter::default()
+        };
+        assert_eq!(parsed.within(&filter), SearchFilter {
+            min_year: Some(2015),
+            max_year: Some(2019),
+            formats: vec![MediaFormat::Movie],
+            exclude_genres: vec!["ecchi".to_string(), "Horror".to_string()],
+            user: Some("kana".to_string()),
+            ..SearchFilter::default()
+        });
+        assert_eq!(ParsedQuery::plain("mecha").within(&filter), filter);
+    }
+}
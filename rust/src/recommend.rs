@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::search::{hits, SearchError, SearchFilter, SearchHit, Searcher};
+use crate::storage::Storage;
+use crate::user_list::taste_seeds;
+use crate::vector_index::{dot, normalize};
+
+/// How [`Searcher::recommend_similar`] picks and scores neighbors, set in the
+/// `[similar]` table of the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimilarOptions {
+    /// Leave out sequels, prequels, side stories and the rest of the seed's
+    /// franchise.
+    pub exclude_franchise: bool,
+    /// Share of the score given to the overlap of generated themes, from 0
+    /// (embedding similarity only) to 1 (themes only).
+    pub theme_weight: f32,
+    /// How many nearest neighbors are rescored with their themes.
+    pub candidates: usize,
+}
+
+impl Default for SimilarOptions {
+    fn default() -> Self {
+        Self {
+            exclude_franchise: true,
+            theme_weight: 0.2,
+            candidates: 100,
+        }
+    }
+}
+
+impl Searcher {
+    /// Returns `k` media like `media_id`: the nearest neighbors of its
+    /// summary embedding, rescored with the Jaccard overlap of their generated
+    /// themes. The media itself, and its franchise unless disabled, are never
+    /// returned. Hits are scored by the blended score.
+    pub async fn recommend_similar(&self, media_id: i32, k: usize, filter: &SearchFilter, options: &SimilarOptions) -> Result<Vec<SearchHit>, SearchError> {
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        let seed = self.index()
+            .vector(media_id)
+            .await?
+            .ok_or(SearchError::MissingEmbedding(media_id))?;
+        let mut excluded: HashSet<i32> = HashSet::from([media_id]);
+        if options.exclude_franchise {
+            excluded.extend(self.storage().franchise(media_id).await?);
+        }
+        let allowed = self.allowed_ids(filter).await?;
+
+        let candidates = options.candidates.max(k) + excluded.len();
+        let mut neighbors: Vec<(i32, f32)> = self.index()
+            .rank(&seed, candidates, Some(&*allowed))
+            .await?
+            .into_iter()
+            .filter(|(id, _)| !excluded.contains(id))
+            .collect();
+        neighbors.truncate(options.candidates.max(k));
+
+        if options.theme_weight > 0.0 {
+            let mut ids: Vec<i32> = neighbors.iter().map(|(id, _)| *id).collect();
+            ids.push(media_id);
+            let themes = themes(self.storage(), &ids).await?;
+            let seed_themes = themes.get(&media_id).cloned().unwrap_or_default();
+            for (id, score) in neighbors.iter_mut() {
+                let overlap = themes.get(id).map_or(0.0, |themes| jaccard(&seed_themes, themes));
+                *score = (1.0 - options.theme_weight) * *score + options.theme_weight * overlap;
+            }
+            neighbors.sort_by(|a, b| b.1.total_cmp(&a.1));
+        }
+        hits(self.storage(), self.policy(filter.user.as_deref()), neighbors, k).await
+    }
+}
+
+/// How liked and disliked media are turned into a relevance score.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TasteMethod {
+    /// Similarity to the mean of the liked media, minus the similarity to the
+    /// mean of the disliked ones. Finds media between the liked ones.
+    Centroid,
+    /// Similarity to the closest liked media, minus the similarity to the
+    /// closest disliked one. Keeps each liked media's neighbors when the liked
+    /// media have little in common.
+    PerSeed,
+}
+
+/// How [`Searcher::recommend_for_taste`] scores and diversifies, set in the
+/// `[taste]` table of the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TasteOptions {
+    pub method: TasteMethod,
+    /// How much resembling a disliked media costs, relative to resembling a
+    /// liked one.
+    pub dislike_weight: f32,
+    /// The maximal marginal relevance trade-off, from 0 (only diversity) to 1
+    /// (only relevance).
+    pub diversity_lambda: f32,
+    /// How many nearest neighbors are considered, per liked media with
+    /// `per_seed`.
+    pub candidates: usize,
+}
+
+impl Default for TasteOptions {
+    fn default() -> Self {
+        Self {
+            method: TasteMethod::Centroid,
+            dislike_weight: 0.5,
+            diversity_lambda: 0.7,
+            candidates: 200,
+        }
+    }
+}
+
+impl Searcher {
+    /// Returns `k` media for someone who liked `liked` and disliked
+    /// `disliked`, none of which are returned. Candidates are ordered by
+    /// maximal marginal relevance, so each hit is picked for its relevance
+    /// minus its similarity to the hits before it; this keeps the list from
+    /// filling up with one franchise. Hits are scored by their relevance.
+    pub async fn recommend_for_taste(&self, liked: &[i32], disliked: &[i32], k: usize, filter: &SearchFilter, options: &TasteOptions) -> Result<Vec<SearchHit>, SearchError> {
+        if liked.is_empty() {
+            return Err(SearchError::NoLikedMedia);
+        }
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        let mut liked_vectors = vec![];
+        for id in liked {
+            liked_vectors.push(self.index().vector(*id).await?.ok_or(SearchError::MissingEmbedding(*id))?);
+        }
+        let mut disliked_vectors = vec![];
+        for id in disliked {
+            disliked_vectors.push(self.index().vector(*id).await?.ok_or(SearchError::MissingEmbedding(*id))?);
+        }
+        let seeds: HashSet<i32> = liked.iter().chain(disliked).copied().collect();
+        let allowed = self.allowed_ids(filter).await?;
+
+        let queries = match options.method {
+            TasteMethod::Centroid => {
+                let mut taste = mean(&liked_vectors);
+                if !disliked_vectors.is_empty() {
+                    for (value, disliked) in taste.iter_mut().zip(mean(&disliked_vectors)) {
+                        *value -= options.dislike_weight * disliked;
+                    }
+                }
+                normalize(&mut taste);
+                vec![taste]
+            }
+            TasteMethod::PerSeed => liked_vectors.clone(),
+        };
+        // Enough neighbors that `candidates` remain once the seeds are left out.
+        let neighbors = options.candidates + seeds.len();
+        let mut candidate_ids: Vec<i32> = vec![];
+        let mut seen = seeds.clone();
+        for query in &queries {
+            let mut ranked = self.index().rank(query, neighbors, Some(&*allowed)).await?;
+            ranked.truncate(neighbors);
+            candidate_ids.extend(ranked.into_iter().map(|(id, _)| id).filter(|id| seen.insert(*id)));
+        }
+
+        let mut candidates: Vec<(i32, Vec<f32>, f32)> = vec![];
+        for id in candidate_ids {
+            let Some(vector) = self.index().vector(id).await? else {
+                continue;
+            };
+            let relevance = match options.method {
+                TasteMethod::Centroid => dot(&queries[0], &vector),
+                TasteMethod::PerSeed => {
+                    let closest = |seeds: &[Vec<f32>]| seeds.iter().map(|seed| dot(seed, &vector)).fold(f32::NEG_INFINITY, f32::max);
+                    let disliked = if disliked_vectors.is_empty() { 0.0 } else { closest(&disliked_vectors) };
+                    closest(&liked_vectors) - options.dislike_weight * disliked
+                }
+            };
+            candidates.push((id, vector, relevance));
+        }
+        hits(self.storage(), self.policy(filter.user.as_deref()), mmr(candidates, options.diversity_lambda), k).await
+    }
+}
+
+impl Searcher {
+    /// Recommends from a user's imported list: their high scores are liked,
+    /// their low scores and dropped media disliked (see
+    /// [`taste_seeds`]), and media they have already seen are left out.
+    /// Seeds without a summary embedding are ignored.
+    pub async fn recommend_for_user(&self, user_name: &str, k: usize, filter: &SearchFilter, options: &TasteOptions) -> Result<Vec<SearchHit>, SearchError> {
+        let entries = self.storage().user_list(user_name).await?;
+        let (mut liked, mut disliked) = taste_seeds(&entries);
+        liked = self.embedded(liked).await?;
+        disliked = self.embedded(disliked).await?;
+        let filter = SearchFilter {
+            exclude_seen_by: Some(user_name.to_string()),
+            user: Some(user_name.to_string()),
+            ..filter.clone()
+        };
+        self.recommend_for_taste(&liked, &disliked, k, &filter, options).await
+    }
+
+    /// The ids that have an indexed vector.
+    async fn embedded(&self, ids: Vec<i32>) -> Result<Vec<i32>, SearchError> {
+        let mut embedded = vec![];
+        for id in ids {
+            if self.index().vector(id).await?.is_some() {
+                embedded.push(id);
+            }
+        }
+        Ok(embedded)
+    }
+}
+
+/// Orders every candidate by maximal marginal relevance, returning `(id,
+/// relevance)` pairs.
+fn mmr(mut candidates: Vec<(i32, Vec<f32>, f32)>, lambda: f32) -> Vec<(i32, f32)> {
+    let mut ordered = Vec::with_capacity(candidates.len());
+    // The highest similarity of each remaining candidate to the ordered ones.
+    let mut redundancy = vec![0.0; candidates.len()];
+    while !candidates.is_empty() {
+        let marginal = |i: usize| lambda * candidates[i].2 - (1.0 - lambda) * redundancy[i];
+        let best = (0..candidates.len())
+            .max_by(|a, b| marginal(*a).total_cmp(&marginal(*b)))
+            .unwrap_or_default();
+        let (id, vector, relevance) = candidates.swap_remove(best);
+        redundancy.swap_remove(best);
+        for ((_, other, _), redundancy) in candidates.iter().zip(redundancy.iter_mut()) {
+            let similarity = dot(&vector, other);
+            *redundancy = if ordered.is_empty() { similarity } else { redundancy.max(similarity) };
+        }
+        ordered.push((id, relevance));
+    }
+    ordered
+}
+
+fn mean(vectors: &[Vec<f32>]) -> Vec<f32> {
+    let mut mean = vec![0.0; vectors.first().map_or(0, |v| v.len())];
+    for vector in vectors {
+        for (total, value) in mean.iter_mut().zip(vector) {
+            *total += value / vectors.len() as f32;
+        }
+    }
+    mean
+}
+
+/// The generated themes of each media, lowercased.
+async fn themes(storage: &Storage, ids: &[i32]) -> Result<HashMap<i32, HashSet<String>>, SearchError> {
+    Ok(storage.summaries(ids).await?
+        .into_iter()
+        .map(|summary| {
+            let themes = summary.generated_summary.generated_themes
+                .iter()
+                .map(|theme| theme.trim().to_lowercase())
+                .filter(|theme| !theme.is_empty())
+                .collect();
+            (summary.id, themes)
+        })
+        .collect())
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmr_interleaves_franchises_that_relevance_groups() {
+        // Media 1 to 3 are one franchise and 11 and 12 another: the same
+        // vector within a franchise, orthogonal between them.
+        let candidates = || vec![
+            (1, vec![1.0, 0.0], 0.9),
+            (2, vec![1.0, 0.0], 0.89),
+            (3, vec![1.0, 0.0], 0.88),
+            (11, vec![0.0, 1.0], 0.8),
+            (12, vec![0.0, 1.0], 0.79),
+        ];
+        let ids = |ordered: Vec<(i32, f32)>| ordered.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(mmr(candidates(), 1.0)), vec![1, 2, 3, 11, 12]);
+        assert_eq!(ids(mmr(candidates(), 0.5)), vec![1, 11, 2, 3, 12]);
+        // Hits keep their relevance as score.
+        assert_eq!(mmr(candidates(), 0.5)[1], (11, 0.8));
+    }
+}
@@ -0,0 +1,475 @@
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::answer::{Answerer, Conversation};
+use crate::recommend::{SimilarOptions, TasteOptions};
+use crate::rerank::RerankedHit;
+use crate::search::{HybridWeights, SearchError, SearchFilter, SearchMode, Searcher};
+use crate::types::{AnimeMetadata, MediaFormat, Season};
+
+// Lines typed at the prompt, kept between sessions.
+const HISTORY_FILE: &str = ".lam_search_history";
+const DEFAULT_K: usize = 10;
+const WIDTH: usize = 80;
+const HELP: &str = "\
+<query>                  search
+#3, show 3               show everything about hit 3
+more like #3             media like hit 3, without its franchise
+taste 21 30 -45          media for someone who liked 21 and 30 but not 45
+                         (AniList ids, shown by `show`)
+recommend kana           media for kana from their imported list; without a
+                         name, for the user set with `user`
+ask <question>           answers from the hits and the media found for it;
+                         further questions follow up on the answer
+mode semantic|keyword|hybrid
+k 20                     number of hits
+filter                   show the filters
+filter year 2010-2019    or 2010- or -2019; `filter year` clears it
+filter season fall       toggles a season, likewise `filter format tv`
+filter genre Fantasy     toggles a required genre, `genre -Ecchi` an excluded one
+filter theme Revenge     same for generated themes
+filter score 75          lowest mean score; `filter score` clears it
+filter clear             removes every filter
+user kana                searches under kana's content policy; `user` resets it
+history                  the searches of this session
+!2                       runs search 2 of the history again
+help, quit";
+
+/// A search run during the session, repeated when the filters change.
+#[derive(Debug, Clone, PartialEq)]
+enum Request {
+    Query(String),
+    /// More like a media, remembered with its title for the history.
+    Similar(i32, String),
+    /// For someone who liked the first media and disliked the second.
+    Taste(Vec<i32>, Vec<i32>),
+    /// For a user, from their imported list.
+    User(String),
+}
+
+/// An interactive search prompt over a [`Searcher`]. Each search replaces the
+/// numbered hits that `show` and `more like` refer to.
+pub struct Repl {
+    searcher: Searcher,
+    weights: HybridWeights,
+    similar: SimilarOptions,
+    taste: TasteOptions,
+    mode: SearchMode,
+    k: usize,
+    filter: SearchFilter,
+    hits: Vec<RerankedHit>,
+    history: Vec<Request>,
+    answerer: Option<Answerer>,
+    // About the hits of the last search, then the media of the last answer.
+    conversation: Conversation,
+}
+
+impl Repl {
+    pub fn new(searcher: Searcher, weights: HybridWeights, similar: SimilarOptions, taste: TasteOptions) -> Self {
+        Self {
+            searcher,
+            weights,
+            similar,
+            taste,
+            mode: SearchMode::Hybrid,
+            k: DEFAULT_K,
+            filter: SearchFilter::default(),
+            hits: vec![],
+            history: vec![],
+            answerer: None,
+            conversation: Conversation::default(),
+        }
+    }
+
+    /// Enables `ask`.
+    pub fn with_answerer(mut self, answerer: Answerer) -> Self {
+        self.answerer = Some(answerer);
+        self
+    }
+
+    /// Reads commands until `quit` or end of input.
+    pub async fn run(&mut self) -> rustyline::Result<()> {
+        let mut editor = DefaultEditor::new()?;
+        let history_file = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(file) = &history_file {
+            // There is no history file before the first session.
+            let _ = editor.load_history(file);
+        }
+        println!("Type a query, or `help`.");
+        loop {
+            let line = match editor.readline("lam> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line)?;
+            if line == "quit" || line == "exit" {
+                break;
+            }
+            if let Err(e) = self.execute(line).await {
+                println!("{}", e);
+            }
+        }
+        if let Some(file) = &history_file {
+            if let Err(e) = editor.save_history(file) {
+                println!("Could not save the history to {}: {}", file.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one line. Errors are messages for the user.
+    pub async fn execute(&mut self, line: &str) -> Result<(), String> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "help" | "?" => println!("{}", HELP),
+            "show" => self.show(rest)?,
+            _ if command.starts_with('#') && rest.is_empty() => self.show(command)?,
+            "more" if rest.starts_with("like") => {
+                let position = hit_number(rest.trim_start_matches("like").trim(), self.hits.len())?;
+                let metadata = &self.hits[position].hit.metadata;
+                let request = Request::Similar(metadata.id, title(metadata).to_string());
+                self.request(request).await?;
+            }
+            "taste" => {
+                let (liked, disliked) = taste(rest)?;
+                self.request(Request::Taste(liked, disliked)).await?;
+            }
+            "recommend" => {
+                let user = Some(rest.to_string())
+                    .filter(|user| !user.is_empty())
+                    .or_else(|| self.filter.user.clone())
+                    .ok_or("name a user, e.g. `recommend kana`")?;
+                self.request(Request::User(user)).await?;
+            }
+            "ask" if !rest.is_empty() => self.ask(rest).await?,
+            "mode" => {
+                self.mode = rest.parse().map_err(|e| format!("unknown mode {:?}, {}", rest, e))?;
+                println!("Searching in {} mode", rest);
+            }
+            "k" => {
+                self.k = rest.parse().ok().filter(|k| *k > 0).ok_or("k must be a positive integer")?;
+                self.repeat().await?;
+            }
+            "user" => {
+                self.filter.user = Some(rest.to_string()).filter(|user| !user.is_empty());
+                match &self.filter.user {
+                    Some(user) => println!("Searching as {}", user),
+                    None => println!("Searching under the default content policy"),
+                }
+                self.repeat().await?;
+            }
+            "filter" => {
+                if !rest.is_empty() {
+                    self.set_filter(rest)?;
+                }
+                println!("{}", describe(&self.filter));
+                if !rest.is_empty() {
+                    self.repeat().await?;
+                }
+            }
+            "history" => {
+                for (i, request) in self.history.iter().enumerate() {
+                    match request {
+                        Request::Query(query) => println!("!{} {}", i + 1, query),
+                        Request::Similar(_, title) => println!("!{} more like {}", i + 1, title),
+                        Request::Taste(liked, disliked) => {
+                            let ids: Vec<String> = liked.iter()
+                                .map(|id| id.to_string())
+                                .chain(disliked.iter().map(|id| format!("-{}", id)))
+                                .collect();
+                            println!("!{} taste {}", i + 1, ids.join(" "));
+                        }
+                        Request::User(user) => println!("!{} recommend {}", i + 1, user),
+                    }
+                }
+            }
+            _ if command.starts_with('!') && rest.is_empty() => {
+                let request = command[1..].parse::<usize>()
+                    .ok()
+                    .and_then(|i| self.history.get(i.wrapping_sub(1)))
+                    .cloned()
+                    .ok_or_else(|| format!("no search {} in the history", &command[1..]))?;
+                self.request(request).await?;
+            }
+            _ => self.request(Request::Query(line.to_string())).await?,
+        }
+        Ok(())
+    }
+
+    async fn request(&mut self, request: Request) -> Result<(), String> {
+        self.hits = self.run_request(&request).await.map_err(|e| e.to_string())?;
+        self.conversation = Conversation::about(self.hits.iter().map(|hit| hit.hit.metadata.id).collect());
+        print_hits(&self.hits);
+        if self.history.last() != Some(&request) {
+            self.history.push(request);
+        }
+        Ok(())
+    }
+
+    async fn ask(&mut self, question: &str) -> Result<(), String> {
+        let answerer = self.answerer.as_ref().ok_or("asking is not available here")?;
+        let answer = answerer.ask(&self.searcher, &mut self.conversation, question, &self.filter)
+            .await
+            .map_err(|e| e.to_string())?;
+        print_wrapped(&answer.text, "");
+        Ok(())
+    }
+
+    /// Runs the last search again, e.g. with new filters.
+    async fn repeat(&mut self) -> Result<(), String> {
+        match self.history.last().cloned() {
+            Some(request) => self.request(request).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn run_request(&self, request: &Request) -> Result<Vec<RerankedHit>, SearchError> {
+        let searcher = &self.searcher;
+        match request {
+            Request::Query(query) => searcher.search_reranked(self.mode, query, self.k, &self.filter, &self.weights).await,
+            Request::Similar(id, _) => Ok(searcher.recommend_similar(*id, self.k, &self.filter, &self.similar).await?
+                .into_iter()
+                .map(RerankedHit::from)
+                .collect()),
+            Request::Taste(liked, disliked) => Ok(searcher.recommend_for_taste(liked, disliked, self.k, &self.filter, &self.taste).await?
+                .into_iter()
+                .map(RerankedHit::from)
+                .collect()),
+            Request::User(user) => Ok(searcher.recommend_for_user(user, self.k, &self.filter, &self.taste).await?
+                .into_iter()
+                .map(RerankedHit::from)
+                .collect()),
+        }
+    }
+
+    fn show(&self, number: &str) -> Result<(), String> {
+        let hit = &self.hits[hit_number(number, self.hits.len())?].hit;
+        let metadata = &hit.metadata;
+        println!("{} [{}]", title(metadata), metadata.id);
+        if let (Some(english), Some(romaji)) = (&metadata.title.english, &metadata.title.romaji) {
+            if english != romaji {
+                println!("Romaji: {}", romaji);
+            }
+        }
+        let season = metadata.season.as_deref().map(|season| format!("{} ", season)).unwrap_or_default();
+        let format = metadata.format.as_deref().map(|format| format!(", {}", format)).unwrap_or_default();
+        println!("Aired: {}{}{}", season, metadata.season_year, format);
+        if let Some(mean_score) = metadata.mean_score {
+            println!("Mean score: {}/100", mean_score);
+        }
+        if let Some(popularity) = metadata.popularity {
+            println!("Popularity: {}", popularity);
+        }
+        if let Some(genres) = metadata.genres.as_ref().filter(|genres| !genres.is_empty()) {
+            println!("Genres: {}", genres.join(", "));
+        }
+        if let Some(tags) = metadata.tags.as_ref().filter(|tags| !tags.is_empty()) {
+            let names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+            println!("Tags: {}", names.join(", "));
+        }
+        if let Some(summary) = &hit.summary {
+            if !summary.generated_themes.is_empty() {
+                println!("Themes: {}", summary.generated_themes.join(", "));
+            }
+            println!("\nSummary:");
+            print_wrapped(&summary.summary, "  ");
+        }
+        if let Some(description) = &metadata.description {
+            println!("\nDescription:");
+            print_wrapped(description, "  ");
+        }
+        Ok(())
+    }
+
+    fn set_filter(&mut self, args: &str) -> Result<(), String> {
+        let (field, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let value = value.trim();
+        let filter = &mut self.filter;
+        match field {
+            "clear" => {
+                *filter = SearchFilter {
+                    user: filter.user.take(),
+                    ..SearchFilter::default()
+                }
+            }
+            "year" => {
+                let (min, max) = match value.split_once('-') {
+                    Some((min, max)) => (year(min)?, year(max)?),
+                    None => (year(value)?, year(value)?),
+                };
+                filter.min_year = min;
+                filter.max_year = max;
+            }
+            "season" => toggle(&mut filter.seasons, value.parse::<Season>()?),
+            "format" => toggle(&mut filter.formats, value.parse::<MediaFormat>()?),
+            "genre" => match value.strip_prefix('-') {
+                Some(genre) => toggle_name(&mut filter.exclude_genres, genre)?,
+                None => toggle_name(&mut filter.include_genres, value.trim_start_matches('+'))?,
+            },
+            "theme" => match value.strip_prefix('-') {
+                Some(theme) => toggle_name(&mut filter.exclude_themes, theme)?,
+                None => toggle_name(&mut filter.include_themes, value.trim_start_matches('+'))?,
+            },
+            "score" => {
+                filter.min_mean_score = match value {
+                    "" => None,
+                    score => Some(score.parse().map_err(|_| format!("invalid score {:?}", score))?),
+                }
+            }
+            _ => return Err(format!("unknown filter {:?}, see `help`", field)),
+        }
+        Ok(())
+    }
+}
+
+/// Prints numbered hits with their title, year, score and generated summary,
+/// and why they were reranked there.
+pub fn print_hits(hits: &[RerankedHit]) {
+    if hits.is_empty() {
+        println!("No match.");
+    }
+    for (i, RerankedHit { hit, justification }) in hits.iter().enumerate() {
+        let metadata = &hit.metadata;
+        println!("\n#{} {} ({}) score {:.3}", i + 1, title(metadata), metadata.season_year, hit.score);
+        if let Some(summary) = &hit.summary {
+            print_wrapped(&summary.summary, "   ");
+        }
+        if let Some(justification) = justification {
+            print_wrapped(&format!("Reranked: {}", justification), "   ");
+        }
+    }
+}
+
+/// Reads `3` or `#3` as an index into `count` hits.
+fn hit_number(number: &str, count: usize) -> Result<usize, String> {
+    if count == 0 {
+        return Err("no hits, search first".to_string());
+    }
+    number.trim_start_matches('#')
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=count).contains(n))
+        .map(|n| n - 1)
+        .ok_or_else(|| format!("no hit {:?}, pick one between 1 and {}", number, count))
+}
+
+/// Reads `21 30 -45` as liked and disliked media ids.
+fn taste(ids: &str) -> Result<(Vec<i32>, Vec<i32>), String> {
+    let mut liked = vec![];
+    let mut disliked = vec![];
+    for id in ids.split(|c: char| c.is_whitespace() || c == ',').filter(|id| !id.is_empty()) {
+        let (list, number) = match id.strip_prefix('-') {
+            Some(number) => (&mut disliked, number),
+            None => (&mut liked, id.trim_start_matches('+')),
+        };
+        list.push(number.parse().map_err(|_| format!("invalid media id {:?}", id))?);
+    }
+    if liked.is_empty() {
+        return Err("name at least one liked media id, e.g. `taste 21 30 -45`".to_string());
+    }
+    Ok((liked, disliked))
+}
+
+fn year(value: &str) -> Result<Option<i32>, String> {
+    match value.trim() {
+        "" => Ok(None),
+        year => year.parse().map(Some).map_err(|_| format!("invalid year {:?}", year)),
+    }
+}
+
+fn toggle<T: PartialEq>(values: &mut Vec<T>, value: T) {
+    match values.iter().position(|v| *v == value) {
+        Some(position) => {
+            values.remove(position);
+        }
+        None => values.push(value),
+    }
+}
+
+/// Toggles a name, ignoring case like the filters do.
+fn toggle_name(names: &mut Vec<String>, name: &str) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("a name is needed".to_string());
+    }
+    match names.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+        Some(position) => {
+            names.remove(position);
+        }
+        None => names.push(name.to_string()),
+    }
+    Ok(())
+}
+
+fn describe(filter: &SearchFilter) -> String {
+    if !filter.restricts_media() {
+        return "No filters".to_string();
+    }
+    let mut parts = vec![];
+    match (filter.min_year, filter.max_year) {
+        (None, None) => {}
+        (min, max) if min == max => parts.push(format!("year {}", min.unwrap_or_default())),
+        (min, max) => parts.push(format!(
+            "years {}-{}",
+            min.map(|y| y.to_string()).unwrap_or_default(),
+            max.map(|y| y.to_string()).unwrap_or_default(),
+        )),
+    }
+    if !filter.seasons.is_empty() {
+        let seasons: Vec<&str> = filter.seasons.iter().map(|season| season.as_str()).collect();
+        parts.push(format!("seasons {}", seasons.join(", ")));
+    }
+    if !filter.formats.is_empty() {
+        let formats: Vec<&str> = filter.formats.iter().map(|format| format.as_str()).collect();
+        parts.push(format!("formats {}", formats.join(", ")));
+    }
+    if let Some(score) = filter.min_mean_score {
+        parts.push(format!("mean score {}+", score));
+    }
+    for (names, label) in [
+        (&filter.include_genres, "genres"),
+        (&filter.exclude_genres, "not genres"),
+        (&filter.include_themes, "themes"),
+        (&filter.exclude_themes, "not themes"),
+    ] {
+        if !names.is_empty() {
+            parts.push(format!("{} {}", label, names.join(", ")));
+        }
+    }
+    format!("Filters: {}", parts.join("; "))
+}
+
+fn title(metadata: &AnimeMetadata) -> &str {
+    metadata.title.english.as_deref()
+        .or(metadata.title.romaji.as_deref())
+        .unwrap_or("(untitled)")
+}
+
+/// Prints `text` word-wrapped to the terminal width, AniList's `<br>` tags
+/// turned into line breaks.
+fn print_wrapped(text: &str, indent: &str) {
+    let text = text.replace("<br>", "\n").replace("<br/>", "\n").replace("<br />", "\n");
+    for paragraph in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut line = String::from(indent);
+        for word in paragraph.split_whitespace() {
+            if line.len() > indent.len() && line.len() + 1 + word.len() > WIDTH {
+                println!("{}", line);
+                line = String::from(indent);
+            }
+            if line.len() > indent.len() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::llm::{ChatMessage, LlmClient, LlmError};
+use crate::search::SearchHit;
+use crate::storage::Storage;
+
+/// A search hit after reranking.
+#[derive(Serialize, Debug)]
+pub struct RerankedHit {
+    #[serde(flatten)]
+    pub hit: SearchHit,
+    /// Why the LLM placed the hit where it is. `None` for hits it was not
+    /// shown or left out of its ranking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub justification: Option<String>,
+}
+
+impl From<SearchHit> for RerankedHit {
+    fn from(hit: SearchHit) -> Self {
+        Self { hit, justification: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Judgement {
+    id: i32,
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LlmRanking {
+    ranking: Vec<Judgement>,
+}
+
+#[derive(Debug)]
+pub enum RerankError {
+    Llm(LlmError),
+    Database(sqlx::Error),
+    /// The reply is not a ranking of the candidates.
+    Invalid(String),
+}
+
+impl fmt::Display for RerankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RerankError::Llm(e) => write!(f, "{}", e),
+            RerankError::Database(e) => write!(f, "database error: {}", e),
+            RerankError::Invalid(message) => write!(f, "the LLM reply is not a ranking of the candidates: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RerankError {}
+
+impl From<LlmError> for RerankError {
+    fn from(e: LlmError) -> Self {
+        RerankError::Llm(e)
+    }
+}
+
+impl From<sqlx::Error> for RerankError {
+    fn from(e: sqlx::Error) -> Self {
+        RerankError::Database(e)
+    }
+}
+
+const INSTRUCTIONS: &str = "You rank anime by how well they match a search \
+request. You are given the request and numbered candidates with their title \
+and summary. Judge what the user is actually looking for, not only the topic. \
+Reply with a JSON object of the form {\"ranking\": [{\"id\": 123, \"reason\": \
+\"...\"}]} listing every candidate id once, the best match first. Each reason \
+is one short sentence on why the anime does or does not fit the request.";
+
+// Longest description sent for a candidate without a generated summary.
+const MAX_DESCRIPTION_CHARS: usize = 600;
+
+/// Reorders the top hits of a search by asking the LLM which best match the
+/// query. Rankings are cached in the `rerank_cache` table per query, model
+/// and candidate list, so repeating a search does not call the LLM again.
+pub struct Reranker {
+    llm: LlmClient,
+    storage: Storage,
+    candidates: usize,
+}
+
+impl Reranker {
+    /// `candidates` is how many of the top hits are sent to the LLM, see
+    /// [`crate::config::rerank_candidates`].
+    pub fn new(llm: LlmClient, storage: Storage, candidates: usize) -> Self {
+        Self { llm, storage, candidates }
+    }
+
+    pub fn candidates(&self) -> usize {
+        self.candidates
+    }
+
+    /// Reranks hits, keeping their order when the LLM is unavailable or its
+    /// reply is not usable.
+    pub async fn rerank(&self, query: &str, hits: Vec<SearchHit>) -> Vec<RerankedHit> {
+        match self.ranking(query, &hits).await {
+            Ok(ranking) => apply(hits, ranking),
+            Err(e) => {
+                warn!(query, error = %e, "Could not rerank hits, keeping their order");
+                apply(hits, vec![])
+            }
+        }
+    }
+
+    pub async fn try_rerank(&self, query: &str, hits: Vec<SearchHit>) -> Result<Vec<RerankedHit>, RerankError> {
+        let ranking = self.ranking(query, &hits).await?;
+        Ok(apply(hits, ranking))
+    }
+
+    async fn ranking(&self, query: &str, hits: &[SearchHit]) -> Result<Vec<Judgement>, RerankError> {
+        let candidates = &hits[..hits.len().min(self.candidates)];
+        if candidates.len() < 2 {
+            return Ok(vec![]);
+        }
+        let key = cache_key(query);
+        let ids: Vec<String> = candidates.iter().map(|hit| hit.metadata.id.to_string()).collect();
+        let ids = ids.join(",");
+        if let Some(cached) = self.storage.cached_rerank(&key, self.llm.model(), &ids).await? {
+            return serde_json::from_str(&cached).map_err(|e| RerankError::Invalid(e.to_string()));
+        }
+
+        let messages = [
+            ChatMessage::system(INSTRUCTIONS),
+            ChatMessage::user(prompt(query, candidates)),
+        ];
+        let reply = self.llm.chat_json(&messages).await?;
+        let reply: LlmRanking = serde_json::from_value(reply).map_err(|e| RerankError::Invalid(e.to_string()))?;
+        let ranking = validate(candidates, reply.ranking)?;
+        let serialized = serde_json::to_string(&ranking).map_err(|e| RerankError::Invalid(e.to_string()))?;
+        self.storage.store_rerank(&key, self.llm.model(), &ids, &serialized).await?;
+        Ok(ranking)
+    }
+}
+
+/// Queries differing only in case or spacing share a cache entry.
+fn cache_key(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn prompt(query: &str, candidates: &[SearchHit]) -> String {
+    let mut prompt = format!("Request: {}\n\nCandidates:\n", query);
+    for hit in candidates {
+        let metadata = &hit.metadata;
+        let title = metadata.title.english.as_deref()
+            .or(metadata.title.romaji.as_deref())
+            .unwrap_or_default();
+        let text = match &hit.summary {
+            Some(summary) => summary.summary.clone(),
+            None => metadata.description.as_deref()
+                .unwrap_or_default()
+                .chars()
+                .take(MAX_DESCRIPTION_CHARS)
+                .collect(),
+        };
+        prompt.push_str(&format!("\nid {}: {} ({})\n{}\n", metadata.id, title, metadata.season_year, text));
+    }
+    prompt
+}
+
+/// Keeps the first judgement of each candidate, dropping ids that were not
+/// sent. A reply naming none of the candidates is an error.
+fn validate(candidates: &[SearchHit], ranking: Vec<Judgement>) -> Result<Vec<Judgement>, RerankError> {
+    let sent: HashSet<i32> = candidates.iter().map(|hit| hit.metadata.id).collect();
+    let mut seen = HashSet::new();
+    let ranking: Vec<Judgement> = ranking
+        .into_iter()
+        .filter(|judgement| sent.contains(&judgement.id) && seen.insert(judgement.id))
+        .collect();
+    if ranking.is_empty() {
+        return Err(RerankError::Invalid("no candidate id in the ranking".to_string()));
+    }
+    Ok(ranking)
+}
+
+/// Puts the ranked hits first, followed by the others in their original
+/// order.
+fn apply(hits: Vec<SearchHit>, ranking: Vec<Judgement>) -> Vec<RerankedHit> {
+    let mut remaining: Vec<Option<SearchHit>> = hits.into_iter().map(Some).collect();
+    let mut reranked = Vec::with_capacity(remaining.len());
+    for judgement in ranking {
+        let position = remaining.iter().position(|hit| {
+            hit.as_ref().is_some_and(|hit| hit.metadata.id == judgement.id)
+        });
+        if let Some(hit) = position.and_then(|position| remaining[position].take()) {
+            reranked.push(RerankedHit { hit, justification: Some(judgement.reason) });
+        }
+    }
+    reranked.extend(remaining.into_iter().flatten().map(RerankedHit::from));
+    reranked
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::Json;
+    use serde_json::json;
+
+    use super::*;
+    use crate::storage::TempStorage;
+    use crate::types::AnimeMetadataRow;
+
+    fn hit(id: i32) -> SearchHit {
+        let metadata = AnimeMetadataRow {
+            id,
+            mal_id: None,
+            english_title: Some(format!("Anime {}", id)),
+            romaji_title: None,
+            season: None,
+            season_year: 2020,
+            format: None,
+            description: Some("A description.".to_string()),
+            popularity: None,
+            mean_score: None,
+            is_adult: None,
+        };
+        SearchHit { metadata: metadata.into_metadata(vec![], vec![]), summary: None, score: 1.0 / id as f32 }
+    }
+
+    fn judgement(id: i32) -> Judgement {
+        Judgement { id, reason: format!("reason {}", id) }
+    }
+
+    fn ids(reranked: &[RerankedHit]) -> Vec<i32> {
+        reranked.iter().map(|reranked| reranked.hit.metadata.id).collect()
+    }
+
+    #[test]
+    fn rankings_keep_the_first_judgement_of_each_sent_candidate() {
+        let candidates = [hit(1), hit(2), hit(3)];
+        let ranking = vec![judgement(3), judgement(9), judgement(3), judgement(1)];
+        let ranking = validate(&candidates, ranking).unwrap();
+        assert_eq!(ranking, vec![judgement(3), judgement(1)]);
+
+        let reranked = apply(vec![hit(1), hit(2), hit(3), hit(4)], ranking);
+        assert_eq!(ids(&reranked), vec![3, 1, 2, 4], "missing candidates keep their order after the ranked ones");
+        let justifications: Vec<Option<&str>> = reranked.iter().map(|r| r.justification.as_deref()).collect();
+        assert_eq!(justifications, vec![Some("reason 3"), Some("reason 1"), None, None]);
+    }
+
+    #[test]
+    fn rankings_without_a_sent_candidate_are_invalid() {
+        let error = validate(&[hit(1), hit(2)], vec![judgement(7), judgement(8)]).unwrap_err();
+        assert!(matches!(error, RerankError::Invalid(_)), "{}", error);
+        assert!(matches!(validate(&[hit(1)], vec![]), Err(RerankError::Invalid(_))));
+    }
+
+    /// A reranker whose backend ranks the candidates it is sent in reverse
+    /// and counts its requests, over an empty database.
+    async fn reranker(name: &str) -> (Reranker, Arc<AtomicUsize>, TempStorage) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let llm = LlmClient::mock(move |Json(request): Json<serde_json::Value>| async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let prompt = request["messages"][1]["content"].as_str().unwrap_or_default().to_string();
+            let mut ranking: Vec<Judgement> = prompt.lines()
+                .filter_map(|line| line.strip_prefix("id ")?.split(':').next()?.parse().ok())
+                .map(judgement)
+                .collect();
+            ranking.reverse();
+            let content = json!({ "ranking": ranking }).to_string();
+            Json(LlmClient::completion(&content))
+        }).await;
+        let db = TempStorage::new(&format!("rerank_{}", name)).await;
+        (Reranker::new(llm, db.storage.clone(), 3), requests, db)
+    }
+
+    #[tokio::test]
+    async fn repeated_rerankings_are_cached() {
+        let (reranker, requests, _db) = reranker("cache").await;
+        let hits = || vec![hit(1), hit(2), hit(3), hit(4)];
+        let reranked = reranker.try_rerank("sad mecha", hits()).await.unwrap();
+        assert_eq!(ids(&reranked), vec![3, 2, 1, 4]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let reranked = reranker.try_rerank("  Sad Mecha ", hits()).await.unwrap();
+        assert_eq!(ids(&reranked), vec![3, 2, 1, 4]);
+        assert_eq!(reranked[0].justification.as_deref(), Some("reason 3"));
+        assert_eq!(requests.load(Ordering::SeqCst), 1, "the same query and candidates hit the cache");
+
+        reranker.try_rerank("sad mecha", vec![hit(2), hit(3), hit(4)]).await.unwrap();
+        reranker.try_rerank("happy mecha", hits()).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3, "other candidates or queries miss it");
+    }
+}
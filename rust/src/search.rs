@@ -0,0 +1,603 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::info;
+
+use crate::config::SearchIndexConfig;
+use crate::content_policy::{ContentPolicy, PolicyProfiles};
+use crate::embedder::Embedder;
+use crate::query_parser::{ParsedQuery, QueryParser};
+use crate::rerank::{RerankedHit, Reranker};
+use crate::storage::Storage;
+use crate::types::{AnimeGeneratedSummary, AnimeMetadata, EmbeddingSource, MediaFormat, Season};
+use crate::vector_index::{normalize, VectorIndex};
+
+/// Restricts which media a search may return. Filters are applied to the
+/// candidates before ranking in every search mode, so `k` hits are returned
+/// whenever enough media match. Names are matched ignoring case; media without
+/// a value for a filtered field (e.g. no format) never match.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchFilter {
+    /// Earliest season year, inclusive.
+    pub min_year: Option<i32>,
+    /// Latest season year, inclusive.
+    pub max_year: Option<i32>,
+    /// When not empty, media must air in one of these seasons.
+    pub seasons: Vec<Season>,
+    /// When not empty, media must have one of these formats.
+    pub formats: Vec<MediaFormat>,
+    pub min_mean_score: Option<i32>,
+    pub min_popularity: Option<i32>,
+    /// Media must have all of these genres.
+    pub include_genres: Vec<String>,
+    /// Media must have none of these genres.
+    pub exclude_genres: Vec<String>,
+    /// Media must have all of these generated themes.
+    pub include_themes: Vec<String>,
+    /// Media must have none of these generated themes.
+    pub exclude_themes: Vec<String>,
+    /// Leaves out media on this user's imported list, except planned ones.
+    pub exclude_seen_by: Option<String>,
+    /// Searches on behalf of this user, under their content policy profile.
+    /// Only set by the caller, never read from a parsed query.
+    #[serde(skip)]
+    pub user: Option<String>,
+}
+
+impl SearchFilter {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Whether the filter leaves out any media by itself, before the content
+    /// policy.
+    pub fn restricts_media(&self) -> bool {
+        self != &Self { user: self.user.clone(), ..Self::default() }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub metadata: AnimeMetadata,
+    pub summary: Option<AnimeGeneratedSummary>,
+    /// Relevance to the query: the cosine similarity of the summary for
+    /// semantic search, the BM25 score for keyword search and the fused score
+    /// for hybrid search.
+    pub score: f32,
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    Embedding(candle_core::Error),
+    Database(sqlx::Error),
+    /// The query vector does not match the stored embeddings.
+    Dimension { query: usize, stored: usize },
+    Index(std::io::Error),
+    /// A media used as a seed has no summary embedding.
+    MissingEmbedding(i32),
+    /// A taste profile needs at least one liked media.
+    NoLikedMedia,
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::Embedding(e) => write!(f, "failed to embed the query: {}", e),
+            SearchError::Database(e) => write!(f, "database error: {}", e),
+            SearchError::Dimension { query, stored } => write!(
+                f,
+                "the query embedding has dimension {} but the stored embeddings have dimension {}",
+                query, stored,
+            ),
+            SearchError::Index(e) => write!(f, "failed to read or write the search index: {}", e),
+            SearchError::MissingEmbedding(id) => write!(f, "media {} has no summary embedding", id),
+            SearchError::NoLikedMedia => write!(f, "at least one liked media is needed to recommend"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+impl From<candle_core::Error> for SearchError {
+    fn from(e: candle_core::Error) -> Self {
+        SearchError::Embedding(e)
+    }
+}
+
+impl From<sqlx::Error> for SearchError {
+    fn from(e: sqlx::Error) -> Self {
+        SearchError::Database(e)
+    }
+}
+
+/// Semantic search over the generated summaries. The summary embeddings of the
+/// embedder's model are indexed once, along with the media each content policy
+/// allows; call [`Searcher::reload`] after new media have been crawled or
+/// embedded.
+pub struct Searcher {
+    storage: Storage,
+    embedder: Arc<Embedder>,
+    policies: PolicyProfiles,
+    // The media each of `policies.distinct()` allows, in the same order.
+    allowed: Vec<HashSet<i32>>,
+    index: VectorIndex,
+    query_parser: Option<QueryParser>,
+    reranker: Option<Reranker>,
+}
+
+impl Searcher {
+    pub async fn load(storage: Storage, embedder: Arc<Embedder>, policies: PolicyProfiles, index: &SearchIndexConfig) -> Result<Self, SearchError> {
+        let index = VectorIndex::load(&storage, &embedder.model_name, EmbeddingSource::Summary, index).await?;
+        info!(count = index.len(), model = %embedder.model_name, "Loaded summary embeddings");
+        let allowed = allowed_media(&storage, &policies).await?;
+        Ok(Self { storage, embedder, policies, allowed, index, query_parser: None, reranker: None })
+    }
+
+    /// Has [`Searcher::search_with`] split queries into text and filters with
+    /// `parser` first.
+    pub fn with_query_parser(mut self, parser: QueryParser) -> Self {
+        self.query_parser = Some(parser);
+        self
+    }
+
+    /// Has [`Searcher::search_reranked`] rerank its hits with `reranker`.
+    pub fn with_reranker(mut self, reranker: Reranker) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    pub async fn reload(&mut self) -> Result<(), SearchError> {
+        self.index.refresh(&self.storage).await?;
+        self.allowed = allowed_media(&self.storage, &self.policies).await?;
+        Ok(())
+    }
+
+    pub(crate) fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// The policy of a search on behalf of `user`, or of anyone.
+    pub(crate) fn policy(&self, user: Option<&str>) -> &ContentPolicy {
+        self.policies.for_user(user)
+    }
+
+    /// The media a search may rank: those the filter and the searching user's
+    /// content policy allow. Applying the policy before ranking keeps the
+    /// nearest neighbor indexes from spending their `k` on excluded media.
+    pub(crate) async fn allowed_ids(&self, filter: &SearchFilter) -> Result<Cow<'_, HashSet<i32>>, SearchError> {
+        let policy = self.policy(filter.user.as_deref());
+        let position = self.policies.distinct().iter().position(|p| *p == policy).unwrap_or_default();
+        let allowed = &self.allowed[position];
+        if !filter.restricts_media() {
+            return Ok(Cow::Borrowed(allowed));
+        }
+        Ok(Cow::Owned(self.storage.filtered_media_ids(filter).await?
+            .into_iter()
+            .filter(|id| allowed.contains(id))
+            .collect()))
+    }
+
+    pub(crate) fn index(&self) -> &VectorIndex {
+        &self.index
+    }
+
+    pub async fn embed_query(&self, query: &str) -> Result<Vec<f32>, SearchError> {
+        let embedder = self.embedder.clone();
+        let query = query.to_string();
+        let mut vector = task::spawn_blocking(move || embedder.embed_one(&query))
+            .await
+            .map_err(candle_core::Error::wrap)??;
+        normalize(&mut vector);
+        if let Some(stored) = self.index.dimension() {
+            if stored != vector.len() {
+                return Err(SearchError::Dimension { query: vector.len(), stored });
+            }
+        }
+        Ok(vector)
+    }
+
+    /// Returns the `k` media whose summaries are closest to `query`, best
+    /// first. Media excluded by the filter or the content policy are skipped.
+    pub async fn search(&self, query: &str, k: usize, filter: &SearchFilter) -> Result<Vec<SearchHit>, SearchError> {
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        let ranked = self.semantic_ranking(query, k, filter).await?;
+        hits(&self.storage, self.policy(filter.user.as_deref()), ranked, k).await
+    }
+
+    async fn semantic_ranking(&self, query: &str, k: usize, filter: &SearchFilter) -> Result<Vec<(i32, f32)>, SearchError> {
+        let vector = self.embed_query(query).await?;
+        let allowed = self.allowed_ids(filter).await?;
+        self.index.rank(&vector, k, Some(&*allowed)).await
+    }
+
+    /// See [`KeywordSearcher::search`].
+    pub async fn keyword_search(&self, query: &str, k: usize, filter: &SearchFilter) -> Result<Vec<SearchHit>, SearchError> {
+        let mut ranked = keyword_ranking(&self.storage, query, k, filter).await?;
+        let allowed = self.allowed_ids(filter).await?;
+        ranked.retain(|(id, _)| allowed.contains(id));
+        hits(&self.storage, self.policy(filter.user.as_deref()), ranked, k).await
+    }
+
+    /// Answers `query` with the ranking of `mode`. `weights` are only used
+    /// by hybrid search. With a query parser, the constraints the query
+    /// states are added to `filter` and only the rest of it is ranked.
+    pub async fn search_with(&self, mode: SearchMode, query: &str, k: usize, filter: &SearchFilter, weights: &HybridWeights) -> Result<Vec<SearchHit>, SearchError> {
+        let parsed = match &self.query_parser {
+            Some(parser) => parser.parse(query).await,
+            None => ParsedQuery::plain(query),
+        };
+        let filter = &parsed.within(filter);
+        let query = parsed.text.as_str();
+        match mode {
+            SearchMode::Semantic => self.search(query, k, filter).await,
+            SearchMode::Keyword => self.keyword_search(query, k, filter).await,
+            SearchMode::Hybrid => self.hybrid_search(query, k, filter, weights).await,
+        }
+    }
+
+    /// Like [`Searcher::search_with`], then reranked by the LLM when a
+    /// reranker is set. The same top candidates are reranked whatever `k`
+    /// is, so that pages of the results agree with each other.
+    pub async fn search_reranked(&self, mode: SearchMode, query: &str, k: usize, filter: &SearchFilter, weights: &HybridWeights) -> Result<Vec<RerankedHit>, SearchError> {
+        let Some(reranker) = &self.reranker else {
+            let hits = self.search_with(mode, query, k, filter, weights).await?;
+            return Ok(hits.into_iter().map(RerankedHit::from).collect());
+        };
+        let hits = self.search_with(mode, query, k.max(reranker.candidates()), filter, weights).await?;
+        let mut reranked = reranker.rerank(query, hits).await;
+        reranked.truncate(k);
+        Ok(reranked)
+    }
+
+    /// Merges the semantic and keyword rankings of `query` as configured by
+    /// `weights`, then boosts popular and well scored media. Returns up to `k`
+    /// hits scored by the fused score.
+    pub async fn hybrid_search(&self, query: &str, k: usize, filter: &SearchFilter, weights: &HybridWeights) -> Result<Vec<SearchHit>, SearchError> {
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        let candidates = weights.candidates.max(k);
+        let mut semantic = self.semantic_ranking(query, candidates, filter).await?;
+        semantic.truncate(candidates);
+        let mut keyword = self.storage.keyword_search(&keyword_terms(query), filter, candidates as i64).await?;
+        let allowed = self.allowed_ids(filter).await?;
+        keyword.retain(|(id, _)| allowed.contains(id));
+
+        let mut fused = fuse(&[(&semantic, weights.semantic), (&keyword, weights.keyword)], weights);
+        let ids: Vec<i32> = fused.iter().map(|(id, _)| *id).collect();
+        let media = self.storage.media(&ids).await?;
+        let max_popularity = media.iter().filter_map(|anime| anime.popularity).max().unwrap_or(0);
+        let priors: HashMap<i32, f32> = media.iter()
+            .map(|anime| (anime.id, weights.prior(anime, max_popularity)))
+            .collect();
+        for (id, score) in fused.iter_mut() {
+            *score *= priors.get(id).copied().unwrap_or(1.0);
+        }
+        fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+        hits(&self.storage, self.policy(filter.user.as_deref()), fused, k).await
+    }
+}
+
+/// Which ranking a query is answered with.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// [`Searcher::search`]
+    Semantic,
+    /// [`Searcher::keyword_search`]
+    Keyword,
+    /// [`Searcher::hybrid_search`]
+    Hybrid,
+}
+
+impl std::str::FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "semantic" => Ok(SearchMode::Semantic),
+            "keyword" => Ok(SearchMode::Keyword),
+            "hybrid" => Ok(SearchMode::Hybrid),
+            _ => Err("expected semantic, keyword or hybrid".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fusion {
+    /// Reciprocal rank fusion: only the positions in each ranking count.
+    Rrf,
+    /// Scores min-max normalized within each ranking, then summed.
+    Weighted,
+}
+
+impl std::str::FromStr for Fusion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rrf" => Ok(Fusion::Rrf),
+            "weighted" => Ok(Fusion::Weighted),
+            _ => Err(format!("unknown fusion {:?}, expected rrf or weighted", s)),
+        }
+    }
+}
+
+/// How [`Searcher::hybrid_search`] combines its rankings, set in the
+/// `[hybrid]` table of the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HybridWeights {
+    pub fusion: Fusion,
+    /// Weight of the embedding ranking.
+    pub semantic: f32,
+    /// Weight of the keyword ranking.
+    pub keyword: f32,
+    /// The constant of reciprocal rank fusion; larger values flatten the
+    /// difference between the first ranks.
+    pub rrf_k: f32,
+    /// Boost of the most popular candidate, scaled logarithmically for the
+    /// others. 0.2 makes it score up to 20% higher.
+    pub popularity: f32,
+    /// Boost of a mean score of 100, scaled linearly.
+    pub mean_score: f32,
+    /// How many candidates each ranking contributes.
+    pub candidates: usize,
+}
+
+impl Default for HybridWeights {
+    fn default() -> Self {
+        Self {
+            fusion: Fusion::Rrf,
+            semantic: 1.0,
+            keyword: 1.0,
+            rrf_k: 60.0,
+            popularity: 0.1,
+            mean_score: 0.1,
+            candidates: 100,
+        }
+    }
+}
+
+impl HybridWeights {
+    /// The multiplier applied to a media's fused score. Priors multiply
+    /// rather than add so that they work the same on RRF and weighted scores.
+    fn prior(&self, anime: &AnimeMetadata, max_popularity: i32) -> f32 {
+        let popularity = match anime.popularity {
+            Some(popularity) if max_popularity > 0 => {
+                (popularity.max(0) as f32).ln_1p() / (max_popularity as f32).ln_1p()
+            }
+            _ => 0.0,
+        };
+        let mean_score = anime.mean_score.map_or(0.0, |score| score.clamp(0, 100) as f32 / 100.0);
+        1.0 + self.popularity * popularity + self.mean_score * mean_score
+    }
+}
+
+/// Fuses rankings, each best first, into one `(id, score)` list, best first.
+fn fuse(rankings: &[(&Vec<(i32, f32)>, f32)], weights: &HybridWeights) -> Vec<(i32, f32)> {
+    let mut fused: HashMap<i32, f32> = HashMap::new();
+    for (ranking, weight) in rankings {
+        match weights.fusion {
+            Fusion::Rrf => {
+                for (rank, (id, _)) in ranking.iter().enumerate() {
+                    *fused.entry(*id).or_default() += weight / (weights.rrf_k + rank as f32 + 1.0);
+                }
+            }
+            Fusion::Weighted => {
+                let max = ranking.iter().map(|(_, s)| *s).fold(f32::NEG_INFINITY, f32::max);
+                let min = ranking.iter().map(|(_, s)| *s).fold(f32::INFINITY, f32::min);
+                for (id, score) in ranking.iter() {
+                    let normalized = if max > min { (score - min) / (max - min) } else { 1.0 };
+                    *fused.entry(*id).or_default() += weight * normalized;
+                }
+            }
+        }
+    }
+    let mut fused: Vec<(i32, f32)> = fused.into_iter().collect();
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    fused
+}
+
+/// Splits a query into lowercase words for keyword search, dropping the
+/// punctuation that FTS5 and tsquery would read as operators.
+pub fn keyword_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = vec![];
+    for term in query.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()) {
+        let term = term.to_lowercase();
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+async fn keyword_ranking(storage: &Storage, query: &str, k: usize, filter: &SearchFilter) -> Result<Vec<(i32, f32)>, SearchError> {
+    // A few more than `k`, for the media the content policy drops.
+    let limit = (k * 4).max(50) as i64;
+    Ok(storage.keyword_search(&keyword_terms(query), filter, limit).await?)
+}
+
+/// Keyword search over titles, descriptions, summaries and generated themes.
+/// Unlike [`Searcher`] it needs no embedding model, which makes it the better
+/// fit for exact titles, romaji spellings and names.
+pub struct KeywordSearcher {
+    storage: Storage,
+    policy: ContentPolicy,
+}
+
+impl KeywordSearcher {
+    pub fn new(storage: Storage, policy: ContentPolicy) -> Self {
+        Self { storage, policy }
+    }
+
+    /// Returns up to `k` media matching any word of `query`, best first.
+    pub async fn search(&self, query: &str, k: usize, filter: &SearchFilter) -> Result<Vec<SearchHit>, SearchError> {
+        if k == 0 {
+            return Ok(vec![]);
+        }
+        hits(&self.storage, &self.policy, keyword_ranking(&self.storage, query, k, filter).await?, k).await
+    }
+}
+
+/// The media each distinct policy allows, in the order of
+/// [`PolicyProfiles::distinct`].
+async fn allowed_media(storage: &Storage, policies: &PolicyProfiles) -> Result<Vec<HashSet<i32>>, SearchError> {
+    let distinct = policies.distinct();
+    let mut allowed = vec![HashSet::new(); distinct.len()];
+    let ids = storage.filtered_media_ids(&SearchFilter::default()).await?;
+    for chunk in ids.chunks(1000) {
+        for anime in storage.media(chunk).await? {
+            for (policy, allowed) in distinct.iter().zip(allowed.iter_mut()) {
+                if policy.allows(&anime) {
+                    allowed.insert(anime.id);
+                }
+            }
+        }
+    }
+    Ok(allowed)
+}
+
+/// Turns ranked `(id, score)` pairs into at most `k` hits, dropping the
+/// media the content policy excludes, in case they changed since the searcher
+/// was loaded. Metadata is fetched a window at a
+/// time so that only a few more rows than `k` are usually read.
+pub(crate) async fn hits(storage: &Storage, policy: &ContentPolicy, ranked: Vec<(i32, f32)>, k: usize) -> Result<Vec<SearchHit>, SearchError> {
+    if k == 0 {
+        return Ok(vec![]);
+    }
+    let window = (k * 2).max(16);
+    let mut hits = Vec::with_capacity(k);
+    for chunk in ranked.chunks(window) {
+        let ids: Vec<i32> = chunk.iter().map(|(id, _)| *id).collect();
+        let mut media: HashMap<i32, AnimeMetadata> = storage.media(&ids).await?
+            .into_iter()
+            .map(|anime| (anime.id, anime))
+            .collect();
+        let mut summaries: HashMap<i32, AnimeGeneratedSummary> = storage.summaries(&ids).await?
+            .into_iter()
+            .map(|summary| (summary.id, summary.generated_summary))
+            .collect();
+        for (id, score) in chunk {
+            let Some(metadata) = media.remove(id) else {
+                continue;
+            };
+            if !policy.allows(&metadata) {
+                continue;
+            }
+            hits.push(SearchHit {
+                metadata,
+                summary: summaries.remove(id),
+                score: *score,
+            });
+            if hits.len() == k {
+                return Ok(hits);
+            }
+        }
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::AnimeMetadataRow;
+
+    fn ids(fused: &[(i32, f32)]) -> Vec<i32> {
+        fused.iter().map(|(id, _)| *id).collect()
+    }
+
+    fn anime(popularity: Option<i32>, mean_score: Option<i32>) -> AnimeMetadata {
+        AnimeMetadataRow {
+            id: 1,
+            mal_id: None,
+            english_title: None,
+            romaji_title: None,
+            season: None,
+            season_year: 2020,
+            format: None,
+            description: None,
+            popularity,
+            mean_score,
+            is_adult: None,
+        }
+        .into_metadata(vec![], vec![])
+    }
+
+    #[test]
+    fn filters_without_conditions_are_empty() {
+        assert!(SearchFilter::default().is_empty());
+        let for_user = SearchFilter { user: Some("kana".to_string()), ..SearchFilter::default() };
+        assert!(!for_user.is_empty());
+        assert!(!for_user.restricts_media());
+        let recent = SearchFilter { min_year: Some(2020), ..for_user };
+        assert!(recent.restricts_media());
+    }
+
+    #[test]
+    fn parsed_filters_reject_unknown_fields_and_users() {
+        let filter: SearchFilter = serde_json::from_str(r#"{"seasons": ["FALL"], "formats": ["TV_SHORT"], "min_mean_score": 70}"#).unwrap();
+        assert_eq!(filter.seasons, vec![Season::Fall]);
+        assert_eq!(filter.formats, vec![MediaFormat::TvShort]);
+        assert_eq!(filter.min_mean_score, Some(70));
+        assert!(serde_json::from_str::<SearchFilter>(r#"{"min_score": 70}"#).is_err());
+        assert!(serde_json::from_str::<SearchFilter>(r#"{"user": "kana"}"#).is_err());
+    }
+
+    #[test]
+    fn rrf_favours_media_in_both_rankings() {
+        let weights = HybridWeights::default();
+        let semantic = vec![(1, 0.9), (2, 0.8), (3, 0.7)];
+        let keyword = vec![(4, 12.0), (2, 11.0), (5, 1.0)];
+        let fused = fuse(&[(&semantic, 1.0), (&keyword, 1.0)], &weights);
+        assert_eq!(ids(&fused), vec![2, 1, 4, 3, 5]);
+        let expected = 1.0 / 62.0 + 1.0 / 62.0;
+        assert!((fused[0].1 - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rrf_ignores_scores_and_honours_weights() {
+        let weights = HybridWeights::default();
+        let semantic = vec![(1, 0.001), (2, 0.0)];
+        let keyword = vec![(2, 1000.0), (1, 999.0)];
+        let fused = fuse(&[(&semantic, 1.0), (&keyword, 1.0)], &weights);
+        assert_eq!(fused[0].1, fused[1].1);
+        assert_eq!(ids(&fused), vec![1, 2], "ties are broken by id");
+
+        let fused = fuse(&[(&semantic, 1.0), (&keyword, 2.0)], &weights);
+        assert_eq!(ids(&fused), vec![2, 1]);
+    }
+
+    #[test]
+    fn weighted_fusion_normalizes_each_ranking() {
+        let weights = HybridWeights { fusion: Fusion::Weighted, ..HybridWeights::default() };
+        let semantic = vec![(1, 0.9), (2, 0.5), (3, 0.1)];
+        let keyword = vec![(3, 40.0), (2, 30.0), (1, 0.0)];
+        let fused = fuse(&[(&semantic, 1.0), (&keyword, 1.0)], &weights);
+        let scores: HashMap<i32, f32> = fused.into_iter().collect();
+        assert!((scores[&1] - 1.0).abs() < 1e-6);
+        assert!((scores[&2] - (0.5 + 0.75)).abs() < 1e-6);
+        assert!((scores[&3] - 1.0).abs() < 1e-6);
+
+        let single = vec![(7, 3.0)];
+        assert_eq!(fuse(&[(&single, 0.5)], &weights), vec![(7, 0.5)]);
+    }
+
+    #[test]
+    fn priors_scale_with_popularity_and_mean_score() {
+        let weights = HybridWeights::default();
+        assert_eq!(weights.prior(&anime(None, None), 1000), 1.0);
+        assert!((weights.prior(&anime(Some(1000), Some(100)), 1000) - 1.2).abs() < 1e-6);
+        let less_popular = weights.prior(&anime(Some(10), None), 1000);
+        assert!(less_popular > 1.0 && less_popular < 1.1);
+        assert_eq!(weights.prior(&anime(Some(10), None), 0), 1.0);
+    }
+}
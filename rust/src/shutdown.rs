@@ -0,0 +1,71 @@
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+/// Tells the stages of a pipeline that they should stop taking new work.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Handles Ctrl-C for the rest of the process: the first press requests
+    /// a shutdown, the second exits immediately with status 130.
+    pub fn on_ctrl_c() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            let mut interrupted = false;
+            loop {
+                if let Err(e) = tokio::signal::ctrl_c().await {
+                    error!(error = %e, "Could not listen for Ctrl-C");
+                    return;
+                }
+                if interrupted {
+                    std::process::exit(130);
+                }
+                interrupted = true;
+                warn!("Interrupted, finishing the work in flight. Press Ctrl-C again to exit now");
+                let _ = sender.send(true);
+            }
+        });
+        Self(receiver)
+    }
+
+    /// A shutdown requested by sending true, for tests.
+    #[cfg(test)]
+    pub(crate) fn manual() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until a shutdown is requested.
+    pub async fn wait(&mut self) {
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_clone_sees_the_request() {
+        let (sender, shutdown) = Shutdown::manual();
+        let mut waiting = shutdown.clone();
+        let waiter = tokio::spawn(async move { waiting.wait().await });
+        assert!(!shutdown.requested());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        sender.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert!(shutdown.requested());
+
+        // Stages that only start waiting afterwards return right away.
+        let mut late = shutdown.clone();
+        tokio::time::timeout(Duration::from_secs(1), late.wait()).await.unwrap();
+    }
+}
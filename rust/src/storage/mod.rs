@@ -0,0 +1,396 @@
+pub mod postgres;
+pub mod sqlite;
+
+use std::collections::HashMap;
+
+use sqlx::{PgPool, Result, SqlitePool};
+
+use crate::config::DatabaseConfig;
+use crate::search::SearchFilter;
+use crate::types::{AnimeEmbedding, AnimeMetadata, AnimeSummary, EmbeddingSetInfo, EmbeddingSource, ListSource, MediaTag, RowCounts, UserListEntry};
+
+#[derive(sqlx::FromRow)]
+pub(crate) struct MediaTagRow {
+    media_id: i32,
+    id: i32,
+    name: String,
+    category: Option<String>,
+    rank: Option<i32>,
+    is_adult: bool,
+}
+
+impl From<MediaTagRow> for MediaTag {
+    fn from(row: MediaTagRow) -> Self {
+        MediaTag {
+            id: row.id,
+            name: row.name,
+            category: row.category,
+            rank: row.rank,
+            is_adult: Some(row.is_adult),
+        }
+    }
+}
+
+/// Storage backend selected by the scheme of the database URL: `sqlite:` for a
+/// local file, `postgres:`/`postgresql:` for a shared server. Cloning is cheap,
+/// both variants wrap a connection pool.
+#[derive(Clone)]
+pub enum Storage {
+    Sqlite(SqlitePool),
+    Postgres { pool: PgPool, pgvector: bool },
+}
+
+impl Storage {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        let url = config.url.as_str();
+        if url.starts_with("sqlite:") {
+            Ok(Storage::Sqlite(sqlite::connect(url).await?))
+        } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            let pool = postgres::connect(url, config.pgvector).await?;
+            Ok(Storage::Postgres { pool, pgvector: config.pgvector })
+        } else {
+            Err(sqlx::Error::Configuration(format!("unsupported database url {:?}, expected a sqlite: or postgres: url", url).into()))
+        }
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            Storage::Sqlite(_) => "sqlite",
+            Storage::Postgres { .. } => "postgres",
+        }
+    }
+
+    pub async fn upsert_metadata(&self, data: Vec<AnimeMetadata>) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Storage::Sqlite(pool) => sqlite::upsert_metadata(pool, data).await,
+            Storage::Postgres { pool, .. } => postgres::upsert_metadata(pool, data).await,
+        }
+    }
+
+    pub async fn upsert_summary(&self, data: AnimeSummary) -> Result<()> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::upsert_summary(pool, data).await,
+            Storage::Postgres { pool, .. } => postgres::upsert_summary(pool, data).await,
+        }
+    }
+
+    /// Returns the lowest and highest `season_year` in `anime_metadata`, or
+    /// `None` when the table is empty.
+    pub async fn season_year_range(&self) -> Result<Option<(i32, i32)>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::season_year_range(pool).await,
+            Storage::Postgres { pool, .. } => postgres::season_year_range(pool).await,
+        }
+    }
+
+    /// Counts the media of every year that still need a summary, before the
+    /// content policy is applied.
+    pub async fn pending_summary_count(&self) -> Result<i64> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::pending_summary_count(pool).await,
+            Storage::Postgres { pool, .. } => postgres::pending_summary_count(pool).await,
+        }
+    }
+
+    /// Returns the metadata of the given year that still needs a summary.
+    pub async fn pending_summaries(&self, season_year: i32) -> Result<Vec<AnimeMetadata>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::pending_summaries(pool, season_year).await,
+            Storage::Postgres { pool, .. } => postgres::pending_summaries(pool, season_year).await,
+        }
+    }
+
+    /// Returns the metadata of the given media with genres and tags, in no
+    /// particular order. Unknown ids are skipped.
+    pub async fn media(&self, ids: &[i32]) -> Result<Vec<AnimeMetadata>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::media(pool, ids).await,
+            Storage::Postgres { pool, .. } => postgres::media(pool, ids).await,
+        }
+    }
+
+    /// Returns the generated summaries of the given media, in no particular
+    /// order. Media without a summary are skipped.
+    pub async fn summaries(&self, ids: &[i32]) -> Result<Vec<AnimeSummary>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::summaries(pool, ids).await,
+            Storage::Postgres { pool, .. } => postgres::summaries(pool, ids).await,
+        }
+    }
+
+    /// Returns the ids of the media matching a search filter, ordered by id.
+    pub async fn filtered_media_ids(&self, filter: &SearchFilter) -> Result<Vec<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::filtered_media_ids(pool, filter).await,
+            Storage::Postgres { pool, .. } => postgres::filtered_media_ids(pool, filter).await,
+        }
+    }
+
+    /// Returns up to `limit` `(id, score)` pairs of the media matching any of
+    /// the keyword `terms` and the filter, best first. Scores are only
+    /// comparable within one backend.
+    pub async fn keyword_search(&self, terms: &[String], filter: &SearchFilter, limit: i64) -> Result<Vec<(i32, f32)>> {
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        match self {
+            Storage::Sqlite(pool) => sqlite::keyword_search(pool, terms, filter, limit).await,
+            Storage::Postgres { pool, .. } => postgres::keyword_search(pool, terms, filter, limit).await,
+        }
+    }
+
+    /// Returns the ids of the media with the given genre, ignoring case.
+    pub async fn media_ids_with_genre(&self, genre: &str) -> Result<Vec<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::media_ids_with_genre(pool, genre).await,
+            Storage::Postgres { pool, .. } => postgres::media_ids_with_genre(pool, genre).await,
+        }
+    }
+
+    /// Returns the ids of the media with the given tag at a rank of at least
+    /// `min_rank` percent, ignoring case.
+    pub async fn media_ids_with_tag(&self, tag: &str, min_rank: i32) -> Result<Vec<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::media_ids_with_tag(pool, tag, min_rank).await,
+            Storage::Postgres { pool, .. } => postgres::media_ids_with_tag(pool, tag, min_rank).await,
+        }
+    }
+
+    /// Stores embeddings in one transaction, replacing any previous vector for
+    /// the same media, model and source. Fails if a vector's dimension differs
+    /// from the ones already stored for its model and source.
+    pub async fn upsert_embeddings(&self, data: Vec<AnimeEmbedding>) -> Result<()> {
+        let mut dimensions: HashMap<(String, EmbeddingSource), usize> = HashMap::new();
+        for embedding in &data {
+            let key = (embedding.model.clone(), embedding.source);
+            let expected = match dimensions.get(&key) {
+                Some(dimension) => Some(*dimension),
+                None => self.embedding_dimension(&embedding.model, embedding.source).await?.map(|d| d as usize),
+            };
+            if let Some(expected) = expected {
+                if expected != embedding.embedding.len() {
+                    return Err(sqlx::Error::Protocol(format!(
+                        "embedding of media {} has dimension {}, but {} {} embeddings have dimension {}",
+                        embedding.id,
+                        embedding.embedding.len(),
+                        embedding.model,
+                        embedding.source.as_str(),
+                        expected,
+                    )));
+                }
+            }
+            dimensions.insert(key, embedding.embedding.len());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Storage::Sqlite(pool) => sqlite::upsert_embeddings(pool, data).await,
+            Storage::Postgres { pool, pgvector } => postgres::upsert_embeddings(pool, *pgvector, data).await,
+        }
+    }
+
+    /// Returns up to `limit` `(id, text)` pairs with an id greater than
+    /// `after_id` whose `source` text has no embedding for `model` yet,
+    /// ordered by id so the caller can page through them with the last id it
+    /// has seen.
+    pub async fn texts_without_embedding(&self, model: &str, source: EmbeddingSource, after_id: i32, limit: i64) -> Result<Vec<(i32, String)>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::texts_without_embedding(pool, model, source, after_id, limit).await,
+            Storage::Postgres { pool, .. } => postgres::texts_without_embedding(pool, model, source, after_id, limit).await,
+        }
+    }
+
+    pub async fn embedding_dimension(&self, model: &str, source: EmbeddingSource) -> Result<Option<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embedding_dimension(pool, model, source).await,
+            Storage::Postgres { pool, .. } => postgres::embedding_dimension(pool, model, source).await,
+        }
+    }
+
+    /// Returns the media ids of every embedding of a model and source, in
+    /// order.
+    pub async fn embedding_ids(&self, model: &str, source: EmbeddingSource) -> Result<Vec<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embedding_ids(pool, model, source).await,
+            Storage::Postgres { pool, .. } => postgres::embedding_ids(pool, model, source).await,
+        }
+    }
+
+    /// Returns every embedding of a model and source, ordered by media id.
+    pub async fn embeddings(&self, model: &str, source: EmbeddingSource) -> Result<Vec<AnimeEmbedding>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embeddings(pool, model, source).await,
+            Storage::Postgres { pool, .. } => postgres::embeddings(pool, model, source).await,
+        }
+    }
+
+    /// Returns the embeddings of a model and source written at or after
+    /// `since`, a `last_created_at` previously returned by
+    /// [`Storage::embedding_sets`].
+    pub async fn embeddings_since(&self, model: &str, source: EmbeddingSource, since: &str) -> Result<Vec<AnimeEmbedding>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embeddings_since(pool, model, source, since).await,
+            Storage::Postgres { pool, .. } => postgres::embeddings_since(pool, model, source, since).await,
+        }
+    }
+
+    pub async fn embedding(&self, id: i32, model: &str, source: EmbeddingSource) -> Result<Option<AnimeEmbedding>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embedding(pool, id, model, source).await,
+            Storage::Postgres { pool, .. } => postgres::embedding(pool, id, model, source).await,
+        }
+    }
+
+    /// Lists the stored embedding sets with their dimension and size.
+    pub async fn embedding_sets(&self) -> Result<Vec<EmbeddingSetInfo>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::embedding_sets(pool).await,
+            Storage::Postgres { pool, .. } => postgres::embedding_sets(pool).await,
+        }
+    }
+
+    /// Creates the pgvector index [`Storage::nearest_embeddings`] uses for an
+    /// embedding set. Fails unless the database is Postgres with pgvector.
+    pub async fn create_vector_index(&self, model: &str, source: EmbeddingSource, dimension: i32) -> Result<()> {
+        match self {
+            Storage::Postgres { pool, pgvector: true } => postgres::create_vector_index(pool, model, source, dimension).await,
+            _ => Err(pgvector_required()),
+        }
+    }
+
+    /// Returns the `limit` embeddings of a set closest to `query`, with their
+    /// cosine similarity, ranked by the database. Fails unless the database
+    /// is Postgres with pgvector.
+    pub async fn nearest_embeddings(&self, model: &str, source: EmbeddingSource, query: &[f32], limit: i64, allowed: Option<&[i32]>) -> Result<Vec<(i32, f32)>> {
+        match self {
+            Storage::Postgres { pool, pgvector: true } => postgres::nearest_embeddings(pool, model, source, query, limit, allowed).await,
+            _ => Err(pgvector_required()),
+        }
+    }
+
+    /// Returns every genre with the number of media in it.
+    pub async fn genre_counts(&self) -> Result<Vec<(String, i64)>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::genre_counts(pool).await,
+            Storage::Postgres { pool, .. } => postgres::genre_counts(pool).await,
+        }
+    }
+
+    /// Counts the stored media, summaries and user list entries.
+    pub async fn row_counts(&self) -> Result<RowCounts> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::row_counts(pool).await,
+            Storage::Postgres { pool, .. } => postgres::row_counts(pool).await,
+        }
+    }
+
+    /// Returns every generated theme with the number of summaries that have
+    /// it. Themes differing only in case are counted together.
+    pub async fn theme_counts(&self) -> Result<Vec<(String, i64)>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::theme_counts(pool).await,
+            Storage::Postgres { pool, .. } => postgres::theme_counts(pool).await,
+        }
+    }
+
+    /// Returns the ids of the media in the same franchise as `id`, including
+    /// `id`: every anime reachable through sequel, prequel, parent, side
+    /// story, spin-off, alternative, summary or compilation relations, in
+    /// either direction. Adaptations and "other" relations are not followed.
+    pub async fn franchise(&self, id: i32) -> Result<Vec<i32>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::franchise(pool, id).await,
+            Storage::Postgres { pool, .. } => postgres::franchise(pool, id).await,
+        }
+    }
+
+    /// Maps MyAnimeList ids to the AniList ids of crawled media, returning
+    /// `(mal_id, id)` pairs for the ones found.
+    pub async fn media_ids_for_mal(&self, mal_ids: &[i32]) -> Result<Vec<(i32, i32)>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::media_ids_for_mal(pool, mal_ids).await,
+            Storage::Postgres { pool, .. } => postgres::media_ids_for_mal(pool, mal_ids).await,
+        }
+    }
+
+    /// Replaces a user's entries imported from `source` with `entries`.
+    /// Entries of the same media imported from the other source are
+    /// overwritten.
+    pub async fn replace_user_list(&self, user_name: &str, source: ListSource, entries: &[UserListEntry]) -> Result<()> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::replace_user_list(pool, user_name, source, entries).await,
+            Storage::Postgres { pool, .. } => postgres::replace_user_list(pool, user_name, source, entries).await,
+        }
+    }
+
+    /// Returns a user's list, ordered by media id.
+    pub async fn user_list(&self, user_name: &str) -> Result<Vec<UserListEntry>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::user_list(pool, user_name).await,
+            Storage::Postgres { pool, .. } => postgres::user_list(pool, user_name).await,
+        }
+    }
+
+    /// Returns the cached LLM ranking of `candidates` for a query, if any.
+    pub async fn cached_rerank(&self, query: &str, model: &str, candidates: &str) -> Result<Option<String>> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::cached_rerank(pool, query, model, candidates).await,
+            Storage::Postgres { pool, .. } => postgres::cached_rerank(pool, query, model, candidates).await,
+        }
+    }
+
+    pub async fn store_rerank(&self, query: &str, model: &str, candidates: &str, ranking: &str) -> Result<()> {
+        match self {
+            Storage::Sqlite(pool) => sqlite::store_rerank(pool, query, model, candidates, ranking).await,
+            Storage::Postgres { pool, .. } => postgres::store_rerank(pool, query, model, candidates, ranking).await,
+        }
+    }
+}
+
+fn pgvector_required() -> sqlx::Error {
+    sqlx::Error::Configuration("the pgvector search index needs a postgres database with pgvector = true".into())
+}
+
+/// A migrated SQLite database in a temp file for unit tests, deleted when
+/// dropped. Dereferences to its [`Storage`].
+#[cfg(test)]
+pub(crate) struct TempStorage {
+    pub(crate) storage: Storage,
+    path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TempStorage {
+    /// `name` must be unique among the tests of the crate.
+    pub(crate) async fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("lam_test_{}_{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::connect(&DatabaseConfig { url: format!("sqlite://{}", path.display()), pgvector: false }).await.unwrap();
+        crate::migrations::migrate(&storage).await.unwrap();
+        Self { storage, path }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempStorage {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempStorage {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{PgPool, Result};
+
+use crate::storage::MediaTagRow;
+use crate::search::SearchFilter;
+use crate::types::{AnimeEmbedding, AnimeMetadata, AnimeMetadataRow, AnimeSummary, AnimeSummaryRow, EmbeddingSetInfo, EmbeddingSource, ListSource, MediaTag, RowCounts, UserListEntry, UserListEntryRow};
+use crate::vector_index::EXACT_FILTER_LIMIT;
+
+const MAX_CONNECTIONS: u32 = 16;
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, sqlx::FromRow)]
+struct Years {
+    max_year: Option<i32>,
+    min_year: Option<i32>,
+}
+
+/// Opens a connection pool to a Postgres server. With `pgvector` the `vector`
+/// extension is created up front so embedding tables can use its column type.
+pub async fn connect(database_url: &str, pgvector: bool) -> Result<PgPool> {
+    let pool = PgPoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .acquire_timeout(ACQUIRE_TIMEOUT)
+        .connect(database_url)
+        .await?;
+    if pgvector {
+        sqlx::query("CREATE EXTENSION IF NOT EXISTS vector;").execute(&pool).await?;
+    }
+    Ok(pool)
+}
+
+pub async fn upsert_metadata(pool: &PgPool, data: Vec<AnimeMetadata>) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let insert_sql = "
+        INSERT INTO anime_metadata (id, mal_id, romaji_title, english_title, season, season_year, format, description, popularity, mean_score, is_adult)
+    ";
+    let mut query = sqlx::QueryBuilder::new(insert_sql);
+    query.push_values(&data, |mut b, anime| {
+        b.push_bind(anime.id)
+            .push_bind(anime.mal_id)
+            .push_bind(anime.title.romaji.clone())
+            .push_bind(anime.title.english.clone())
+            .push_bind(anime.season.clone())
+            .push_bind(anime.season_year)
+            .push_bind(anime.format.clone())
+            .push_bind(anime.description.clone())
+            .push_bind(anime.popularity)
+            .push_bind(anime.mean_score)
+            .push_bind(anime.is_adult);
+    });
+    query.push("
+        ON CONFLICT (id) DO UPDATE SET
+            mal_id = EXCLUDED.mal_id,
+            romaji_title = EXCLUDED.romaji_title,
+            english_title = EXCLUDED.english_title,
+            season = EXCLUDED.season,
+            season_year = EXCLUDED.season_year,
+            format = EXCLUDED.format,
+            description = EXCLUDED.description,
+            popularity = EXCLUDED.popularity,
+            mean_score = EXCLUDED.mean_score,
+            is_adult = EXCLUDED.is_adult
+    ");
+    query.build().execute(&mut *tx).await?;
+
+    for anime in &data {
+        sqlx::query("DELETE FROM media_genre WHERE media_id = $1;").bind(anime.id).execute(&mut *tx).await?;
+        for genre in anime.genres.iter().flatten() {
+            sqlx::query("INSERT INTO genre (name) VALUES ($1) ON CONFLICT (name) DO NOTHING;").bind(genre).execute(&mut *tx).await?;
+            sqlx::query("INSERT INTO media_genre (media_id, genre_id) SELECT $1, id FROM genre WHERE name = $2 ON CONFLICT DO NOTHING;")
+                .bind(anime.id)
+                .bind(genre)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM media_tag WHERE media_id = $1;").bind(anime.id).execute(&mut *tx).await?;
+        for tag in anime.tags.iter().flatten() {
+            sqlx::query("
+                INSERT INTO tag (id, name, category, is_adult) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (id) DO UPDATE SET
+                    name = EXCLUDED.name,
+                    category = EXCLUDED.category,
+                    is_adult = EXCLUDED.is_adult;
+                ")
+                .bind(tag.id)
+                .bind(&tag.name)
+                .bind(&tag.category)
+                .bind(tag.is_adult.unwrap_or(false))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("
+                INSERT INTO media_tag (media_id, tag_id, rank) VALUES ($1, $2, $3)
+                ON CONFLICT (media_id, tag_id) DO UPDATE SET rank = EXCLUDED.rank;
+                ")
+                .bind(anime.id)
+                .bind(tag.id)
+                .bind(tag.rank)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Media loaded without relations keep the stored ones.
+        if let Some(relations) = &anime.relations {
+            sqlx::query("DELETE FROM media_relation WHERE media_id = $1;").bind(anime.id).execute(&mut *tx).await?;
+            for relation in relations {
+                sqlx::query("
+                    INSERT INTO media_relation (media_id, related_id, relation_type) VALUES ($1, $2, $3)
+                    ON CONFLICT (media_id, related_id) DO UPDATE SET relation_type = EXCLUDED.relation_type;
+                    ")
+                    .bind(anime.id)
+                    .bind(relation.id)
+                    .bind(&relation.relation_type)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn upsert_summary(pool: &PgPool, data: AnimeSummary) -> Result<()> {
+    sqlx::query("
+        INSERT INTO anime_summary (id, summary, generated_genres, generated_themes)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (id) DO UPDATE SET
+            summary = EXCLUDED.summary,
+            generated_genres = EXCLUDED.generated_genres,
+            generated_themes = EXCLUDED.generated_themes
+        ")
+        .bind(data.id)
+        .bind(data.generated_summary.summary)
+        .bind(data.generated_summary.generated_genres.join(","))
+        .bind(data.generated_summary.generated_themes.join(","))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn season_year_range(pool: &PgPool) -> Result<Option<(i32, i32)>> {
+    let years: Years = sqlx::query_as("SELECT MAX(season_year) AS max_year, MIN(season_year) AS min_year FROM anime_metadata;")
+        .fetch_one(pool)
+        .await?;
+    Ok(years.min_year.zip(years.max_year))
+}
+
+pub async fn pending_summary_count(pool: &PgPool) -> Result<i64> {
+    sqlx::query_scalar("
+        SELECT COUNT(*) FROM anime_metadata m
+        WHERE m.description <> ''
+            AND m.description IS NOT NULL
+            AND m.season_year IS NOT NULL
+            AND NOT EXISTS (
+                SELECT 1 FROM anime_summary s WHERE s.id = m.id
+            );
+        ").fetch_one(pool).await
+}
+
+pub async fn pending_summaries(pool: &PgPool, season_year: i32) -> Result<Vec<AnimeMetadata>> {
+    let rows: Vec<AnimeMetadataRow> = sqlx::query_as("
+        SELECT m.id, m.mal_id, m.english_title, m.romaji_title, m.season, m.season_year, m.format, m.description, m.popularity, m.mean_score, m.is_adult
+        FROM anime_metadata m
+        WHERE m.description <> ''
+            AND m.description IS NOT NULL
+            AND m.season_year = $1
+            AND NOT EXISTS (
+                SELECT 1 FROM anime_summary s WHERE s.id = m.id
+            );
+        ").bind(season_year).fetch_all(pool).await?;
+    with_genres_and_tags(pool, rows).await
+}
+
+/// Attaches genres and tags from the join tables to metadata rows.
+pub async fn with_genres_and_tags(pool: &PgPool, rows: Vec<AnimeMetadataRow>) -> Result<Vec<AnimeMetadata>> {
+    let ids: Vec<i32> = rows.iter().map(|row| row.id).collect();
+    let mut genres = genres_for(pool, &ids).await?;
+    let mut tags = tags_for(pool, &ids).await?;
+    Ok(rows.into_iter().map(|row| {
+        let id = row.id;
+        row.into_metadata(genres.remove(&id).unwrap_or_default(), tags.remove(&id).unwrap_or_default())
+    }).collect())
+}
+
+pub async fn genres_for(pool: &PgPool, ids: &[i32]) -> Result<HashMap<i32, Vec<String>>> {
+    let rows: Vec<(i32, String)> = sqlx::query_as("
+        SELECT mg.media_id, g.name FROM media_genre mg
+        JOIN genre g ON g.id = mg.genre_id
+        WHERE mg.media_id = ANY($1)
+        ORDER BY mg.media_id, g.name;
+        ").bind(ids).fetch_all(pool).await?;
+    let mut genres: HashMap<i32, Vec<String>> = HashMap::new();
+    for (media_id, name) in rows {
+        genres.entry(media_id).or_default().push(name);
+    }
+    Ok(genres)
+}
+
+pub async fn tags_for(pool: &PgPool, ids: &[i32]) -> Result<HashMap<i32, Vec<MediaTag>>> {
+    let rows: Vec<MediaTagRow> = sqlx::query_as("
+        SELECT mt.media_id, t.id, t.name, t.category, mt.rank, t.is_adult FROM media_tag mt
+        JOIN tag t ON t.id = mt.tag_id
+        WHERE mt.media_id = ANY($1)
+        ORDER BY mt.media_id, mt.rank DESC NULLS LAST;
+        ").bind(ids).fetch_all(pool).await?;
+    let mut tags: HashMap<i32, Vec<MediaTag>> = HashMap::new();
+    for row in rows {
+        tags.entry(row.media_id).or_default().push(row.into());
+    }
+    Ok(tags)
+}
+
+pub async fn media(pool: &PgPool, ids: &[i32]) -> Result<Vec<AnimeMetadata>> {
+    let rows: Vec<AnimeMetadataRow> = sqlx::query_as("
+        SELECT id, mal_id, english_title, romaji_title, season, season_year, format, description, popularity, mean_score, is_adult
+        FROM anime_metadata
+        WHERE id = ANY($1);
+        ").bind(ids).fetch_all(pool).await?;
+    with_genres_and_tags(pool, rows).await
+}
+
+pub async fn summaries(pool: &PgPool, ids: &[i32]) -> Result<Vec<AnimeSummary>> {
+    let rows: Vec<AnimeSummaryRow> = sqlx::query_as("
+        SELECT id, summary, generated_genres, generated_themes
+        FROM anime_summary
+        WHERE id = ANY($1);
+        ").bind(ids).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(AnimeSummaryRow::into_summary).collect())
+}
+
+/// Appends the conditions of a search filter on `anime_metadata m`.
+fn push_filter(query: &mut sqlx::QueryBuilder<sqlx::Postgres>, filter: &SearchFilter) {
+    if let Some(min_year) = filter.min_year {
+        query.push(" AND m.season_year >= ").push_bind(min_year);
+    }
+    if let Some(max_year) = filter.max_year {
+        query.push(" AND m.season_year <= ").push_bind(max_year);
+    }
+    if !filter.seasons.is_empty() {
+        push_in(query, "m.season", filter.seasons.iter().map(|season| season.as_str()));
+    }
+    if !filter.formats.is_empty() {
+        push_in(query, "m.format", filter.formats.iter().map(|format| format.as_str()));
+    }
+    if let Some(min_mean_score) = filter.min_mean_score {
+        query.push(" AND m.mean_score >= ").push_bind(min_mean_score);
+    }
+    if let Some(min_popularity) = filter.min_popularity {
+        query.push(" AND m.popularity >= ").push_bind(min_popularity);
+    }
+    let genres = filter.include_genres.iter().map(|genre| (genre, true))
+        .chain(filter.exclude_genres.iter().map(|genre| (genre, false)));
+    for (genre, include) in genres {
+        query.push(if include { " AND EXISTS" } else { " AND NOT EXISTS" })
+            .push(" (SELECT 1 FROM media_genre mg JOIN genre g ON g.id = mg.genre_id WHERE mg.media_id = m.id AND LOWER(g.name) = LOWER(")
+            .push_bind(genre.clone())
+            .push("))");
+    }
+    // Generated themes are stored comma-separated.
+    let themes = filter.include_themes.iter().map(|theme| (theme, true))
+        .chain(filter.exclude_themes.iter().map(|theme| (theme, false)));
+    for (theme, include) in themes {
+        query.push(if include { " AND EXISTS" } else { " AND NOT EXISTS" })
+            .push(" (SELECT 1 FROM anime_summary s, unnest(string_to_array(s.generated_themes, ',')) t WHERE s.id = m.id AND LOWER(TRIM(t)) = LOWER(")
+            .push_bind(theme.clone())
+            .push("))");
+    }
+    if let Some(user_name) = &filter.exclude_seen_by {
+        query.push(" AND NOT EXISTS (SELECT 1 FROM user_list ul WHERE ul.media_id = m.id AND ul.status <> 'PLANNING' AND ul.user_name = ")
+            .push_bind(user_name.clone())
+            .push(")");
+    }
+}
+
+fn push_in<'a>(query: &mut sqlx::QueryBuilder<sqlx::Postgres>, column: &str, values: impl Iterator<Item = &'a str>) {
+    query.push(format!(" AND {} IN (", column));
+    let mut separated = query.separated(", ");
+    for value in values {
+        separated.push_bind(value.to_string());
+    }
+    separated.push_unseparated(")");
+}
+
+pub async fn filtered_media_ids(pool: &PgPool, filter: &SearchFilter) -> Result<Vec<i32>> {
+    let mut query: sqlx::QueryBuilder<sqlx::Postgres> = sqlx::QueryBuilder::new("SELECT m.id FROM anime_metadata m WHERE 1 = 1");
+    push_filter(&mut query, filter);
+    query.push(" ORDER BY m.id");
+    query.build_query_scalar().fetch_all(pool).await
+}
+
+/// Ranks media by `ts_rank_cd` over their titles and description and their
+/// generated summary and themes, title matches weighing the most. `terms` are
+/// ORed and matched both as written and stemmed.
+pub async fn keyword_search(pool: &PgPool, terms: &[String], filter: &SearchFilter, limit: i64) -> Result<Vec<(i32, f32)>> {
+    let expression = terms.iter()
+        .map(|term| term.replace(|c: char| !c.is_alphanumeric(), ""))
+        .filter(|term| !term.is_empty())
+        .collect::<Vec<_>>()
+        .join(" | ");
+    let mut query: sqlx::QueryBuilder<sqlx::Postgres> = sqlx::QueryBuilder::new("
+        WITH q AS (
+            SELECT to_tsquery('simple', ");
+    query.push_bind(expression.clone());
+    query.push(") || to_tsquery('english', ");
+    query.push_bind(expression);
+    query.push(") AS query
+        ), matches AS (
+            SELECT m.id, ts_rank_cd(m.search_vector, q.query) AS rank
+            FROM anime_metadata m, q WHERE m.search_vector @@ q.query
+            UNION ALL
+            SELECT s.id, ts_rank_cd(s.search_vector, q.query) AS rank
+            FROM anime_summary s, q WHERE s.search_vector @@ q.query
+        )
+        SELECT m.id, SUM(x.rank)::float8 AS score FROM matches x
+        JOIN anime_metadata m ON m.id = x.id
+        WHERE 1 = 1");
+    push_filter(&mut query, filter);
+    query.push(" GROUP BY m.id ORDER BY score DESC, m.id LIMIT ").push_bind(limit);
+    let rows: Vec<(i32, f64)> = query.build_query_as().fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, score)| (id, score as f32)).collect())
+}
+
+
+pub async fn media_ids_with_genre(pool: &PgPool, genre: &str) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        SELECT mg.media_id FROM media_genre mg
+        JOIN genre g ON g.id = mg.genre_id
+        WHERE LOWER(g.name) = LOWER($1)
+        ORDER BY mg.media_id;
+        ").bind(genre).fetch_all(pool).await
+}
+
+pub async fn media_ids_with_tag(pool: &PgPool, tag: &str, min_rank: i32) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        SELECT mt.media_id FROM media_tag mt
+        JOIN tag t ON t.id = mt.tag_id
+        WHERE LOWER(t.name) = LOWER($1) AND COALESCE(mt.rank, 0) >= $2
+        ORDER BY mt.media_id;
+        ").bind(tag).bind(min_rank).fetch_all(pool).await
+}
+
+pub async fn genre_counts(pool: &PgPool) -> Result<Vec<(String, i64)>> {
+    sqlx::query_as("
+        SELECT g.name, COUNT(mg.media_id) FROM genre g
+        LEFT JOIN media_genre mg ON mg.genre_id = g.id
+        GROUP BY g.id, g.name
+        ORDER BY g.name;
+        ").fetch_all(pool).await
+}
+
+pub async fn row_counts(pool: &PgPool) -> Result<RowCounts> {
+    sqlx::query_as("
+        SELECT
+            (SELECT COUNT(*) FROM anime_metadata) AS media,
+            (SELECT COUNT(*) FROM anime_summary) AS summaries,
+            (SELECT COUNT(DISTINCT user_name) FROM user_list) AS list_users,
+            (SELECT COUNT(*) FROM user_list) AS list_entries;
+        ").fetch_one(pool).await
+}
+
+pub async fn theme_counts(pool: &PgPool) -> Result<Vec<(String, i64)>> {
+    // Generated themes are stored comma-separated.
+    sqlx::query_as("
+        SELECT MIN(TRIM(t)), COUNT(DISTINCT s.id) FROM anime_summary s, unnest(string_to_array(s.generated_themes, ',')) t
+        WHERE TRIM(t) <> ''
+        GROUP BY LOWER(TRIM(t))
+        ORDER BY LOWER(TRIM(t));
+        ").fetch_all(pool).await
+}
+
+pub async fn franchise(pool: &PgPool, id: i32) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        WITH RECURSIVE
+            edge (a, b) AS (
+                SELECT media_id, related_id FROM media_relation WHERE relation_type IN ('PREQUEL', 'SEQUEL', 'PARENT', 'SIDE_STORY', 'SPIN_OFF', 'ALTERNATIVE', 'SUMMARY', 'COMPILATION', 'CONTAINS')
+                UNION ALL
+                SELECT related_id, media_id FROM media_relation WHERE relation_type IN ('PREQUEL', 'SEQUEL', 'PARENT', 'SIDE_STORY', 'SPIN_OFF', 'ALTERNATIVE', 'SUMMARY', 'COMPILATION', 'CONTAINS')
+            ),
+            franchise (id) AS (
+                SELECT $1::INTEGER
+                UNION
+                SELECT edge.b FROM edge JOIN franchise ON edge.a = franchise.id
+            )
+        SELECT id FROM franchise ORDER BY id;
+        ")
+        .bind(id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn media_ids_for_mal(pool: &PgPool, mal_ids: &[i32]) -> Result<Vec<(i32, i32)>> {
+    sqlx::query_as("SELECT mal_id, id FROM anime_metadata WHERE mal_id = ANY($1);")
+        .bind(mal_ids)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn replace_user_list(pool: &PgPool, user_name: &str, source: ListSource, entries: &[UserListEntry]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM user_list WHERE user_name = $1 AND source = $2;")
+        .bind(user_name)
+        .bind(source.as_str())
+        .execute(&mut *tx)
+        .await?;
+    for entry in entries {
+        sqlx::query("
+            INSERT INTO user_list (user_name, media_id, status, score, progress, source) VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_name, media_id) DO UPDATE SET
+                status = EXCLUDED.status,
+                score = EXCLUDED.score,
+                progress = EXCLUDED.progress,
+                source = EXCLUDED.source,
+                updated_at = now();
+            ")
+            .bind(user_name)
+            .bind(entry.media_id)
+            .bind(entry.status.as_str())
+            .bind(entry.score)
+            .bind(entry.progress)
+            .bind(source.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn user_list(pool: &PgPool, user_name: &str) -> Result<Vec<UserListEntry>> {
+    let rows: Vec<UserListEntryRow> = sqlx::query_as("
+        SELECT user_name, media_id, status, score, progress, source FROM user_list
+        WHERE user_name = $1
+        ORDER BY media_id;
+        ").bind(user_name).fetch_all(pool).await?;
+    rows.into_iter()
+        .map(|row| row.into_entry().map_err(|e| sqlx::Error::Decode(e.into())))
+        .collect()
+}
+
+pub async fn cached_rerank(pool: &PgPool, query: &str, model: &str, candidates: &str) -> Result<Option<String>> {
+    sqlx::query_scalar("SELECT ranking FROM rerank_cache WHERE query = $1 AND model = $2 AND candidates = $3;")
+        .bind(query)
+        .bind(model)
+        .bind(candidates)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn store_rerank(pool: &PgPool, query: &str, model: &str, candidates: &str, ranking: &str) -> Result<()> {
+    sqlx::query("
+        INSERT INTO rerank_cache (query, model, candidates, ranking) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (query, model, candidates) DO UPDATE SET ranking = excluded.ranking, created_at = now();
+        ")
+        .bind(query)
+        .bind(model)
+        .bind(candidates)
+        .bind(ranking)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// pgvector's text representation, e.g. `[0.1,0.2]`.
+fn to_vector_literal(embedding: &[f32]) -> String {
+    let values: Vec<String> = embedding.iter().map(|x| x.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+pub async fn upsert_embeddings(pool: &PgPool, pgvector: bool, data: Vec<AnimeEmbedding>) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for embedding in data {
+        let dimension = embedding.embedding.len() as i32;
+        let query = if pgvector {
+            sqlx::query("
+                INSERT INTO anime_embedding (id, model, source, dimension, embedding) VALUES ($1, $2, $3, $4, $5::vector)
+                ON CONFLICT (id, model, source) DO UPDATE SET
+                    dimension = EXCLUDED.dimension,
+                    embedding = EXCLUDED.embedding,
+                    created_at = now();
+                ")
+                .bind(embedding.id)
+                .bind(embedding.model)
+                .bind(embedding.source.as_str())
+                .bind(dimension)
+                .bind(to_vector_literal(&embedding.embedding))
+        } else {
+            sqlx::query("
+                INSERT INTO anime_embedding (id, model, source, dimension, embedding) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (id, model, source) DO UPDATE SET
+                    dimension = EXCLUDED.dimension,
+                    embedding = EXCLUDED.embedding,
+                    created_at = now();
+                ")
+                .bind(embedding.id)
+                .bind(embedding.model)
+                .bind(embedding.source.as_str())
+                .bind(dimension)
+                .bind(embedding.embedding)
+        };
+        query.execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn texts_without_embedding(pool: &PgPool, model: &str, source: EmbeddingSource, after_id: i32, limit: i64) -> Result<Vec<(i32, String)>> {
+    let sql = match source {
+        EmbeddingSource::Summary => "
+            SELECT s.id, s.summary FROM anime_summary s
+            JOIN anime_metadata m ON m.id = s.id
+            WHERE s.id > $1
+                AND s.summary IS NOT NULL
+                AND s.summary <> ''
+                AND NOT EXISTS (
+                    SELECT 1 FROM anime_embedding e WHERE e.id = s.id AND e.model = $2 AND e.source = $3
+                )
+            ORDER BY s.id
+            LIMIT $4;
+        ",
+        EmbeddingSource::Description => "
+            SELECT m.id, m.description FROM anime_metadata m
+            WHERE m.id > $1
+                AND m.description IS NOT NULL
+                AND m.description <> ''
+                AND NOT EXISTS (
+                    SELECT 1 FROM anime_embedding e WHERE e.id = m.id AND e.model = $2 AND e.source = $3
+                )
+            ORDER BY m.id
+            LIMIT $4;
+        ",
+    };
+    sqlx::query_as(sql)
+        .bind(after_id)
+        .bind(model)
+        .bind(source.as_str())
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn embedding_ids(pool: &PgPool, model: &str, source: EmbeddingSource) -> Result<Vec<i32>> {
+    sqlx::query_scalar("SELECT id FROM anime_embedding WHERE model = $1 AND source = $2 ORDER BY id;")
+        .bind(model)
+        .bind(source.as_str())
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn embedding_dimension(pool: &PgPool, model: &str, source: EmbeddingSource) -> Result<Option<i32>> {
+    sqlx::query_scalar("SELECT dimension FROM anime_embedding WHERE model = $1 AND source = $2 LIMIT 1;")
+        .bind(model)
+        .bind(source.as_str())
+        .fetch_optional(pool)
+        .await
+}
+
+// Casting to real[] reads both plain arrays and pgvector columns.
+pub async fn embeddings(pool: &PgPool, model: &str, source: EmbeddingSource) -> Result<Vec<AnimeEmbedding>> {
+    let rows: Vec<(i32, Vec<f32>)> = sqlx::query_as("
+        SELECT id, embedding::real[] FROM anime_embedding
+        WHERE model = $1 AND source = $2
+        ORDER BY id;
+        ").bind(model).bind(source.as_str()).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, embedding)| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding,
+    }).collect())
+}
+
+pub async fn embeddings_since(pool: &PgPool, model: &str, source: EmbeddingSource, since: &str) -> Result<Vec<AnimeEmbedding>> {
+    let rows: Vec<(i32, Vec<f32>)> = sqlx::query_as("
+        SELECT id, embedding::real[] FROM anime_embedding
+        WHERE model = $1 AND source = $2 AND created_at >= $3::timestamptz
+        ORDER BY id;
+        ").bind(model).bind(source.as_str()).bind(since).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, embedding)| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding,
+    }).collect())
+}
+
+pub async fn embedding(pool: &PgPool, id: i32, model: &str, source: EmbeddingSource) -> Result<Option<AnimeEmbedding>> {
+    let embedding: Option<Vec<f32>> = sqlx::query_scalar("
+        SELECT embedding::real[] FROM anime_embedding
+        WHERE id = $1 AND model = $2 AND source = $3;
+        ").bind(id).bind(model).bind(source.as_str()).fetch_optional(pool).await?;
+    Ok(embedding.map(|embedding| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding,
+    }))
+}
+
+// A string literal for SQL that cannot take parameters, with the standard
+// (non-escape) string syntax.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// The name of the HNSW index of one embedding set. Model names can be long or
+// contain any character, so they are hashed (FNV-1a) to fit an identifier.
+fn vector_index_name(model: &str, source: EmbeddingSource, dimension: i32) -> String {
+    let hash = model.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("anime_embedding_hnsw_{}_{}_{:016x}", source.as_str(), dimension, hash)
+}
+
+// The set's rows, as a predicate with literals so that the planner can match
+// it against the partial index.
+fn embedding_set_predicate(model: &str, source: EmbeddingSource) -> String {
+    format!("model = {} AND source = {}", quote_literal(model), quote_literal(source.as_str()))
+}
+
+/// Creates the cosine HNSW index [`nearest_embeddings`] searches one embedding
+/// set with. pgvector only indexes vectors of a fixed dimension, so each set
+/// gets a partial index over the column cast to its dimension.
+pub async fn create_vector_index(pool: &PgPool, model: &str, source: EmbeddingSource, dimension: i32) -> Result<()> {
+    let sql = format!(
+        "CREATE INDEX IF NOT EXISTS {} ON anime_embedding USING hnsw ((embedding::vector({})) vector_cosine_ops) WHERE {};",
+        vector_index_name(model, source, dimension),
+        dimension,
+        embedding_set_predicate(model, source),
+    );
+    sqlx::query(&sql).execute(pool).await?;
+    Ok(())
+}
+
+/// Ranks the embeddings of a set by cosine similarity to `query` with the
+/// pgvector `<=>` operator, best first. `allowed` restricts the ranked media:
+/// small allowed sets are ranked exactly without the HNSW index, larger ones
+/// with an iterative scan that goes on until `limit` allowed media are found.
+/// Needs pgvector 0.8 and the index of [`create_vector_index`] to be fast.
+pub async fn nearest_embeddings(pool: &PgPool, model: &str, source: EmbeddingSource, query: &[f32], limit: i64, allowed: Option<&[i32]>) -> Result<Vec<(i32, f32)>> {
+    let dimension = query.len();
+    let predicate = embedding_set_predicate(model, source);
+    let mut tx = pool.begin().await?;
+    let sql = match allowed {
+        // The planner cannot use the HNSW index on a materialized CTE, so the
+        // allowed rows are fetched by id and all of them are scored.
+        Some(allowed) if allowed.len() <= EXACT_FILTER_LIMIT => format!(
+            "WITH candidates AS MATERIALIZED (
+                SELECT id, embedding::vector({dimension}) AS embedding
+                FROM anime_embedding
+                WHERE {predicate} AND id = ANY($2)
+            )
+            SELECT id, (1 - (embedding <=> $1::vector({dimension})))::real
+            FROM candidates
+            ORDER BY embedding <=> $1::vector({dimension})
+            LIMIT $3;",
+        ),
+        _ => {
+            // The HNSW scan stops after `ef_search` candidates, before the
+            // filter on the allowed media, unless it is iterative.
+            sqlx::query("SELECT set_config('hnsw.ef_search', $1, true);")
+                .bind(limit.clamp(40, 1000).to_string())
+                .execute(&mut *tx)
+                .await?;
+            if allowed.is_some() {
+                sqlx::query("SELECT set_config('hnsw.iterative_scan', 'strict_order', true);")
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            format!(
+                "SELECT id, (1 - (embedding::vector({dimension}) <=> $1::vector({dimension})))::real
+                FROM anime_embedding
+                WHERE {predicate} AND ($2::integer[] IS NULL OR id = ANY($2))
+                ORDER BY embedding::vector({dimension}) <=> $1::vector({dimension})
+                LIMIT $3;",
+            )
+        }
+    };
+    let rows = sqlx::query_as(&sql)
+        .bind(to_vector_literal(query))
+        .bind(allowed)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(rows)
+}
+
+pub async fn embedding_sets(pool: &PgPool) -> Result<Vec<EmbeddingSetInfo>> {
+    sqlx::query_as("
+        SELECT model, source, MAX(dimension) AS dimension, COUNT(*) AS count, MAX(created_at)::text AS last_created_at
+        FROM anime_embedding
+        GROUP BY model, source
+        ORDER BY model, source;
+        ").fetch_all(pool).await
+}
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Result, SqlitePool};
+
+use crate::storage::MediaTagRow;
+use crate::search::SearchFilter;
+use crate::types::{AnimeEmbedding, AnimeMetadata, AnimeMetadataRow, AnimeSummary, AnimeSummaryRow, EmbeddingSetInfo, EmbeddingSource, ListSource, MediaTag, RowCounts, UserListEntry, UserListEntryRow};
+
+const MAX_CONNECTIONS: u32 = 8;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+// Rows per multi-row insert, well under SQLite's limit of 32766 bound
+// parameters.
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, sqlx::FromRow)]
+struct Years {
+    max_year: Option<i32>,
+    min_year: Option<i32>,
+}
+
+/// Opens a connection pool shared by every task of a run. WAL lets readers
+/// proceed while a loader is writing, and the busy timeout makes concurrent
+/// writers wait for the lock instead of failing with `database is locked`.
+pub async fn connect(database_url: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(database_url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT)
+        .foreign_keys(true);
+    SqlitePoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .connect_with(options)
+        .await
+}
+
+pub async fn upsert_metadata(pool: &SqlitePool, data: Vec<AnimeMetadata>) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let insert_sql = "
+        INSERT INTO anime_metadata (id, mal_id, romaji_title, english_title, season, season_year, format, description, popularity, mean_score, is_adult)
+    ";
+    let mut query = sqlx::QueryBuilder::new(insert_sql);
+    query.push_values(&data, |mut b, anime| {
+        b.push_bind(anime.id)
+            .push_bind(anime.mal_id)
+            .push_bind(anime.title.romaji.clone())
+            .push_bind(anime.title.english.clone())
+            .push_bind(anime.season.clone())
+            .push_bind(anime.season_year)
+            .push_bind(anime.format.clone())
+            .push_bind(anime.description.clone())
+            .push_bind(anime.popularity)
+            .push_bind(anime.mean_score)
+            .push_bind(anime.is_adult);
+    });
+    query.push("
+        ON CONFLICT (id) DO UPDATE SET
+            mal_id = excluded.mal_id,
+            romaji_title = excluded.romaji_title,
+            english_title = excluded.english_title,
+            season = excluded.season,
+            season_year = excluded.season_year,
+            format = excluded.format,
+            description = excluded.description,
+            popularity = excluded.popularity,
+            mean_score = excluded.mean_score,
+            is_adult = excluded.is_adult
+    ");
+    query.build().execute(&mut *tx).await?;
+
+    for anime in &data {
+        sqlx::query("DELETE FROM media_genre WHERE media_id = ?;").bind(anime.id).execute(&mut *tx).await?;
+        for genre in anime.genres.iter().flatten() {
+            sqlx::query("INSERT OR IGNORE INTO genre (name) VALUES (?);").bind(genre).execute(&mut *tx).await?;
+            sqlx::query("INSERT OR IGNORE INTO media_genre (media_id, genre_id) SELECT ?, id FROM genre WHERE name = ?;")
+                .bind(anime.id)
+                .bind(genre)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM media_tag WHERE media_id = ?;").bind(anime.id).execute(&mut *tx).await?;
+        for tag in anime.tags.iter().flatten() {
+            sqlx::query("
+                INSERT INTO tag (id, name, category, is_adult) VALUES (?, ?, ?, ?)
+                ON CONFLICT (id) DO UPDATE SET
+                    name = excluded.name,
+                    category = excluded.category,
+                    is_adult = excluded.is_adult;
+                ")
+                .bind(tag.id)
+                .bind(&tag.name)
+                .bind(&tag.category)
+                .bind(tag.is_adult.unwrap_or(false))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("INSERT OR REPLACE INTO media_tag (media_id, tag_id, rank) VALUES (?, ?, ?);")
+                .bind(anime.id)
+                .bind(tag.id)
+                .bind(tag.rank)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Media loaded without relations keep the stored ones.
+        if let Some(relations) = &anime.relations {
+            sqlx::query("DELETE FROM media_relation WHERE media_id = ?;").bind(anime.id).execute(&mut *tx).await?;
+            for relation in relations {
+                sqlx::query("
+                    INSERT INTO media_relation (media_id, related_id, relation_type) VALUES (?, ?, ?)
+                    ON CONFLICT (media_id, related_id) DO UPDATE SET relation_type = excluded.relation_type;
+                    ")
+                    .bind(anime.id)
+                    .bind(relation.id)
+                    .bind(&relation.relation_type)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn upsert_summary(pool: &SqlitePool, data: AnimeSummary) -> Result<()> {
+    let insert_sql = "
+        INSERT INTO anime_summary (id, summary, generated_genres, generated_themes)
+    ";
+    let mut query = sqlx::QueryBuilder::new(insert_sql);
+    query.push_values(vec![data], |mut b, anime| {
+        b.push_bind(anime.id)
+            .push_bind(anime.generated_summary.summary)
+            .push_bind(anime.generated_summary.generated_genres.join(","))
+            .push_bind(anime.generated_summary.generated_themes.join(","));
+    });
+    // Not INSERT OR REPLACE, which would skip the keyword index's delete
+    // trigger.
+    query.push("
+        ON CONFLICT (id) DO UPDATE SET
+            summary = excluded.summary,
+            generated_genres = excluded.generated_genres,
+            generated_themes = excluded.generated_themes
+    ");
+    query.build().execute(pool).await?;
+    Ok(())
+}
+
+pub async fn season_year_range(pool: &SqlitePool) -> Result<Option<(i32, i32)>> {
+    let years: Years = sqlx::query_as("SELECT MAX(season_year) AS max_year, MIN(season_year) AS min_year FROM anime_metadata;")
+        .fetch_one(pool)
+        .await?;
+    Ok(years.min_year.zip(years.max_year))
+}
+
+pub async fn pending_summary_count(pool: &SqlitePool) -> Result<i64> {
+    sqlx::query_scalar("
+        SELECT COUNT(*) FROM anime_metadata m
+        WHERE m.description <> ''
+            AND m.description IS NOT NULL
+            AND m.season_year IS NOT NULL
+            AND m.id NOT IN (
+                SELECT id FROM anime_summary
+            );
+        ").fetch_one(pool).await
+}
+
+pub async fn pending_summaries(pool: &SqlitePool, season_year: i32) -> Result<Vec<AnimeMetadata>> {
+    let rows: Vec<AnimeMetadataRow> = sqlx::query_as("
+        SELECT m.id, m.mal_id, m.english_title, m.romaji_title, m.season, m.season_year, m.format, m.description, m.popularity, m.mean_score, m.is_adult
+        FROM anime_metadata m
+        WHERE m.description <> ''
+            AND m.description IS NOT NULL
+            AND m.season_year = ?
+            AND m.id NOT IN (
+                SELECT id FROM anime_summary
+            );
+        ").bind(season_year).fetch_all(pool).await?;
+    with_genres_and_tags(pool, rows).await
+}
+
+/// Attaches genres and tags from the join tables to metadata rows.
+pub async fn with_genres_and_tags(pool: &SqlitePool, rows: Vec<AnimeMetadataRow>) -> Result<Vec<AnimeMetadata>> {
+    let ids: Vec<i32> = rows.iter().map(|row| row.id).collect();
+    let mut genres = genres_for(pool, &ids).await?;
+    let mut tags = tags_for(pool, &ids).await?;
+    Ok(rows.into_iter().map(|row| {
+        let id = row.id;
+        row.into_metadata(genres.remove(&id).unwrap_or_default(), tags.remove(&id).unwrap_or_default())
+    }).collect())
+}
+
+// Ids are passed as one JSON array so the number of bound parameters does not
+// grow with the number of rows.
+fn json_ids(ids: &[i32]) -> String {
+    serde_json::to_string(ids).unwrap()
+}
+
+pub async fn genres_for(pool: &SqlitePool, ids: &[i32]) -> Result<HashMap<i32, Vec<String>>> {
+    let rows: Vec<(i32, String)> = sqlx::query_as("
+        SELECT mg.media_id, g.name FROM media_genre mg
+        JOIN genre g ON g.id = mg.genre_id
+        WHERE mg.media_id IN (SELECT value FROM json_each(?))
+        ORDER BY mg.media_id, g.name;
+        ").bind(json_ids(ids)).fetch_all(pool).await?;
+    let mut genres: HashMap<i32, Vec<String>> = HashMap::new();
+    for (media_id, name) in rows {
+        genres.entry(media_id).or_default().push(name);
+    }
+    Ok(genres)
+}
+
+pub async fn tags_for(pool: &SqlitePool, ids: &[i32]) -> Result<HashMap<i32, Vec<MediaTag>>> {
+    let rows: Vec<MediaTagRow> = sqlx::query_as("
+        SELECT mt.media_id, t.id, t.name, t.category, mt.rank, t.is_adult FROM media_tag mt
+        JOIN tag t ON t.id = mt.tag_id
+        WHERE mt.media_id IN (SELECT value FROM json_each(?))
+        ORDER BY mt.media_id, mt.rank DESC;
+        ").bind(json_ids(ids)).fetch_all(pool).await?;
+    let mut tags: HashMap<i32, Vec<MediaTag>> = HashMap::new();
+    for row in rows {
+        tags.entry(row.media_id).or_default().push(row.into());
+    }
+    Ok(tags)
+}
+
+pub async fn media(pool: &SqlitePool, ids: &[i32]) -> Result<Vec<AnimeMetadata>> {
+    // The columns are named rather than `*`: right after a migration added
+    // one, SQLite may prepare `*` against the old schema and only re-prepare
+    // when stepping, and sqlx then reads the rows one column short.
+    let rows: Vec<AnimeMetadataRow> = sqlx::query_as("
+        SELECT id, mal_id, english_title, romaji_title, season, season_year, format, description, popularity, mean_score, is_adult
+        FROM anime_metadata
+        WHERE id IN (SELECT value FROM json_each(?));
+        ").bind(json_ids(ids)).fetch_all(pool).await?;
+    with_genres_and_tags(pool, rows).await
+}
+
+pub async fn summaries(pool: &SqlitePool, ids: &[i32]) -> Result<Vec<AnimeSummary>> {
+    let rows: Vec<AnimeSummaryRow> = sqlx::query_as("
+        SELECT id, summary, generated_genres, generated_themes
+        FROM anime_summary
+        WHERE id IN (SELECT value FROM json_each(?));
+        ").bind(json_ids(ids)).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(AnimeSummaryRow::into_summary).collect())
+}
+
+/// Appends the conditions of a search filter on `anime_metadata m`.
+fn push_filter(query: &mut sqlx::QueryBuilder<sqlx::Sqlite>, filter: &SearchFilter) {
+    if let Some(min_year) = filter.min_year {
+        query.push(" AND m.season_year >= ").push_bind(min_year);
+    }
+    if let Some(max_year) = filter.max_year {
+        query.push(" AND m.season_year <= ").push_bind(max_year);
+    }
+    if !filter.seasons.is_empty() {
+        push_in(query, "m.season", filter.seasons.iter().map(|season| season.as_str()));
+    }
+    if !filter.formats.is_empty() {
+        push_in(query, "m.format", filter.formats.iter().map(|format| format.as_str()));
+    }
+    if let Some(min_mean_score) = filter.min_mean_score {
+        query.push(" AND m.mean_score >= ").push_bind(min_mean_score);
+    }
+    if let Some(min_popularity) = filter.min_popularity {
+        query.push(" AND m.popularity >= ").push_bind(min_popularity);
+    }
+    let genres = filter.include_genres.iter().map(|genre| (genre, true))
+        .chain(filter.exclude_genres.iter().map(|genre| (genre, false)));
+    for (genre, include) in genres {
+        query.push(if include { " AND EXISTS" } else { " AND NOT EXISTS" })
+            .push(" (SELECT 1 FROM media_genre mg JOIN genre g ON g.id = mg.genre_id WHERE mg.media_id = m.id AND g.name = ")
+            .push_bind(genre.clone())
+            .push(" COLLATE NOCASE)");
+    }
+    // Generated themes are stored comma-separated.
+    let themes = filter.include_themes.iter().map(|theme| (theme, true))
+        .chain(filter.exclude_themes.iter().map(|theme| (theme, false)));
+    for (theme, include) in themes {
+        query.push(if include { " AND EXISTS" } else { " AND NOT EXISTS" })
+            .push(" (SELECT 1 FROM anime_summary s WHERE s.id = m.id AND instr(',' || lower(replace(s.generated_themes, ', ', ',')) || ',', ',' || lower(")
+            .push_bind(theme.clone())
+            .push(") || ',') > 0)");
+    }
+    if let Some(user_name) = &filter.exclude_seen_by {
+        query.push(" AND NOT EXISTS (SELECT 1 FROM user_list ul WHERE ul.media_id = m.id AND ul.status <> 'PLANNING' AND ul.user_name = ")
+            .push_bind(user_name.clone())
+            .push(")");
+    }
+}
+
+fn push_in<'a>(query: &mut sqlx::QueryBuilder<sqlx::Sqlite>, column: &str, values: impl Iterator<Item = &'a str>) {
+    query.push(format!(" AND {} IN (", column));
+    let mut separated = query.separated(", ");
+    for value in values {
+        separated.push_bind(value.to_string());
+    }
+    separated.push_unseparated(")");
+}
+
+pub async fn filtered_media_ids(pool: &SqlitePool, filter: &SearchFilter) -> Result<Vec<i32>> {
+    let mut query: sqlx::QueryBuilder<sqlx::Sqlite> = sqlx::QueryBuilder::new("SELECT m.id FROM anime_metadata m WHERE 1 = 1");
+    push_filter(&mut query, filter);
+    query.push(" ORDER BY m.id");
+    query.build_query_scalar().fetch_all(pool).await
+}
+
+/// Ranks media by BM25 over their titles and description and their generated
+/// summary and themes, title matches weighing the most. `terms` are ORed, so
+/// media matching more of them rank higher.
+pub async fn keyword_search(pool: &SqlitePool, terms: &[String], filter: &SearchFilter, limit: i64) -> Result<Vec<(i32, f32)>> {
+    let expression = terms.iter()
+        .map(|term| format!("\"{}\"", term.replace('"', "")))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let mut query: sqlx::QueryBuilder<sqlx::Sqlite> = sqlx::QueryBuilder::new("
+        WITH matches AS (
+            SELECT rowid AS id, bm25(anime_metadata_fts, 10.0, 10.0, 1.0) AS rank
+            FROM anime_metadata_fts WHERE anime_metadata_fts MATCH ");
+    query.push_bind(expression.clone());
+    query.push("
+            UNION ALL
+            SELECT rowid AS id, bm25(anime_summary_fts, 1.0, 2.0) AS rank
+            FROM anime_summary_fts WHERE anime_summary_fts MATCH ");
+    query.push_bind(expression);
+    // bm25() is lower for better matches.
+    query.push("
+        )
+        SELECT m.id, -SUM(x.rank) AS score FROM matches x
+        JOIN anime_metadata m ON m.id = x.id
+        WHERE 1 = 1");
+    push_filter(&mut query, filter);
+    query.push(" GROUP BY m.id ORDER BY score DESC, m.id LIMIT ").push_bind(limit);
+    let rows: Vec<(i32, f64)> = query.build_query_as().fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, score)| (id, score as f32)).collect())
+}
+
+
+pub async fn media_ids_with_genre(pool: &SqlitePool, genre: &str) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        SELECT mg.media_id FROM media_genre mg
+        JOIN genre g ON g.id = mg.genre_id
+        WHERE g.name = ? COLLATE NOCASE
+        ORDER BY mg.media_id;
+        ").bind(genre).fetch_all(pool).await
+}
+
+pub async fn media_ids_with_tag(pool: &SqlitePool, tag: &str, min_rank: i32) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        SELECT mt.media_id FROM media_tag mt
+        JOIN tag t ON t.id = mt.tag_id
+        WHERE t.name = ? COLLATE NOCASE AND COALESCE(mt.rank, 0) >= ?
+        ORDER BY mt.media_id;
+        ").bind(tag).bind(min_rank).fetch_all(pool).await
+}
+
+pub async fn genre_counts(pool: &SqlitePool) -> Result<Vec<(String, i64)>> {
+    sqlx::query_as("
+        SELECT g.name, COUNT(mg.media_id) FROM genre g
+        LEFT JOIN media_genre mg ON mg.genre_id = g.id
+        GROUP BY g.id
+        ORDER BY g.name;
+        ").fetch_all(pool).await
+}
+
+pub async fn row_counts(pool: &SqlitePool) -> Result<RowCounts> {
+    sqlx::query_as("
+        SELECT
+            (SELECT COUNT(*) FROM anime_metadata) AS media,
+            (SELECT COUNT(*) FROM anime_summary) AS summaries,
+            (SELECT COUNT(DISTINCT user_name) FROM user_list) AS list_users,
+            (SELECT COUNT(*) FROM user_list) AS list_entries;
+        ").fetch_one(pool).await
+}
+
+pub async fn theme_counts(pool: &SqlitePool) -> Result<Vec<(String, i64)>> {
+    // Generated themes are stored comma-separated; split them recursively.
+    sqlx::query_as("
+        WITH RECURSIVE split (id, theme, rest) AS (
+            SELECT id, '', generated_themes || ',' FROM anime_summary
+            UNION ALL
+            SELECT id, trim(substr(rest, 1, instr(rest, ',') - 1)), substr(rest, instr(rest, ',') + 1)
+            FROM split WHERE rest <> ''
+        )
+        SELECT min(theme), COUNT(DISTINCT id) FROM split
+        WHERE theme <> ''
+        GROUP BY lower(theme)
+        ORDER BY lower(theme);
+        ").fetch_all(pool).await
+}
+
+pub async fn franchise(pool: &SqlitePool, id: i32) -> Result<Vec<i32>> {
+    sqlx::query_scalar("
+        WITH RECURSIVE
+            edge (a, b) AS (
+                SELECT media_id, related_id FROM media_relation WHERE relation_type IN ('PREQUEL', 'SEQUEL', 'PARENT', 'SIDE_STORY', 'SPIN_OFF', 'ALTERNATIVE', 'SUMMARY', 'COMPILATION', 'CONTAINS')
+                UNION ALL
+                SELECT related_id, media_id FROM media_relation WHERE relation_type IN ('PREQUEL', 'SEQUEL', 'PARENT', 'SIDE_STORY', 'SPIN_OFF', 'ALTERNATIVE', 'SUMMARY', 'COMPILATION', 'CONTAINS')
+            ),
+            franchise (id) AS (
+                SELECT ?
+                UNION
+                SELECT edge.b FROM edge JOIN franchise ON edge.a = franchise.id
+            )
+        SELECT id FROM franchise ORDER BY id;
+        ")
+        .bind(id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn media_ids_for_mal(pool: &SqlitePool, mal_ids: &[i32]) -> Result<Vec<(i32, i32)>> {
+    sqlx::query_as("SELECT mal_id, id FROM anime_metadata WHERE mal_id IN (SELECT value FROM json_each(?));")
+        .bind(json_ids(mal_ids))
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn replace_user_list(pool: &SqlitePool, user_name: &str, source: ListSource, entries: &[UserListEntry]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM user_list WHERE user_name = ? AND source = ?;")
+        .bind(user_name)
+        .bind(source.as_str())
+        .execute(&mut *tx)
+        .await?;
+    for entry in entries {
+        sqlx::query("
+            INSERT INTO user_list (user_name, media_id, status, score, progress, source) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (user_name, media_id) DO UPDATE SET
+                status = excluded.status,
+                score = excluded.score,
+                progress = excluded.progress,
+                source = excluded.source,
+                updated_at = CURRENT_TIMESTAMP;
+            ")
+            .bind(user_name)
+            .bind(entry.media_id)
+            .bind(entry.status.as_str())
+            .bind(entry.score)
+            .bind(entry.progress)
+            .bind(source.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn user_list(pool: &SqlitePool, user_name: &str) -> Result<Vec<UserListEntry>> {
+    let rows: Vec<UserListEntryRow> = sqlx::query_as("
+        SELECT user_name, media_id, status, score, progress, source FROM user_list
+        WHERE user_name = ?
+        ORDER BY media_id;
+        ").bind(user_name).fetch_all(pool).await?;
+    rows.into_iter()
+        .map(|row| row.into_entry().map_err(|e| sqlx::Error::Decode(e.into())))
+        .collect()
+}
+
+pub async fn cached_rerank(pool: &SqlitePool, query: &str, model: &str, candidates: &str) -> Result<Option<String>> {
+    sqlx::query_scalar("SELECT ranking FROM rerank_cache WHERE query = ? AND model = ? AND candidates = ?;")
+        .bind(query)
+        .bind(model)
+        .bind(candidates)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn store_rerank(pool: &SqlitePool, query: &str, model: &str, candidates: &str, ranking: &str) -> Result<()> {
+    sqlx::query("
+        INSERT INTO rerank_cache (query, model, candidates, ranking) VALUES (?, ?, ?, ?)
+        ON CONFLICT (query, model, candidates) DO UPDATE SET ranking = excluded.ranking, created_at = CURRENT_TIMESTAMP;
+        ")
+        .bind(query)
+        .bind(model)
+        .bind(candidates)
+        .bind(ranking)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+pub fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+pub async fn upsert_embeddings(pool: &SqlitePool, data: Vec<AnimeEmbedding>) -> Result<()> {
+    let insert_sql = "
+        INSERT INTO anime_embedding (id, model, source, dimension, embedding)
+    ";
+    let mut tx = pool.begin().await?;
+    for chunk in data.chunks(INSERT_CHUNK_SIZE) {
+        let mut query = sqlx::QueryBuilder::new(insert_sql);
+        query.push_values(chunk, |mut b, embedding| {
+            b.push_bind(embedding.id)
+                .push_bind(embedding.model.clone())
+                .push_bind(embedding.source.as_str())
+                .push_bind(embedding.embedding.len() as i32)
+                .push_bind(to_blob(&embedding.embedding));
+        });
+        query.push("
+            ON CONFLICT (id, model, source) DO UPDATE SET
+                dimension = excluded.dimension,
+                embedding = excluded.embedding,
+                created_at = CURRENT_TIMESTAMP
+        ");
+        query.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn texts_without_embedding(pool: &SqlitePool, model: &str, source: EmbeddingSource, after_id: i32, limit: i64) -> Result<Vec<(i32, String)>> {
+    let sql = match source {
+        EmbeddingSource::Summary => "
+            SELECT s.id, s.summary FROM anime_summary s
+            JOIN anime_metadata m ON m.id = s.id
+            WHERE s.id > ?
+                AND s.summary IS NOT NULL
+                AND s.summary <> ''
+                AND NOT EXISTS (
+                    SELECT 1 FROM anime_embedding e WHERE e.id = s.id AND e.model = ? AND e.source = ?
+                )
+            ORDER BY s.id
+            LIMIT ?;
+        ",
+        EmbeddingSource::Description => "
+            SELECT m.id, m.description FROM anime_metadata m
+            WHERE m.id > ?
+                AND m.description IS NOT NULL
+                AND m.description <> ''
+                AND NOT EXISTS (
+                    SELECT 1 FROM anime_embedding e WHERE e.id = m.id AND e.model = ? AND e.source = ?
+                )
+            ORDER BY m.id
+            LIMIT ?;
+        ",
+    };
+    sqlx::query_as(sql)
+        .bind(after_id)
+        .bind(model)
+        .bind(source.as_str())
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn embedding_ids(pool: &SqlitePool, model: &str, source: EmbeddingSource) -> Result<Vec<i32>> {
+    sqlx::query_scalar("SELECT id FROM anime_embedding WHERE model = ? AND source = ? ORDER BY id;")
+        .bind(model)
+        .bind(source.as_str())
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn embedding_dimension(pool: &SqlitePool, model: &str, source: EmbeddingSource) -> Result<Option<i32>> {
+    sqlx::query_scalar("SELECT dimension FROM anime_embedding WHERE model = ? AND source = ? LIMIT 1;")
+        .bind(model)
+        .bind(source.as_str())
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn embeddings(pool: &SqlitePool, model: &str, source: EmbeddingSource) -> Result<Vec<AnimeEmbedding>> {
+    let rows: Vec<(i32, Vec<u8>)> = sqlx::query_as("
+        SELECT id, embedding FROM anime_embedding
+        WHERE model = ? AND source = ?
+        ORDER BY id;
+        ").bind(model).bind(source.as_str()).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, blob)| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding: from_blob(&blob),
+    }).collect())
+}
+
+pub async fn embeddings_since(pool: &SqlitePool, model: &str, source: EmbeddingSource, since: &str) -> Result<Vec<AnimeEmbedding>> {
+    let rows: Vec<(i32, Vec<u8>)> = sqlx::query_as("
+        SELECT id, embedding FROM anime_embedding
+        WHERE model = ? AND source = ? AND created_at >= ?
+        ORDER BY id;
+        ").bind(model).bind(source.as_str()).bind(since).fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id, blob)| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding: from_blob(&blob),
+    }).collect())
+}
+
+pub async fn embedding(pool: &SqlitePool, id: i32, model: &str, source: EmbeddingSource) -> Result<Option<AnimeEmbedding>> {
+    let blob: Option<Vec<u8>> = sqlx::query_scalar("
+        SELECT embedding FROM anime_embedding
+        WHERE id = ? AND model = ? AND source = ?;
+        ").bind(id).bind(model).bind(source.as_str()).fetch_optional(pool).await?;
+    Ok(blob.map(|blob| AnimeEmbedding {
+        id,
+        model: model.to_string(),
+        source,
+        embedding: from_blob(&blob),
+    }))
+}
+
+pub async fn embedding_sets(pool: &SqlitePool) -> Result<Vec<EmbeddingSetInfo>> {
+    sqlx::query_as("
+        SELECT model, source, MAX(dimension) AS dimension, COUNT(*) AS count, MAX(created_at) AS last_created_at
+        FROM anime_embedding
+        GROUP BY model, source
+        ORDER BY model, source;
+        ").fetch_all(pool).await
+}
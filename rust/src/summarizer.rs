@@ -1,177 +1,133 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use reqwest::{Client, Error};
-use serde_json::json;
-use tokio::time::sleep;
+use serde::Deserialize;
 use tokio::sync::mpsc;
+use tracing::{debug, info, info_span, warn, Instrument};
 
+use crate::config::LlmConfig;
+use crate::llm::{ChatMessage, LlmClient};
+use crate::metrics::metrics;
+use crate::progress::Progress;
 use crate::types::{AnimeMetadata, AnimeSummary};
 
+/// The summarization requests, set in the `[summarize]` table of the config
+/// file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SummarizeOptions {
+    pub temperature: f32,
+    pub max_tokens: u32,
+    /// Attempts at a media before it is skipped.
+    pub max_attempts: u32,
+    /// Pause after a failed request. Rate limited requests wait for as long
+    /// as the backend asks instead.
+    pub retry_delay_secs: u64,
+}
+
+impl Default for SummarizeOptions {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            max_tokens: 1024,
+            max_attempts: 3,
+            retry_delay_secs: 10,
+        }
+    }
+}
+
+const INSTRUCTIONS: &str = "You are an expert in animes. Given the title of an anime and a description, generate a 2 sentence summary as well as some related keywords such as themes and genres.\n\nUse the following output format in json:\n\n{\n  \"summary\": \"summary of the anime\",\n  \"themes\": [\"theme1\", \"theme2\"],\n  \"genres\": [\"genre1\", \"genre2\"]\n}";
+
 pub struct Summarizer {
     receiver: mpsc::Receiver<Option<AnimeMetadata>>,
     sender: mpsc::Sender<Option<AnimeSummary>>,
     ready_sender: mpsc::Sender<usize>,
     idx: usize,
-    url: String,
-    api_key: String,
+    llm: LlmClient,
+    progress: Arc<Progress>,
 }
 
 impl Summarizer {
+    /// A summarizer sending its requests with the configured API key at
+    /// `idx`, sampled and retried as `options` say.
     pub fn new(
         receiver: mpsc::Receiver<Option<AnimeMetadata>>,
         sender: mpsc::Sender<Option<AnimeSummary>>,
         ready_sender: mpsc::Sender<usize>,
         idx: usize,
-        api_key: String,
+        llm: &LlmConfig,
+        options: &SummarizeOptions,
+        progress: Arc<Progress>,
     ) -> Self {
-        let url = "https://api.groq.com/openai/v1/chat/completions";
+        let llm = LlmClient::with_key(llm, idx)
+            .with_sampling(options.temperature, options.max_tokens)
+            .with_retries(options.max_attempts, Duration::from_secs(options.retry_delay_secs));
         Self {
             receiver,
             sender,
             ready_sender,
             idx,
-            url: url.to_string(),
-            api_key: api_key,
+            llm,
+            progress,
         }
     }
 
-    // Vec<Summarizer>
-    // Vec<Receiver>
-    // db_query has Vec<Sender>
-    // each summarizer sends (idx) -> db_query sends AnimeMetadata to senders[idx]
-
-    pub async fn start_summarize_job(
-        &mut self,
-    ) -> Result<bool, Error> {
+    /// Summarizes the media sent by the query until it sends `None`, which
+    /// returns true, or drops its sender, which returns false. Media that
+    /// cannot be summarized are logged and skipped.
+    pub async fn start_summarize_job(&mut self) -> bool {
         loop {
             let _ = self.ready_sender.send(self.idx).await;
-            println!("Summarizer {} is ready!", self.idx);
+            debug!("Ready");
 
             match self.receiver.recv().await {
-                Some(maybe_data) => {
-                    match maybe_data {
-                        Some(data) => {
-                            println!("Summarizer {} has received data", self.idx);
-                            // sleep(Duration::from_secs(((self.idx + 1) * 5).try_into().unwrap())).await;
-                            let anime_id = data.id;
-                            let response: Result<Option<AnimeSummary>, Error> = Self::summarize_anime(&self.url, &self.api_key, data).await.map(|response| Self::parse_response(response, anime_id));
-                            if response.is_err() {
-                                println!("Summarize error: {:?}", response.unwrap_err());
-                                continue;
-                            }
-                            let maybe_data = response.unwrap();
-                            if maybe_data.is_none() {
-                                println!("Got none after summarizing anime");
-                                continue;
-                            }
-                            let send_result = self.sender.send(maybe_data).await;
-                            if send_result.is_err() {
-                                println!("Summary send error: {:?}", send_result.unwrap_err());
-                            }
-                        },
-                        None => {
-                            println!("Finished receiving metadata, ending summarizer job");
-                            let _ = self.sender.send(None).await;
-                            println!("Finished sending anime summaries");
-                            return Ok(true);
-                        },
-                    }
-                },
-                None => return Ok(false),
-            };
-        }
-    }
-
-    async fn summarize_anime(
-        url: &String,
-        api_key: &String,
-        anime: AnimeMetadata
-    ) -> Result<serde_json::Value, Error> {
-        let mut retry = -1;
-        loop {
-            retry += 1;
-            if retry == 3 {
-                println!("Tried 3 times, skip to next request...");
-                return Ok(serde_json::Value::Null);
-            }
-            let payload = json!({
-                "messages": [
-                    {
-                        "role": "system",
-                        "content": "You are an expert in animes. Given the title of an anime and a description, generate a 2 sentence summary as well as some related keywords such as themes and genres.\n\nUse the following output format in json:\n\n{\n  \"summary\": \"summary of the anime\",\n  \"themes\": [\"theme1\", \"theme2\"],\n  \"genres\": [\"genre1\", \"genre2\"]\n}"
-                    },
-                    {
-                        "role": "user",
-                        "content": format!("Title: {}\nDescription: {}", anime.title.english.clone().or(anime.title.romaji.clone()).unwrap_or_default(), anime.description.clone().unwrap_or_default()),
+                Some(Some(data)) => {
+                    let span = info_span!("media", id = data.id);
+                    let summary = self.summarize_anime(&data).instrument(span.clone()).await;
+                    self.progress.inc(1);
+                    let Some(summary) = summary else {
+                        continue;
+                    };
+                    debug!(parent: &span, "Summarized");
+                    match self.sender.send(Some(summary)).await {
+                        Ok(()) => metrics().summaries_produced.inc(),
+                        Err(e) => warn!(parent: &span, error = %e, "Could not send the summary to the loader"),
                     }
-                ],
-                "model": "llama-3.3-70b-versatile",
-                "temperature": 1,
-                "max_tokens": 1024,
-                "top_p": 1,
-                "stream": false,
-                "response_format": {
-                    "type": "json_object"
-                },
-                "stop": null
-            });
-
-            let client = Client::new();
-            let response = client.post(url)
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Bearer {}", api_key))
-                .body(payload.to_string())
-                .send()
-                .await
-                .unwrap();
-
-            if response.status() == 429 {
-                let retry_after = response.headers()
-                    .get("retry-after")
-                    .map(|x| x.to_str())
-                    .unwrap_or(Ok("10"))
-                    .unwrap()
-                    .parse::<i32>()
-                    .unwrap();
-                println!("Hit limit, sleeping for {} seconds", retry_after);
-                sleep(Duration::from_secs(retry_after.try_into().unwrap())).await;
-                continue;
-            }
-
-            if response.status() != 200 {
-                println!("Got the following: {}", response.status());
-                println!("Sleeping for 10 seconds");
-                sleep(Duration::from_secs(10)).await;
-                continue;
+                }
+                Some(None) => {
+                    info!("Finished summarizing");
+                    return true;
+                }
+                None => return false,
             }
-            return response.text()
-                .await
-                .map(|response| {
-                    serde_json::from_str(&response).unwrap()
-                });
         }
     }
 
-    fn parse_response(
-        data: serde_json::Value,
-        anime_id: i32,
-    ) -> Option<AnimeSummary> {
-        if data.is_null() {
-            return None;
-        }
-        // println!("{:?}", data["choices"][0]["message"]["content"].as_str().clone());
-        let anime_summary = match serde_json::from_str(data["choices"][0]["message"]["content"].as_str().clone().unwrap()) {
-            Ok(summary) => Some(summary),
+    async fn summarize_anime(&self, anime: &AnimeMetadata) -> Option<AnimeSummary> {
+        let title = anime.title.english.as_ref().or(anime.title.romaji.as_ref());
+        let messages = [
+            ChatMessage::system(INSTRUCTIONS),
+            ChatMessage::user(format!(
+                "Title: {}\nDescription: {}",
+                title.map_or("", String::as_str),
+                anime.description.as_deref().unwrap_or_default(),
+            )),
+        ];
+        let content = match self.llm.chat(&messages, true).await {
+            Ok(content) => content,
             Err(e) => {
-                println!("Error: {}", e);
-                None
+                metrics().summary_request_failures.inc();
+                warn!(error = %e, "Giving up on this media");
+                return None;
             }
         };
-        anime_summary.map(|generated_summary| {
-            AnimeSummary {
-                id: anime_id,
-                generated_summary: generated_summary
+        match serde_json::from_str(&content) {
+            Ok(generated_summary) => Some(AnimeSummary { id: anime.id, generated_summary }),
+            Err(e) => {
+                metrics().summary_parse_failures.inc();
+                warn!(error = %e, "Could not parse the generated summary");
+                None
             }
-        })
+        }
     }
 }
@@ -9,11 +9,15 @@ pub struct Title {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AnimeMetadata {
     pub id: i32,
+
+    #[serde(rename = "idMal")]
+    pub mal_id: Option<i32>,
     pub title: Title,
     pub season: Option<String>,
 
     #[serde(rename = "seasonYear")]
     pub season_year: i32,
+    pub format: Option<String>,
     pub description: Option<String>,
     pub popularity: Option<i32>,
 
@@ -21,22 +25,191 @@ pub struct AnimeMetadata {
     pub mean_score: Option<i32>,
 
     pub genres: Option<Vec<String>>,
+    pub tags: Option<Vec<MediaTag>>,
+
+    #[serde(rename = "isAdult")]
+    pub is_adult: Option<bool>,
+
+    /// Related anime. Only set on media fresh from AniList; stored relations
+    /// are read with `Storage::franchise`.
+    #[serde(default, deserialize_with = "anime_relations", skip_serializing)]
+    pub relations: Option<Vec<MediaRelation>>,
+}
+
+/// A relation from one anime to another, e.g. a `SEQUEL` or `SIDE_STORY`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRelation {
+    pub id: i32,
+    /// AniList's `MediaRelation`.
+    pub relation_type: String,
+}
+
+/// Reads AniList's `relations { edges { relationType node { id type } } }`,
+/// keeping the relations to anime only.
+fn anime_relations<'de, D>(deserializer: D) -> Result<Option<Vec<MediaRelation>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Connection {
+        edges: Vec<Edge>,
+    }
+    #[derive(Deserialize)]
+    struct Edge {
+        #[serde(rename = "relationType")]
+        relation_type: Option<String>,
+        node: Option<Node>,
+    }
+    #[derive(Deserialize)]
+    struct Node {
+        id: i32,
+        #[serde(rename = "type")]
+        media_type: Option<String>,
+    }
+
+    let connection: Option<Connection> = Option::deserialize(deserializer)?;
+    Ok(connection.map(|connection| {
+        connection.edges
+            .into_iter()
+            .filter_map(|edge| match (edge.relation_type, edge.node) {
+                (Some(relation_type), Some(node)) if node.media_type.as_deref() == Some("ANIME") => {
+                    Some(MediaRelation { id: node.id, relation_type })
+                }
+                _ => None,
+            })
+            .collect()
+    }))
+}
+
+/// AniList's `MediaSeason`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Season {
+    Winter,
+    Spring,
+    Summer,
+    Fall,
+}
+
+impl Season {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Season::Winter => "WINTER",
+            Season::Spring => "SPRING",
+            Season::Summer => "SUMMER",
+            Season::Fall => "FALL",
+        }
+    }
+}
+
+impl std::str::FromStr for Season {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "WINTER" => Ok(Season::Winter),
+            "SPRING" => Ok(Season::Spring),
+            "SUMMER" => Ok(Season::Summer),
+            "FALL" | "AUTUMN" => Ok(Season::Fall),
+            _ => Err(format!("unknown season {:?}, expected winter, spring, summer or fall", s)),
+        }
+    }
+}
+
+/// AniList's `MediaFormat`, limited to the anime formats.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MediaFormat {
+    Tv,
+    TvShort,
+    Movie,
+    Special,
+    Ova,
+    Ona,
+    Music,
+}
+
+impl MediaFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaFormat::Tv => "TV",
+            MediaFormat::TvShort => "TV_SHORT",
+            MediaFormat::Movie => "MOVIE",
+            MediaFormat::Special => "SPECIAL",
+            MediaFormat::Ova => "OVA",
+            MediaFormat::Ona => "ONA",
+            MediaFormat::Music => "MUSIC",
+        }
+    }
+}
+
+impl std::str::FromStr for MediaFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().replace('-', "_").as_str() {
+            "TV" => Ok(MediaFormat::Tv),
+            "TV_SHORT" => Ok(MediaFormat::TvShort),
+            "MOVIE" => Ok(MediaFormat::Movie),
+            "SPECIAL" => Ok(MediaFormat::Special),
+            "OVA" => Ok(MediaFormat::Ova),
+            "ONA" => Ok(MediaFormat::Ona),
+            "MUSIC" => Ok(MediaFormat::Music),
+            _ => Err(format!("unknown format {:?}, expected tv, tv_short, movie, special, ova, ona or music", s)),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MediaTag {
+    pub id: i32,
+    pub name: String,
+    pub category: Option<String>,
+    pub rank: Option<i32>,
+
+    #[serde(rename = "isAdult")]
+    pub is_adult: Option<bool>,
 }
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct AnimeMetadataRow {
     pub id: i32,
+    pub mal_id: Option<i32>,
     pub english_title: Option<String>,
     pub romaji_title: Option<String>,
     pub season: Option<String>,
 
     pub season_year: i32,
+    pub format: Option<String>,
     pub description: Option<String>,
     pub popularity: Option<i32>,
 
     pub mean_score: Option<i32>,
 
-    pub genres: Option<String>,
+    pub is_adult: Option<bool>,
+}
+
+impl AnimeMetadataRow {
+    pub fn into_metadata(self, genres: Vec<String>, tags: Vec<MediaTag>) -> AnimeMetadata {
+        AnimeMetadata {
+            id: self.id,
+            mal_id: self.mal_id,
+            title: Title {
+                romaji: self.romaji_title,
+                english: self.english_title,
+            },
+            season: self.season,
+            season_year: self.season_year,
+            format: self.format,
+            description: self.description,
+            popularity: self.popularity,
+            mean_score: self.mean_score,
+            genres: Some(genres),
+            tags: Some(tags),
+            is_adult: self.is_adult,
+            relations: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -53,3 +226,228 @@ pub struct AnimeSummary {
     pub id: i32,
     pub generated_summary: AnimeGeneratedSummary
 }
+
+/// `anime_summary` stores the generated genres and themes comma-separated.
+#[derive(sqlx::FromRow, Debug)]
+pub struct AnimeSummaryRow {
+    pub id: i32,
+    pub summary: Option<String>,
+    pub generated_genres: Option<String>,
+    pub generated_themes: Option<String>,
+}
+
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.iter()
+        .flat_map(|list| list.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl AnimeSummaryRow {
+    pub fn into_summary(self) -> AnimeSummary {
+        AnimeSummary {
+            id: self.id,
+            generated_summary: AnimeGeneratedSummary {
+                summary: self.summary.unwrap_or_default(),
+                generated_genres: split_list(self.generated_genres),
+                generated_themes: split_list(self.generated_themes),
+            },
+        }
+    }
+}
+
+/// The text an embedding was computed from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingSource {
+    /// `anime_summary.summary`
+    Summary,
+    /// `anime_metadata.description`
+    Description,
+}
+
+impl EmbeddingSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmbeddingSource::Summary => "summary",
+            EmbeddingSource::Description => "description",
+        }
+    }
+}
+
+impl std::str::FromStr for EmbeddingSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(EmbeddingSource::Summary),
+            "description" => Ok(EmbeddingSource::Description),
+            _ => Err(format!("unknown embedding source {:?}, expected summary or description", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimeEmbedding {
+    pub id: i32,
+    pub model: String,
+    pub source: EmbeddingSource,
+    pub embedding: Vec<f32>,
+}
+
+/// What is stored for one model and source, to tell embedding sets apart.
+#[derive(sqlx::FromRow, Serialize, Debug, Clone)]
+pub struct EmbeddingSetInfo {
+    pub model: String,
+    pub source: String,
+    pub dimension: i32,
+    pub count: i64,
+    pub last_created_at: String,
+}
+
+/// How many rows the main tables hold.
+#[derive(sqlx::FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct RowCounts {
+    pub media: i64,
+    pub summaries: i64,
+    pub list_users: i64,
+    pub list_entries: i64,
+}
+
+/// AniList's `MediaListStatus`. MAL statuses are mapped onto it.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ListStatus {
+    Current,
+    Planning,
+    Completed,
+    Dropped,
+    Paused,
+    Repeating,
+}
+
+impl ListStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListStatus::Current => "CURRENT",
+            ListStatus::Planning => "PLANNING",
+            ListStatus::Completed => "COMPLETED",
+            ListStatus::Dropped => "DROPPED",
+            ListStatus::Paused => "PAUSED",
+            ListStatus::Repeating => "REPEATING",
+        }
+    }
+
+    /// Whether the user has started watching, i.e. anything but planning.
+    pub fn is_seen(&self) -> bool {
+        *self != ListStatus::Planning
+    }
+}
+
+impl std::str::FromStr for ListStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "CURRENT" => Ok(ListStatus::Current),
+            "PLANNING" => Ok(ListStatus::Planning),
+            "COMPLETED" => Ok(ListStatus::Completed),
+            "DROPPED" => Ok(ListStatus::Dropped),
+            "PAUSED" => Ok(ListStatus::Paused),
+            "REPEATING" => Ok(ListStatus::Repeating),
+            _ => Err(format!("unknown list status {:?}", s)),
+        }
+    }
+}
+
+/// Where a list entry was imported from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListSource {
+    Anilist,
+    Mal,
+}
+
+impl ListSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListSource::Anilist => "anilist",
+            ListSource::Mal => "mal",
+        }
+    }
+}
+
+impl std::str::FromStr for ListSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anilist" => Ok(ListSource::Anilist),
+            "mal" => Ok(ListSource::Mal),
+            _ => Err(format!("unknown list source {:?}, expected anilist or mal", s)),
+        }
+    }
+}
+
+/// One media on a user's list.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct UserListEntry {
+    pub user_name: String,
+    /// The AniList id.
+    pub media_id: i32,
+    pub status: ListStatus,
+    /// Out of 100, `None` when the user gave no score.
+    pub score: Option<i32>,
+    /// Episodes watched.
+    pub progress: Option<i32>,
+    pub source: ListSource,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct UserListEntryRow {
+    pub user_name: String,
+    pub media_id: i32,
+    pub status: String,
+    pub score: Option<i32>,
+    pub progress: Option<i32>,
+    pub source: String,
+}
+
+impl UserListEntryRow {
+    pub fn into_entry(self) -> Result<UserListEntry, String> {
+        Ok(UserListEntry {
+            user_name: self.user_name,
+            media_id: self.media_id,
+            status: self.status.parse()?,
+            score: self.score,
+            progress: self.progress,
+            source: self.source.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seasons_parse_ignoring_case() {
+        assert_eq!("winter".parse::<Season>(), Ok(Season::Winter));
+        assert_eq!("Autumn".parse::<Season>(), Ok(Season::Fall));
+        assert!("monsoon".parse::<Season>().is_err());
+        assert_eq!(serde_json::to_string(&Season::Spring).unwrap(), "\"SPRING\"");
+        assert_eq!(serde_json::from_str::<Season>("\"SUMMER\"").unwrap(), Season::Summer);
+    }
+
+    #[test]
+    fn formats_parse_as_anilist_names() {
+        assert_eq!("tv-short".parse::<MediaFormat>(), Ok(MediaFormat::TvShort));
+        assert_eq!("OVA".parse::<MediaFormat>(), Ok(MediaFormat::Ova));
+        assert!("manga".parse::<MediaFormat>().is_err());
+        for format in [MediaFormat::Tv, MediaFormat::TvShort, MediaFormat::Movie, MediaFormat::Music] {
+            assert_eq!(format.as_str().parse::<MediaFormat>(), Ok(format));
+            assert_eq!(serde_json::to_string(&format).unwrap(), format!("\"{}\"", format.as_str()));
+        }
+    }
+}
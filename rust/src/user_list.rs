@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::downloader::{CrawlOptions, Downloader};
+use crate::storage::Storage;
+use crate::types::{ListSource, ListStatus, UserListEntry};
+
+/// Scores at or above this make a media a liked taste seed.
+pub const LIKED_SCORE: i32 = 75;
+/// Scores at or below this make a media a disliked taste seed.
+pub const DISLIKED_SCORE: i32 = 45;
+
+const MEDIA_LIST_QUERY: &str = "
+query ($userName: String) {
+  MediaListCollection(userName: $userName, type: ANIME) {
+    lists {
+      entries {
+        mediaId
+        status
+        score(format: POINT_100)
+        progress
+      }
+    }
+  }
+}
+";
+
+#[derive(Debug)]
+pub enum ListImportError {
+    Io(String, std::io::Error),
+    Xml(String),
+    Parse(String),
+    Http(reqwest::Error),
+    AniList(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ListImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListImportError::Io(file, e) => write!(f, "{}: {}", file, e),
+            ListImportError::Xml(message) => write!(f, "invalid MAL export: {}", message),
+            ListImportError::Parse(message) => write!(f, "{}", message),
+            ListImportError::Http(e) => write!(f, "AniList request failed: {}", e),
+            ListImportError::AniList(message) => write!(f, "AniList error: {}", message),
+            ListImportError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ListImportError {}
+
+impl From<reqwest::Error> for ListImportError {
+    fn from(e: reqwest::Error) -> Self {
+        ListImportError::Http(e)
+    }
+}
+
+impl From<sqlx::Error> for ListImportError {
+    fn from(e: sqlx::Error) -> Self {
+        ListImportError::Database(e)
+    }
+}
+
+/// Downloads a public AniList anime list from the endpoint of `options`.
+pub async fn fetch_anilist_list(options: &CrawlOptions, user_name: &str) -> Result<Vec<UserListEntry>, ListImportError> {
+    let response = Downloader::graphql(options, MEDIA_LIST_QUERY, json!({"userName": user_name})).await?;
+    if response.is_null() {
+        return Err(ListImportError::AniList(format!("could not fetch the list of {:?}", user_name)));
+    }
+    if let Some(errors) = response["errors"].as_array() {
+        let messages: Vec<&str> = errors.iter().filter_map(|error| error["message"].as_str()).collect();
+        return Err(ListImportError::AniList(messages.join("; ")));
+    }
+
+    // A media can be on several custom lists.
+    let mut entries: Vec<UserListEntry> = vec![];
+    let lists = response["data"]["MediaListCollection"]["lists"].as_array().cloned().unwrap_or_default();
+    for entry in lists.iter().flat_map(|list| list["entries"].as_array().cloned().unwrap_or_default()) {
+        let media_id = entry["mediaId"].as_i64()
+            .ok_or_else(|| ListImportError::Parse(format!("list entry without a media id: {}", entry)))?;
+        let status: ListStatus = entry["status"].as_str()
+            .unwrap_or_default()
+            .parse()
+            .map_err(ListImportError::Parse)?;
+        if entries.iter().any(|e| e.media_id == media_id as i32) {
+            continue;
+        }
+        entries.push(UserListEntry {
+            user_name: user_name.to_string(),
+            media_id: media_id as i32,
+            status,
+            score: entry["score"].as_f64().map(|score| score.round() as i32).filter(|score| *score > 0),
+            progress: entry["progress"].as_i64().map(|progress| progress as i32),
+            source: ListSource::Anilist,
+        });
+    }
+    Ok(entries)
+}
+
+/// Replaces a user's AniList entries with their current AniList list and
+/// returns how many were imported.
+pub async fn import_anilist_list(storage: &Storage, options: &CrawlOptions, user_name: &str) -> Result<usize, ListImportError> {
+    let entries = fetch_anilist_list(options, user_name).await?;
+    storage.replace_user_list(user_name, ListSource::Anilist, &entries).await?;
+    Ok(entries.len())
+}
+
+/// An `<anime>` entry of a MAL export, still keyed by MAL id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalEntry {
+    pub mal_id: i32,
+    pub title: String,
+    pub status: ListStatus,
+    /// Out of 100, MAL's 1 to 10 scaled up.
+    pub score: Option<i32>,
+    pub progress: Option<i32>,
+}
+
+/// A parsed MAL export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalExport {
+    /// `<myinfo><user_name>`, when present.
+    pub user_name: Option<String>,
+    pub entries: Vec<MalEntry>,
+}
+
+/// MAL exports name statuses, older ones number them.
+fn mal_status(status: &str) -> Result<ListStatus, String> {
+    match status.trim() {
+        "Watching" | "1" => Ok(ListStatus::Current),
+        "Completed" | "2" => Ok(ListStatus::Completed),
+        "On-Hold" | "3" => Ok(ListStatus::Paused),
+        "Dropped" | "4" => Ok(ListStatus::Dropped),
+        "Plan to Watch" | "6" => Ok(ListStatus::Planning),
+        other => Err(format!("unknown MAL status {:?}", other)),
+    }
+}
+
+/// Parses the XML of a MAL anime list export (the file inside the `.xml.gz`
+/// MAL lets users download).
+pub fn read_mal_export(xml: &str) -> Result<MalExport, ListImportError> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| ListImportError::Xml(e.to_string()))?;
+    let root = document.root_element();
+    if !root.has_tag_name("myanimelist") {
+        return Err(ListImportError::Xml(format!("expected a <myanimelist> root, found <{}>", root.tag_name().name())));
+    }
+    let text = |node: roxmltree::Node, tag: &str| -> Option<String> {
+        node.children()
+            .find(|child| child.has_tag_name(tag))
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_string())
+    };
+    let number = |node: roxmltree::Node, tag: &str| -> Result<Option<i32>, ListImportError> {
+        match text(node, tag) {
+            Some(value) if !value.is_empty() => value.parse()
+                .map(Some)
+                .map_err(|_| ListImportError::Xml(format!("invalid <{}> {:?}", tag, value))),
+            _ => Ok(None),
+        }
+    };
+
+    let user_name = root.children()
+        .find(|child| child.has_tag_name("myinfo"))
+        .and_then(|info| text(info, "user_name"))
+        .filter(|name| !name.is_empty());
+    let mut entries = vec![];
+    for anime in root.children().filter(|child| child.has_tag_name("anime")) {
+        let mal_id = number(anime, "series_animedb_id")?
+            .ok_or_else(|| ListImportError::Xml("<anime> without a <series_animedb_id>".to_string()))?;
+        let status = mal_status(&text(anime, "my_status").unwrap_or_default())
+            .map_err(|e| ListImportError::Xml(format!("anime {}: {}", mal_id, e)))?;
+        entries.push(MalEntry {
+            mal_id,
+            title: text(anime, "series_title").unwrap_or_default(),
+            status,
+            score: number(anime, "my_score")?.filter(|score| *score > 0).map(|score| score * 10),
+            progress: number(anime, "my_watched_episodes")?,
+        });
+    }
+    Ok(MalExport { user_name, entries })
+}
+
+/// What a MAL import did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalImport {
+    pub user_name: String,
+    pub imported: usize,
+    /// Entries whose MAL id matches no crawled media, as `(mal_id, title)`.
+    pub unmatched: Vec<(i32, String)>,
+}
+
+/// Imports a MAL export file, replacing the user's MAL entries. MAL ids are
+/// matched to AniList ids through the crawled metadata, so entries of media
+/// that were not crawled are skipped and reported. `user_name` defaults to
+/// the name in the export.
+pub async fn import_mal_export(storage: &Storage, path: &Path, user_name: Option<&str>) -> Result<MalImport, ListImportError> {
+    let xml = std::fs::read_to_string(path).map_err(|e| ListImportError::Io(path.display().to_string(), e))?;
+    let export = read_mal_export(&xml)?;
+    let user_name = user_name
+        .map(|name| name.to_string())
+        .or(export.user_name)
+        .ok_or_else(|| ListImportError::Parse("the export has no user name, pass one".to_string()))?;
+
+    let mal_ids: Vec<i32> = export.entries.iter().map(|entry| entry.mal_id).collect();
+    let ids: HashMap<i32, i32> = storage.media_ids_for_mal(&mal_ids).await?.into_iter().collect();
+    let mut entries = vec![];
+    let mut unmatched = vec![];
+    for entry in export.entries {
+        match ids.get(&entry.mal_id) {
+            Some(media_id) => entries.push(UserListEntry {
+                user_name: user_name.clone(),
+                media_id: *media_id,
+                status: entry.status,
+                score: entry.score,
+                progress: entry.progress,
+                source: ListSource::Mal,
+            }),
+            None => unmatched.push((entry.mal_id, entry.title)),
+        }
+    }
+    storage.replace_user_list(&user_name, ListSource::Mal, &entries).await?;
+    Ok(MalImport { user_name, imported: entries.len(), unmatched })
+}
+
+/// Splits a list into liked and disliked media for
+/// [`crate::search::Searcher::recommend_for_taste`]: scores of at least
+/// [`LIKED_SCORE`] are liked, scores of at most [`DISLIKED_SCORE`] and
+/// unscored dropped media are disliked.
+pub fn taste_seeds(entries: &[UserListEntry]) -> (Vec<i32>, Vec<i32>) {
+    let mut liked = vec![];
+    let mut disliked = vec![];
+    for entry in entries {
+        match entry.score {
+            Some(score) if score >= LIKED_SCORE => liked.push(entry.media_id),
+            Some(score) if score <= DISLIKED_SCORE => disliked.push(entry.media_id),
+            None if entry.status == ListStatus::Dropped => disliked.push(entry.media_id),
+            _ => {}
+        }
+    }
+    (liked, disliked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(anime: &str) -> String {
+        format!("<?xml version=\"1.0\"?><myanimelist><myinfo><user_name> kana </user_name></myinfo>{}</myanimelist>", anime)
+    }
+
+    #[test]
+    fn mal_exports_read_named_and_numbered_statuses() {
+        let xml = export("
+            <anime>
+                <series_animedb_id>21</series_animedb_id>
+                <series_title><![CDATA[One Piece]]></series_title>
+                <my_watched_episodes>1000</my_watched_episodes>
+                <my_score>9</my_score>
+                <my_status>Watching</my_status>
+            </anime>
+            <anime>
+                <series_animedb_id>30</series_animedb_id>
+                <my_score>0</my_score>
+                <my_status>4</my_status>
+            </anime>");
+        let export = read_mal_export(&xml).unwrap();
+        assert_eq!(export.user_name.as_deref(), Some("kana"));
+        assert_eq!(export.entries, vec![
+            MalEntry { mal_id: 21, title: "One Piece".to_string(), status: ListStatus::Current, score: Some(90), progress: Some(1000) },
+            // A score of 0 means unscored.
+            MalEntry { mal_id: 30, title: String::new(), status: ListStatus::Dropped, score: None, progress: None },
+        ]);
+    }
+
+    #[test]
+    fn mal_exports_need_ids_and_known_statuses() {
+        let error = read_mal_export(&export("<anime><my_status>Completed</my_status></anime>")).unwrap_err();
+        assert!(error.to_string().contains("without a <series_animedb_id>"), "{}", error);
+
+        let error = read_mal_export(&export("<anime><series_animedb_id>21</series_animedb_id><my_status>5</my_status></anime>")).unwrap_err();
+        assert!(error.to_string().contains("anime 21: unknown MAL status \"5\""), "{}", error);
+
+        assert!(read_mal_export("<mylist/>").is_err());
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::SearchIndexConfig;
+use crate::hnsw::{read_u64, write_u64, Hnsw, HnswParams};
+use crate::search::SearchError;
+use crate::storage::Storage;
+use crate::types::{AnimeEmbedding, EmbeddingSource};
+
+const INDEX_MAGIC: &[u8; 8] = b"LAMHNSW2";
+
+// Below this many allowed media a filtered query scans them exactly, which is
+// both faster and exact.
+pub(crate) const EXACT_FILTER_LIMIT: usize = 2000;
+
+pub(crate) fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Scores every vector, exact but linear in the number of media.
+    Exact,
+    /// An HNSW graph persisted under the index directory.
+    Hnsw,
+    /// Ranked by Postgres with pgvector's `<=>` operator, without loading the
+    /// vectors. Needs `pgvector = true`.
+    Pgvector,
+}
+
+impl std::str::FromStr for IndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(IndexKind::Exact),
+            "hnsw" => Ok(IndexKind::Hnsw),
+            "pgvector" => Ok(IndexKind::Pgvector),
+            _ => Err(format!("unknown search index {:?}, expected exact, hnsw or pgvector", s)),
+        }
+    }
+}
+
+/// Every embedding of one set kept in memory, normalized so that a dot product
+/// is the cosine similarity.
+pub struct ExactIndex {
+    ids: Vec<i32>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl ExactIndex {
+    pub async fn load(storage: &Storage, model: &str, source: EmbeddingSource) -> Result<Self, sqlx::Error> {
+        Ok(Self::from_embeddings(storage.embeddings(model, source).await?))
+    }
+
+    pub fn from_embeddings(embeddings: Vec<AnimeEmbedding>) -> Self {
+        let mut ids = Vec::with_capacity(embeddings.len());
+        let mut vectors = Vec::with_capacity(embeddings.len());
+        for mut embedding in embeddings {
+            normalize(&mut embedding.embedding);
+            ids.push(embedding.id);
+            vectors.push(embedding.embedding);
+        }
+        Self { ids, vectors }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn dimension(&self) -> Option<usize> {
+        self.vectors.first().map(|v| v.len())
+    }
+
+    /// The normalized vector of a media. Embeddings are loaded in id order.
+    pub fn vector(&self, id: i32) -> Option<&[f32]> {
+        self.ids.binary_search(&id).ok().map(|position| self.vectors[position].as_slice())
+    }
+
+    /// Ranks the indexed media, or only those in `allowed`, by descending
+    /// similarity to a normalized query vector.
+    pub fn rank(&self, query: &[f32], allowed: Option<&HashSet<i32>>) -> Vec<(i32, f32)> {
+        let mut scored: Vec<(i32, f32)> = self.ids.iter()
+            .zip(&self.vectors)
+            .filter(|(id, _)| allowed.is_none_or(|allowed| allowed.contains(id)))
+            .map(|(id, vector)| (*id, dot(query, vector)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored
+    }
+}
+
+/// An HNSW graph over one embedding set, saved to
+/// `<index dir>/<model>.<source>.hnsw`. Opening it inserts the embeddings
+/// written since the file was saved and removes those deleted since, so only
+/// new or re-embedded summaries cost anything after the first build.
+pub struct HnswIndex {
+    model: String,
+    source: EmbeddingSource,
+    path: PathBuf,
+    // `created_at` of the newest embedding in the graph, as the database
+    // formats it.
+    synced_at: Option<String>,
+    ef_search: usize,
+    graph: Hnsw,
+}
+
+impl HnswIndex {
+    pub fn path(dir: &Path, model: &str, source: EmbeddingSource) -> PathBuf {
+        let name: String = model.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect();
+        dir.join(format!("{}.{}.hnsw", name, source.as_str()))
+    }
+
+    pub async fn open(storage: &Storage, model: &str, source: EmbeddingSource, config: &SearchIndexConfig) -> Result<Self, SearchError> {
+        let path = Self::path(Path::new(&config.dir), model, source);
+        let mut index = match Self::read(&path, model, source) {
+            Ok(Some((graph, synced_at))) => {
+                info!(count = graph.len(), path = %path.display(), "Loaded index");
+                Self { model: model.to_string(), source, path, synced_at, ef_search: config.ef_search, graph }
+            }
+            Ok(None) => Self::empty(model, source, path, config.ef_search),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Rebuilding index");
+                Self::empty(model, source, path, config.ef_search)
+            }
+        };
+        index.sync(storage).await?;
+        Ok(index)
+    }
+
+    fn empty(model: &str, source: EmbeddingSource, path: PathBuf, ef_search: usize) -> Self {
+        Self {
+            model: model.to_string(),
+            source,
+            path,
+            synced_at: None,
+            ef_search,
+            graph: Hnsw::new(0, HnswParams::default()),
+        }
+    }
+
+    /// Reads a saved index, or `None` if there is none or it belongs to
+    /// another embedding set.
+    fn read(path: &Path, model: &str, source: EmbeddingSource) -> io::Result<Option<(Hnsw, Option<String>)>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a LAM HNSW index"));
+        }
+        if read_string(&mut reader)? != model || read_string(&mut reader)? != source.as_str() {
+            return Ok(None);
+        }
+        let synced_at = Some(read_string(&mut reader)?).filter(|s| !s.is_empty());
+        let graph = Hnsw::read_from(&mut reader)?;
+        Ok(Some((graph, synced_at)))
+    }
+
+    /// Writes the index next to its final path and renames it, so a crash
+    /// never leaves a truncated file behind.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("hnsw.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(INDEX_MAGIC)?;
+        write_string(&mut writer, &self.model)?;
+        write_string(&mut writer, self.source.as_str())?;
+        write_string(&mut writer, self.synced_at.as_deref().unwrap_or(""))?;
+        self.graph.write_to(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Inserts the embeddings written since the last sync, removes the ones
+    /// deleted since, and saves the index if anything changed. Returns the
+    /// number of vectors inserted.
+    pub async fn sync(&mut self, storage: &Storage) -> Result<usize, SearchError> {
+        let sets = storage.embedding_sets().await?;
+        let Some(set) = sets.into_iter().find(|set| set.model == self.model && set.source == self.source.as_str()) else {
+            if self.graph.len() + self.graph.deleted() > 0 {
+                self.graph = Hnsw::new(0, HnswParams::default());
+                self.synced_at = None;
+                self.save().map_err(SearchError::Index)?;
+            }
+            return Ok(0);
+        };
+        // Deleting embeddings leaves the newest one in place, but not the count.
+        if self.synced_at.as_deref() == Some(set.last_created_at.as_str()) && self.graph.len() == set.count as usize {
+            return Ok(0);
+        }
+        let dimension = set.dimension as usize;
+        if self.graph.dimension() != dimension {
+            // A new index, or the set was re-embedded with another dimension.
+            self.graph = Hnsw::new(dimension, HnswParams::default());
+            self.synced_at = None;
+        }
+        if self.synced_at.is_some() {
+            let stored: HashSet<i32> = storage.embedding_ids(&self.model, self.source).await?.into_iter().collect();
+            let removed: Vec<i32> = self.graph.ids().filter(|id| !stored.contains(id)).collect();
+            for id in removed {
+                self.graph.remove(id);
+            }
+        }
+        let embeddings = match &self.synced_at {
+            Some(since) => storage.embeddings_since(&self.model, self.source, since).await?,
+            None => storage.embeddings(&self.model, self.source).await?,
+        };
+        let total = embeddings.len();
+        for (i, mut embedding) in embeddings.into_iter().enumerate() {
+            if embedding.embedding.len() != dimension {
+                continue;
+            }
+            normalize(&mut embedding.embedding);
+            self.graph.insert(embedding.id, &embedding.embedding);
+            if (i + 1) % 1000 == 0 {
+                info!(indexed = i + 1, total, "Indexing");
+            }
+        }
+        self.synced_at = Some(set.last_created_at);
+        if self.graph.deleted() > self.graph.len() / 4 {
+            // Tombstones slow searches down and replaced vectors keep the
+            // links of their old position.
+            info!(deleted = self.graph.deleted(), "Rebuilding index");
+            self.graph = Hnsw::new(dimension, HnswParams::default());
+            self.synced_at = None;
+            return Box::pin(self.sync(storage)).await;
+        }
+        self.save().map_err(SearchError::Index)?;
+        info!(total, path = %self.path.display(), "Indexed vectors");
+        Ok(total)
+    }
+
+    pub fn len(&self) -> usize {
+        self.graph.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graph.is_empty()
+    }
+
+    pub fn graph(&self) -> &Hnsw {
+        &self.graph
+    }
+
+    /// Ranks candidates for a normalized query, best first. Small filtered
+    /// sets are scanned exactly; otherwise the search list is widened by the
+    /// inverse of the filter's selectivity so that `k` allowed media usually
+    /// survive.
+    pub fn rank(&self, query: &[f32], k: usize, allowed: Option<&HashSet<i32>>) -> Vec<(i32, f32)> {
+        let Some(allowed) = allowed else {
+            let ef = self.ef_search.max(k * 4);
+            return self.graph.search(query, ef, ef);
+        };
+        if allowed.len() <= EXACT_FILTER_LIMIT {
+            let mut scored: Vec<(i32, f32)> = allowed.iter()
+                .filter_map(|id| self.graph.vector(*id).map(|vector| (*id, dot(query, vector))))
+                .collect();
+            scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+            return scored;
+        }
+        let selectivity = (self.graph.len() / allowed.len()).max(1);
+        let ef = (self.ef_search.max(k * 4) * selectivity).min(self.graph.len());
+        self.graph.search(query, ef, ef)
+            .into_iter()
+            .filter(|(id, _)| allowed.contains(id))
+            .collect()
+    }
+}
+
+fn write_string(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let len = read_u64(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// An embedding set searched by Postgres. Only its size and dimension are
+/// kept; vectors are read when needed.
+pub struct PgvectorIndex {
+    storage: Storage,
+    model: String,
+    source: EmbeddingSource,
+    len: usize,
+    dimension: Option<usize>,
+}
+
+impl PgvectorIndex {
+    pub async fn open(storage: &Storage, model: &str, source: EmbeddingSource) -> Result<Self, SearchError> {
+        let mut index = Self {
+            storage: storage.clone(),
+            model: model.to_string(),
+            source,
+            len: 0,
+            dimension: None,
+        };
+        index.sync().await?;
+        Ok(index)
+    }
+
+    /// Reads the size of the set and creates its index once its dimension is
+    /// known.
+    async fn sync(&mut self) -> Result<(), SearchError> {
+        let sets = self.storage.embedding_sets().await?;
+        let set = sets.into_iter().find(|set| set.model == self.model && set.source == self.source.as_str());
+        self.len = set.as_ref().map_or(0, |set| set.count as usize);
+        self.dimension = set.map(|set| set.dimension as usize);
+        if let Some(dimension) = self.dimension {
+            self.storage.create_vector_index(&self.model, self.source, dimension as i32).await?;
+        }
+        Ok(())
+    }
+
+    async fn vector(&self, id: i32) -> Result<Option<Vec<f32>>, SearchError> {
+        let embedding = self.storage.embedding(id, &self.model, self.source).await?;
+        Ok(embedding.map(|mut embedding| {
+            normalize(&mut embedding.embedding);
+            embedding.embedding
+        }))
+    }
+
+    async fn rank(&self, query: &[f32], k: usize, allowed: Option<Vec<i32>>) -> Result<Vec<(i32, f32)>, SearchError> {
+        Ok(self.storage.nearest_embeddings(&self.model, self.source, query, k as i64, allowed.as_deref()).await?)
+    }
+}
+
+/// The nearest neighbor index a [`crate::search::Searcher`] ranks with.
+pub enum VectorIndex {
+    Exact {
+        model: String,
+        source: EmbeddingSource,
+        index: ExactIndex,
+    },
+    Hnsw(HnswIndex),
+    Pgvector(PgvectorIndex),
+}
+
+impl VectorIndex {
+    pub async fn load(storage: &Storage, model: &str, source: EmbeddingSource, config: &SearchIndexConfig) -> Result<Self, SearchError> {
+        match config.kind {
+            IndexKind::Exact => Ok(VectorIndex::Exact {
+                model: model.to_string(),
+                source,
+                index: ExactIndex::load(storage, model, source).await?,
+            }),
+            IndexKind::Hnsw => Ok(VectorIndex::Hnsw(HnswIndex::open(storage, model, source, config).await?)),
+            IndexKind::Pgvector => Ok(VectorIndex::Pgvector(PgvectorIndex::open(storage, model, source).await?)),
+        }
+    }
+
+    /// Picks up embeddings written since the index was loaded.
+    pub async fn refresh(&mut self, storage: &Storage) -> Result<(), SearchError> {
+        match self {
+            VectorIndex::Exact { model, source, index } => {
+                *index = ExactIndex::load(storage, model, *source).await?;
+            }
+            VectorIndex::Hnsw(index) => {
+                index.sync(storage).await?;
+            }
+            VectorIndex::Pgvector(index) => {
+                index.sync().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            VectorIndex::Exact { index, .. } => index.len(),
+            VectorIndex::Hnsw(index) => index.len(),
+            VectorIndex::Pgvector(index) => index.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dimension(&self) -> Option<usize> {
+        match self {
+            VectorIndex::Exact { index, .. } => index.dimension(),
+            VectorIndex::Hnsw(index) => Some(index.graph().dimension()).filter(|_| !index.is_empty()),
+            VectorIndex::Pgvector(index) => index.dimension,
+        }
+    }
+
+    /// The normalized indexed vector of a media.
+    pub async fn vector(&self, id: i32) -> Result<Option<Vec<f32>>, SearchError> {
+        match self {
+            VectorIndex::Exact { index, .. } => Ok(index.vector(id).map(|v| v.to_vec())),
+            VectorIndex::Hnsw(index) => Ok(index.graph().vector(id).map(|v| v.to_vec())),
+            VectorIndex::Pgvector(index) => index.vector(id).await,
+        }
+    }
+
+    /// Ranks candidates for a normalized query, best first. The exact index
+    /// ranks every allowed media, HNSW returns a few times `k` candidates and
+    /// pgvector exactly `k`.
+    pub async fn rank(&self, query: &[f32], k: usize, allowed: Option<&HashSet<i32>>) -> Result<Vec<(i32, f32)>, SearchError> {
+        match self {
+            VectorIndex::Exact { index, .. } => Ok(index.rank(query, allowed)),
+            VectorIndex::Hnsw(index) => Ok(index.rank(query, k, allowed)),
+            VectorIndex::Pgvector(index) => {
+                let allowed = allowed.map(|allowed| Vec::from_iter(allowed.iter().copied()));
+                index.rank(query, k, allowed).await
+            }
+        }
+    }
+}